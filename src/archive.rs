@@ -0,0 +1,493 @@
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use time::{OffsetDateTime, PrimitiveDateTime};
+use zip::extra_fields::ExtraField;
+use zip::read::ZipFile;
+use zip::write::{FullFileOptions, SimpleFileOptions};
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::config::AppConfig;
+use crate::dir_state::{self, CopyDiff, DirState, FileError, ListedEntry};
+use crate::file_filter::RESERVED_PREFIX;
+use crate::output::{complain, say};
+use crate::plan::AppComparison;
+use crate::report::CopyReport;
+use crate::{add_file_errors, apply_direction, ask, check_case_collisions, interrupt, rollback, snapshot, SyncOptions, SyncResult};
+
+// Apps with `archive = true` keep everything in this one file inside their
+// `dropbox_path`, rather than in a mirror of the app's folder.
+pub const ARCHIVE_FILENAME: &str = "archive.zip";
+
+// What to do when the app and its archive conflict: keep one side, or neither.
+static CONFLICT_CHOICES: [ask::Choice<Option<SyncResult>>; 3] = [
+    ask::Choice { name: "app", value: Some(SyncResult::AppNewerThanDropbox) },
+    ask::Choice { name: "archive", value: Some(SyncResult::DropboxNewerThanApp) },
+    ask::Choice { name: "skip", value: None },
+];
+
+pub fn get_archive_path(app: &AppConfig) -> PathBuf {
+    app.dropbox_path.join(ARCHIVE_FILENAME)
+}
+
+// Scans the app's archive as if it were a folder. A missing archive
+// looks just like an empty folder.
+pub fn scan_archive(app: &AppConfig) -> DirState {
+    let archive_path = get_archive_path(app);
+    let entries = if archive_path.exists() { read_entries(&archive_path) } else { Ok(vec![]) };
+    let state = match entries {
+        Ok(entries) => {
+            let listings = list_entries(&archive_path, &entries);
+            DirState::from_listing(&archive_path, &app.file_filter, &|dir: &Path| {
+                Ok(listings.get(dir).map(|listing| listing.values().cloned().collect()).unwrap_or_default())
+            })
+        },
+        Err(e) => DirState::from_listing(&archive_path, &app.file_filter, &|_: &Path| Err(io::Error::new(e.kind(), e.to_string()))),
+    };
+    // There's nothing on disk to hash, so the archive can only be compared
+    // by modification time and size.
+    state.with_compare_options(dir_state::CompareOptions { compare_hashes: false, paranoid: false, ..app.compare })
+}
+
+// Groups the archive's entries by the directory they'd be in, if the
+// archive were a folder, adding any directories that are only implied.
+fn list_entries(root: &Path, entries: &[ArchiveEntry]) -> HashMap<PathBuf, BTreeMap<String, ListedEntry>> {
+    let mut listings: HashMap<PathBuf, BTreeMap<String, ListedEntry>> = HashMap::new();
+    listings.entry(root.to_path_buf()).or_default();
+    for entry in entries {
+        let components: Vec<&str> = entry.name.split('/').collect();
+        let mut dir = root.to_path_buf();
+        for (i, component) in components.iter().enumerate() {
+            let is_dir = i + 1 < components.len() || entry.is_dir;
+            let listed = if is_dir {
+                ListedEntry { name: component.to_string(), is_dir: true, size: 0, modified: 0 }
+            } else {
+                ListedEntry { name: component.to_string(), is_dir: false, size: entry.size, modified: entry.modified }
+            };
+            listings.entry(dir.clone()).or_default().entry(component.to_string()).or_insert(listed);
+            dir.push(component);
+            if is_dir {
+                listings.entry(dir.clone()).or_default();
+            }
+        }
+    }
+    listings
+}
+
+fn to_entry_name(rel_path: &Path) -> String {
+    rel_path.iter().map(|component| component.to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+pub fn compare_app_with_archive(app: &AppConfig, hostname: &str) -> AppComparison {
+    let app_state = app.scan_path().with_compare_options(dir_state::CompareOptions { compare_hashes: false, paranoid: false, ..app.compare });
+    let archive_state = scan_archive(app);
+    let last_sync = snapshot::load(app, hostname);
+    // Unlike a folder, an archive we can't read is one we can't read any of.
+    let result = if archive_state.was_read_cleanly() {
+        snapshot::classify_with_snapshot(&app_state, &archive_state, last_sync.as_ref())
+    } else {
+        SyncResult::ReadError
+    };
+    let diff = match result {
+        SyncResult::AppNewerThanDropbox => {
+            let mut diff = app_state.diff_copy_into(&archive_state);
+            if !app.may_delete_from_dropbox(hostname) {
+                diff.to_delete.clear();
+            }
+            diff
+        },
+        SyncResult::DropboxNewerThanApp => {
+            let mut diff = archive_state.diff_copy_into(&app_state);
+            if !app.may_delete_from_app() {
+                diff.to_delete.clear();
+            }
+            diff
+        },
+        _ => Default::default(),
+    };
+    AppComparison { result, diff, app_state, dropbox_state: archive_state }
+}
+
+pub fn sync_app_with_archive(app: &AppConfig, confirm_if_app_is_newer: bool, options: &SyncOptions) -> (SyncResult, CopyReport) {
+    let mut report = CopyReport::default();
+    let comparison = compare_app_with_archive(app, &options.hostname);
+    for error in comparison.app_state.read_errors().into_iter().chain(comparison.dropbox_state.read_errors()) {
+        complain!("  Warning: {}", error);
+    }
+    let (app_state, archive_state) = (&comparison.app_state, &comparison.dropbox_state);
+    let (result, forced) = apply_direction(comparison.result, options);
+    let (result, should_ask) = match result {
+        SyncResult::AppNewerThanDropbox => {
+            if !forced {
+                say!("  App state is newer than its archive.");
+            }
+            (SyncResult::AppNewerThanDropbox, confirm_if_app_is_newer)
+        },
+        SyncResult::DropboxNewerThanApp => {
+            if !forced {
+                say!("  Archive state is newer than app.");
+            }
+            (SyncResult::DropboxNewerThanApp, true)
+        },
+        SyncResult::AlreadySynced | SyncResult::BothEmpty => {
+            say!("  App state matches its archive. Nothing to do!");
+            report.completed = true;
+            return (comparison.result, report);
+        },
+        SyncResult::Conflict => {
+            complain!("  App and archive state are in conflict; manual resolution required.");
+            if options.non_interactive {
+                complain!("  Skipping it, since we're running non-interactively.");
+                return (SyncResult::Skipped, report);
+            }
+            match ask::ask_with_choices("  ", "Which side do you want to keep? ", &CONFLICT_CHOICES) {
+                Some(result) => (result, false),
+                None => {
+                    say!("  Okay, leaving both sides alone for now.");
+                    return (SyncResult::Skipped, report);
+                },
+            }
+        },
+        SyncResult::ReadError => {
+            complain!("  Unable to read the app or its archive, so not doing anything.");
+            return (comparison.result, report);
+        },
+        SyncResult::Skipped | SyncResult::Merged => unreachable!("classification never skips or merges an app"),
+    };
+    let (from_state, to_state) = if result == SyncResult::AppNewerThanDropbox { (app_state, archive_state) } else { (archive_state, app_state) };
+    let mut diff = from_state.diff_copy_into(to_state);
+    diff.to_copy = from_state.files_differing_from(to_state).into_iter().map(|(path, _)| path).collect();
+    let may_delete = if result == SyncResult::AppNewerThanDropbox { app.may_delete_from_dropbox(&options.hostname) } else { app.may_delete_from_app() };
+    if !may_delete {
+        diff.to_delete.clear();
+    }
+    if options.dry_run {
+        say!("  Dry run: would copy {} file(s) and delete {}.", diff.to_copy.len(), diff.to_delete.len());
+        return (result, report);
+    }
+    if should_ask && !options.non_interactive && !options.already_confirmed {
+        if !ask::ask_yes_or_no("  Proceed with synchronization (y/n) ? ") {
+            say!("  Okay, not doing anything.");
+            return (result, report);
+        }
+    } else {
+        say!("  Synchronizing files.");
+    }
+    if !check_case_collisions(from_state, &mut report) {
+        return (result, report);
+    }
+    let _changing_files = interrupt::ChangingFiles::start();
+    let outcome = if result == SyncResult::AppNewerThanDropbox {
+        update_archive(app, app_state, &diff)
+    } else {
+        extract_archive(app, archive_state, app_state, &diff)
+    };
+    match outcome {
+        Ok(()) => {
+            report.files_copied = diff.to_copy.len();
+            report.files_deleted = diff.to_delete.len();
+            report.completed = true;
+            snapshot::save(app, &options.hostname, &scan_archive(app));
+        },
+        Err(errors) => add_file_errors(&mut report, "sync", &errors),
+    }
+    (result, report)
+}
+
+// Replaces the archive with one containing everything in the app's
+// folder, plus anything we've been told not to delete.
+fn update_archive(app: &AppConfig, app_state: &DirState, diff: &CopyDiff) -> Result<(), Vec<FileError>> {
+    let archive_path = get_archive_path(app);
+    let to_error = |path: &Path, error: io::Error| vec![FileError { path: path.to_path_buf(), error }];
+    let old_entries = if archive_path.exists() {
+        read_entries(&archive_path).map_err(|e| to_error(&archive_path, e))?
+    } else {
+        vec![]
+    };
+    if let Some(backup_path) = app.new_backup_path() {
+        if archive_path.exists() {
+            fs::create_dir_all(&backup_path)
+                .and_then(|_| fs::copy(&archive_path, backup_path.join(ARCHIVE_FILENAME)))
+                .map_err(|e| to_error(&backup_path, e))?;
+        }
+        rollback::record(&backup_path, &[rollback::Change::new(rollback::Side::Dropbox, "", &[PathBuf::from(ARCHIVE_FILENAME)], &[])])?;
+    }
+    let mut sources: BTreeMap<String, Source> = BTreeMap::new();
+    for (rel_path, entry) in app_state.to_listing() {
+        let source = if entry.is_dir {
+            Source::Dir
+        } else {
+            Source::File { path: app.path.join(&rel_path), modified: entry.modified }
+        };
+        sources.insert(to_entry_name(&rel_path), source);
+    }
+    // Whatever's in the archive but not the app stays there, unless the
+    // diff says it should be deleted.
+    for entry in old_entries {
+        let rel_path: PathBuf = entry.name.split('/').collect();
+        let is_kept = !diff.to_delete.iter().any(|deleted| rel_path.starts_with(deleted));
+        if is_kept && !sources.contains_key(&entry.name) && !app_state.has_file(&rel_path) {
+            sources.insert(entry.name.clone(), if entry.is_dir { Source::Dir } else { Source::Archived });
+        }
+    }
+    let tmp_path = app.dropbox_path.join(format!("{}tmp-{}", RESERVED_PREFIX, ARCHIVE_FILENAME));
+    let result = write_archive(&tmp_path, &archive_path, &sources).and_then(|_| {
+        if interrupt::is_interrupted() {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "interrupted before the archive was replaced"));
+        }
+        fs::rename(&tmp_path, &archive_path)
+    });
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(to_error(&archive_path, e));
+    }
+    Ok(())
+}
+
+// Extracts whatever's changed in the archive into the app's folder, and
+// deletes whatever the archive doesn't have.
+fn extract_archive(app: &AppConfig, archive_state: &DirState, app_state: &DirState, diff: &CopyDiff) -> Result<(), Vec<FileError>> {
+    let archive_path = get_archive_path(app);
+    let mut archive = open_archive(&archive_path).map_err(|error| vec![FileError { path: archive_path.clone(), error }])?;
+    if let Some(backup_path) = app.new_backup_path() {
+        let to_back_up = [diff.to_copy.clone(), diff.to_delete.clone()].concat();
+        app_state.back_up_into(&to_back_up, &backup_path)?;
+        rollback::record(&backup_path, &[rollback::Change::new(rollback::Side::App, "", &diff.to_copy, &diff.to_delete)])?;
+    }
+    let mut errors = vec![];
+    // Empty directories only show up as entries of their own.
+    for (rel_path, entry) in archive_state.to_listing() {
+        if entry.is_dir {
+            let dir_path = app.path.join(rel_path);
+            if let Err(error) = fs::create_dir_all(&dir_path) {
+                errors.push(FileError { path: dir_path, error });
+            }
+        }
+    }
+    for rel_path in diff.to_copy.iter() {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let dest_path = app.path.join(rel_path);
+        if let Err(error) = extract_entry(&mut archive, &to_entry_name(rel_path), &dest_path) {
+            errors.push(FileError { path: dest_path, error });
+        }
+    }
+    if !errors.is_empty() || interrupt::is_interrupted() {
+        return Err(errors);
+    }
+    dir_state::remove_files_from(&app.path, &diff.to_delete, app.use_trash)
+}
+
+// The standard zip timestamps only have two-second precision and no time
+// zone, so modification times are also kept to the second in the
+// "extended timestamp" extra field.
+const EXTENDED_TIMESTAMP_ID: u16 = 0x5455;
+
+// What we need to know about an entry in the archive. Names are relative
+// to the root of the archive, with forward slashes and no trailing slash
+// for directories.
+#[derive(Debug, Clone, PartialEq)]
+struct ArchiveEntry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: u64,
+}
+
+// Where an entry in a new archive comes from.
+enum Source {
+    Dir,
+    File { path: PathBuf, modified: u64 },
+    // The entry of the same name in the archive we're replacing, which is
+    // copied over without recompressing it.
+    Archived,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn open_archive(path: &Path) -> io::Result<ZipArchive<fs::File>> {
+    Ok(ZipArchive::new(fs::File::open(path)?)?)
+}
+
+// Makes sure an entry's name can't put anything outside the folder it's
+// extracted into.
+fn check_name(name: &str) -> io::Result<()> {
+    let is_safe = !name.is_empty() && !name.contains('\\') && Path::new(name).components().all(|component| matches!(component, Component::Normal(_)));
+    if is_safe {
+        Ok(())
+    } else {
+        Err(invalid(format!("the archive contains an unsafe path: {}", name)))
+    }
+}
+
+fn get_modified(file: &ZipFile<fs::File>) -> u64 {
+    let extended = file.extra_data_fields().find_map(|field| match field {
+        ExtraField::ExtendedTimestamp(timestamp) => timestamp.mod_time(),
+        _ => None,
+    });
+    if let Some(secs) = extended {
+        return secs as u64;
+    }
+    // Archives made elsewhere might only have the standard timestamp,
+    // which we treat as UTC.
+    file.last_modified()
+        .and_then(|modified| PrimitiveDateTime::try_from(modified).ok())
+        .map_or(0, |modified| modified.assume_utc().unix_timestamp().max(0) as u64)
+}
+
+// How new files are added to the archive: deflated, and with their
+// modification time kept to the second.
+fn get_file_options(modified: u64) -> io::Result<FullFileOptions<'static, 'static>> {
+    let date_time = OffsetDateTime::from_unix_timestamp(modified as i64).ok()
+        .and_then(|modified| zip::DateTime::try_from(PrimitiveDateTime::new(modified.date(), modified.time())).ok())
+        .unwrap_or_default();
+    let mut options = FullFileOptions::default().compression_method(CompressionMethod::Deflated).last_modified_time(date_time);
+    // The flag says only the modification time follows.
+    let mut timestamp = vec![1];
+    timestamp.extend_from_slice(&(modified.min(u32::MAX as u64) as u32).to_le_bytes());
+    options.add_extra_field(EXTENDED_TIMESTAMP_ID, timestamp, false)?;
+    Ok(options)
+}
+
+fn read_entries(path: &Path) -> io::Result<Vec<ArchiveEntry>> {
+    let mut archive = open_archive(path)?;
+    let mut entries = vec![];
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        let name = file.name()?.trim_end_matches('/').to_string();
+        check_name(&name)?;
+        if file.encrypted() || !matches!(file.compression(), CompressionMethod::Stored | CompressionMethod::Deflated) {
+            return Err(invalid(format!("{} is encrypted or compressed in a way that isn't supported", name)));
+        }
+        entries.push(ArchiveEntry { name, is_dir: file.is_dir(), size: file.size(), modified: get_modified(&file) });
+    }
+    Ok(entries)
+}
+
+fn extract_entry(archive: &mut ZipArchive<fs::File>, name: &str, dest_path: &Path) -> io::Result<()> {
+    let mut file = archive.by_name(name)?;
+    let modified = get_modified(&file);
+    if let Some(parent) = dest_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let filename = dest_path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = dest_path.with_file_name(format!("{}tmp-{}", RESERVED_PREFIX, filename));
+    let result = fs::File::create(&tmp_path).and_then(|mut dest| {
+        // Reading the entry all the way through checks its CRC.
+        io::copy(&mut file, &mut dest)?;
+        dest.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified))
+    }).and_then(|_| fs::rename(&tmp_path, dest_path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+// Writes a new archive at `path` from the given sources, reading any
+// archived ones from `old_archive_path`.
+fn write_archive(path: &Path, old_archive_path: &Path, sources: &BTreeMap<String, Source>) -> io::Result<()> {
+    let mut writer = ZipWriter::new(io::BufWriter::new(fs::File::create(path)?));
+    let mut old_archive = None;
+    for (name, source) in sources.iter() {
+        match source {
+            Source::Dir => writer.add_directory(name.as_str(), SimpleFileOptions::default())?,
+            Source::File { path, modified } => {
+                let mut file = fs::File::open(path)?;
+                let is_large = file.metadata()?.len() >= u32::MAX as u64;
+                writer.start_file(name.as_str(), get_file_options(*modified)?.large_file(is_large))?;
+                io::copy(&mut file, &mut writer)?;
+            },
+            Source::Archived => {
+                if old_archive.is_none() {
+                    old_archive = Some(open_archive(old_archive_path)?);
+                }
+                writer.raw_copy_file(old_archive.as_mut().unwrap().by_name(name)?)?;
+            },
+        }
+    }
+    let mut file = writer.finish()?;
+    file.flush()?;
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+#[test]
+fn test_check_name() {
+    assert!(check_name("subdir/shoop").is_ok());
+    assert!(check_name("../boop").is_err());
+    assert!(check_name("/etc/boop").is_err());
+    assert!(check_name("subdir\\..\\boop").is_err());
+}
+
+#[test]
+fn test_sync_app_with_archive() {
+    let tmp_dir = PathBuf::from(".test_sync_app_with_archive");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app = AppConfig {
+        name: String::from("boop"),
+        path: tmp_dir.join("app"),
+        dropbox_path: tmp_dir.join("dropbox"),
+        ..Default::default()
+    };
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &app.file_filter);
+    src_state.copy_into(&app.path).unwrap();
+    fs::create_dir_all(app.path.join("empty")).unwrap();
+    fs::create_dir_all(&app.dropbox_path).unwrap();
+    let make_options = |hostname: &str| SyncOptions { non_interactive: true, ..SyncOptions::new(hostname) };
+    let options = make_options("my_computer");
+    let other_options = make_options("other_computer");
+    // Neither computer has synced before, but both know the archive used
+    // to be empty, so nothing's in conflict.
+    snapshot::save(&app, "my_computer", &scan_archive(&app));
+    snapshot::save(&app, "other_computer", &scan_archive(&app));
+
+    // The archive starts out empty, so the app is newer.
+    let (result, report) = sync_app_with_archive(&app, false, &options);
+    assert_eq!(result, SyncResult::AppNewerThanDropbox);
+    assert!(report.completed);
+    let entries = read_entries(&get_archive_path(&app)).unwrap();
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, vec!["boop", "empty", "subdir", "subdir/shoop"]);
+    assert_eq!(open_archive(&get_archive_path(&app)).unwrap().by_name("boop").unwrap().compression(), CompressionMethod::Deflated);
+    assert!(scan_archive(&app).are_contents_equal_to(&app.scan_path()));
+    assert_eq!(sync_app_with_archive(&app, false, &options).0, SyncResult::AlreadySynced);
+
+    // Another computer syncing into a fresh folder gets everything back.
+    let other_app = AppConfig { path: tmp_dir.join("other_app"), ..Default::default() };
+    let other_app = AppConfig { name: app.name.clone(), dropbox_path: app.dropbox_path.clone(), ..other_app };
+    fs::create_dir_all(&other_app.path).unwrap();
+    let (result, report) = sync_app_with_archive(&other_app, false, &other_options);
+    assert_eq!(result, SyncResult::DropboxNewerThanApp);
+    assert!(report.completed);
+    assert_eq!(fs::read(other_app.path.join("subdir").join("shoop")).unwrap(), fs::read(app.path.join("subdir").join("shoop")).unwrap());
+    assert!(other_app.path.join("empty").is_dir());
+    assert!(other_app.scan_path().are_contents_equal_to(&app.scan_path()));
+
+    // Deleting a file from the app deletes it from the archive, and then
+    // from the other computer.
+    fs::remove_file(app.path.join("boop")).unwrap();
+    assert_eq!(sync_app_with_archive(&app, false, &options).0, SyncResult::AppNewerThanDropbox);
+    assert!(!scan_archive(&app).has_file(Path::new("boop")));
+    assert_eq!(sync_app_with_archive(&other_app, false, &other_options).0, SyncResult::DropboxNewerThanApp);
+    assert!(!other_app.path.join("boop").exists());
+
+    // Without mirroring, what's only in the archive is copied into the new
+    // one as-is.
+    let app = AppConfig { compare: dir_state::CompareOptions { mirror: false, ..app.compare }, ..app };
+    fs::remove_file(app.path.join("subdir").join("shoop")).unwrap();
+    fs::write(app.path.join("new_file"), "blarg").unwrap();
+    assert_eq!(sync_app_with_archive(&app, false, &options).0, SyncResult::AppNewerThanDropbox);
+    let names: Vec<String> = read_entries(&get_archive_path(&app)).unwrap().into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, vec!["empty", "new_file", "subdir", "subdir/shoop"]);
+    extract_entry(&mut open_archive(&get_archive_path(&app)).unwrap(), "subdir/shoop", &tmp_dir.join("shoop")).unwrap();
+    assert_eq!(fs::read(tmp_dir.join("shoop")).unwrap(), fs::read(other_app.path.join("subdir").join("shoop")).unwrap());
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
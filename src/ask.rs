@@ -1,3 +1,27 @@
+use crate::output::{self, Level};
+
+// Questions go wherever our messages do, unless we've been told to keep
+// stdout clear of them (e.g. for JSON), in which case they go to stderr.
+fn is_using_stderr() -> bool {
+    !output::is_enabled(Level::Quiet)
+}
+
+fn show(line: &str) {
+    if is_using_stderr() {
+        eprintln!("{}", line);
+    } else {
+        println!("{}", line);
+    }
+}
+
+fn prompt_reply(prompt: &str) -> String {
+    if is_using_stderr() {
+        rprompt::prompt_reply_stderr(prompt).unwrap()
+    } else {
+        rprompt::prompt_reply_stdout(prompt).unwrap()
+    }
+}
+
 fn parse_yes_or_no(value: &str) -> Option<bool> {
     let lower = value.to_lowercase();
     if lower.starts_with("y") {
@@ -11,7 +35,7 @@ fn parse_yes_or_no(value: &str) -> Option<bool> {
 
 pub fn ask_yes_or_no(prompt: &str) -> bool {
     loop {
-        let reply = rprompt::prompt_reply_stdout(prompt).unwrap();
+        let reply = prompt_reply(prompt);
         if let Some(response) = parse_yes_or_no(&reply) {
             return response;
         }
@@ -28,7 +52,17 @@ fn parse_choice<T: Copy, U: AsRef<[Choice<T>]>>(input: &str, choices: U) -> Opti
         }
     }
 
-    None
+    // Otherwise, accept any prefix (like the first letter) that only one
+    // choice starts with.
+    if lowercase_input.is_empty() {
+        return None;
+    }
+    let mut matches = choices.as_ref().iter()
+        .filter(|choice| choice.name.to_lowercase().starts_with(&lowercase_input));
+    match (matches.next(), matches.next()) {
+        (Some(choice), None) => Some(choice.value),
+        _ => None,
+    }
 }
 
 #[derive(Debug)]
@@ -38,14 +72,14 @@ pub struct Choice<T> {
 }
 
 pub fn ask_with_choices<T: Copy, U: AsRef<[Choice<T>]>>(indentation: &str, prompt: &str, choices: U) -> T {
-    println!("{}Options:", indentation);
+    show(&format!("{}Options:", indentation));
     for choice in choices.as_ref() {
-        println!("{}  {}", indentation, choice.name);
+        show(&format!("{}  {}", indentation, choice.name));
     }
     
     let prompt = format!("{}{}", indentation, prompt);
     loop {
-        let reply = rprompt::prompt_reply_stdout(&prompt).unwrap();
+        let reply = prompt_reply(&prompt);
         if let Some(choice) = parse_choice(&reply, &choices) {
             return choice;
         }
@@ -78,4 +112,16 @@ fn test_parse_choice() {
     assert_eq!(parse_choice("foo", &choices), Some(Boop::Foo));
     assert_eq!(parse_choice("", &choices), None);
     assert_eq!(parse_choice("blarg", &choices), None);
+    assert_eq!(parse_choice("f", &choices), Some(Boop::Foo));
+    assert_eq!(parse_choice("B", &choices), Some(Boop::Bar));
+
+    let choices = [
+        Choice { name: "use app", value: Boop::Foo },
+        Choice { name: "use dropbox", value: Boop::Bar },
+    ];
+
+    assert_eq!(parse_choice("u", &choices), None);
+    assert_eq!(parse_choice("use ", &choices), None);
+    assert_eq!(parse_choice("use a", &choices), Some(Boop::Foo));
+    assert_eq!(parse_choice("use d", &choices), Some(Boop::Bar));
 }
@@ -1,39 +1,198 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 use glob::Pattern;
 use toml::Value;
 
 use self::super::util;
+use crate::output::complain;
+use crate::file_filter::{FileFilter, SymlinkMode};
+use crate::dir_state::{CompareOptions, DirState, DEFAULT_MTIME_TOLERANCE_SECS};
+use crate::remote::RemoteTarget;
 
-#[derive(Debug, PartialEq)]
+pub const DEFAULT_PLAY_WATCH_SECONDS: u64 = 3;
+
+#[derive(Debug, PartialEq, Default)]
 pub struct AppConfig {
     pub name: String,
     pub path: PathBuf,
     pub play_watch_dir: Option<PathBuf>,
     pub play_path: Option<PathBuf>,
-    pub include_only: Option<Pattern>,
+    pub play_args: Vec<String>,
+    // How long no processes have to be running in the watch dir before we
+    // decide the app has finished, and how long to wait for that at most.
+    pub play_watch_seconds: u64,
+    pub play_max_wait_seconds: Option<u64>,
+    pub file_filter: FileFilter,
     pub dropbox_path: PathBuf,
     pub disabled: bool,
+    // A name shared by related apps, so they can be synced together.
+    pub group: Option<String>,
+    pub delete_authority: Option<String>,
+    pub compare: CompareOptions,
+    pub remote: Option<RemoteTarget>,
+    pub backup_dir: Option<PathBuf>,
+    pub use_trash: bool,
+    // Whether the Dropbox copy is a single zip archive instead of a folder.
+    pub archive: bool,
+    // Whether the Dropbox copy's files are encrypted with a passphrase.
+    pub encrypt: bool,
+    // Whether to re-read every copied file to make sure it arrived intact.
+    pub verify: bool,
+    // A command to run after a sync that changed something.
+    pub after_sync: Option<String>,
 }
 
 impl AppConfig {
-    pub fn validate(&self) {
-        util::ensure_path_exists(&self.path);
+    // Only the delete authority (if any) may delete files from Dropbox, and
+    // nobody may if the app isn't mirrored.
+    pub fn may_delete_from_dropbox(&self, hostname: &str) -> bool {
+        self.compare.mirror && util::may_delete(hostname, self.delete_authority.as_deref())
+    }
+
+    pub fn may_delete_from_app(&self) -> bool {
+        self.compare.mirror
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(problem) = self.encryption_problem() {
+            return Err(format!("The app's {}.", problem));
+        }
+        // The usual reason for this is a removable drive that isn't
+        // plugged in, which is no reason to give up on other apps.
+        util::ensure_path_exists(&self.path).map_err(|e| format!("{} (Is it on a drive that isn't connected?)", e))?;
 
         if !&self.dropbox_path.exists() {
-            std::fs::create_dir_all(&self.dropbox_path).unwrap();
+            std::fs::create_dir_all(&self.dropbox_path).map_err(|e| {
+                format!("Unable to create '{}': {}", self.dropbox_path.to_string_lossy(), e)
+            })?;
+        }
+        util::ensure_path_exists(&self.dropbox_path)
+    }
+
+    // Everything that would stop the app from being synced (or played),
+    // without changing anything. The Dropbox folder doesn't need to exist
+    // yet, since syncing creates it.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = vec![];
+        if !self.path.is_dir() {
+            problems.push(format!("path '{}' is not an existing folder", self.path.to_string_lossy()));
         }
-        util::ensure_path_exists(&self.dropbox_path);
+        if self.dropbox_path.exists() && !self.dropbox_path.is_dir() {
+            problems.push(format!("dropbox_path '{}' is not a folder", self.dropbox_path.to_string_lossy()));
+        }
+        if let Some(problem) = self.encryption_problem() {
+            problems.push(problem);
+        }
+        if let Some(play_path) = &self.play_path {
+            if !play_path.is_file() {
+                problems.push(format!("play_path '{}' is not an existing file", play_path.to_string_lossy()));
+            }
+        }
+        problems
+    }
+
+    // Encryption only works with a plain folder in Dropbox.
+    fn encryption_problem(&self) -> Option<String> {
+        match (self.encrypt, self.archive, &self.remote) {
+            (true, true, _) => Some(String::from("encrypt and archive settings can't be used together")),
+            (true, _, Some(_)) => Some(String::from("encrypt and remote_host settings can't be used together")),
+            _ => None,
+        }
+    }
+
+    pub fn scan_path(&self) -> DirState {
+        DirState::from_dir(&self.path, &self.file_filter).with_compare_options(self.compare)
+    }
+
+    pub fn scan_dropbox_path(&self) -> DirState {
+        DirState::from_dir(&self.dropbox_path, &self.file_filter).with_compare_options(self.compare)
+    }
+
+    // A fresh, timestamped folder to back files up into before a sync, if
+    // backups are enabled.
+    pub fn new_backup_path(&self) -> Option<PathBuf> {
+        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.backup_dir.as_ref().map(|dir| dir.join(format!("{}-{}", self.name, timestamp)))
+    }
+}
+
+// The TOML config, unless there's only a JSON one.
+pub fn get_config_path(dropbox_dir: &Path, profile: Option<&str>) -> PathBuf {
+    let stem = match profile {
+        Some(name) => format!("dropsync.{}", name),
+        None => String::from("dropsync"),
+    };
+    let toml_path = dropbox_dir.join(format!("{}.toml", stem));
+    let json_path = dropbox_dir.join(format!("{}.json", stem));
+    if !toml_path.exists() && json_path.exists() {
+        json_path
+    } else {
+        toml_path
     }
 }
 
+// The table of settings that apply to every app that doesn't set them
+// itself.
+const DEFAULTS_KEY: &str = "defaults";
+
+// Fills in whatever the app doesn't set from the defaults. Tables, like
+// overrides for particular hosts, aren't inherited, since they'd then take
+// precedence over the app's own settings.
+fn with_defaults(app_config: &Value, defaults: Option<&toml::value::Table>) -> Value {
+    let mut app_table = app_config.as_table().cloned().unwrap_or_default();
+    for (key, value) in defaults.into_iter().flatten() {
+        if !value.is_table() && !app_table.contains_key(key) {
+            app_table.insert(key.clone(), value.clone());
+        }
+    }
+    Value::Table(app_table)
+}
+
 fn normalize_path_slashes(path: &str) -> String {
-    return path.replace("/", &std::path::MAIN_SEPARATOR.to_string());
+    path.replace('/', std::path::MAIN_SEPARATOR_STR)
+}
+
+// The table of overrides for the given host: either the one named after
+// it, or failing that, one that lists it in its `hosts` key, so several
+// similar computers can share the same overrides. Names match with or
+// without a domain like `.local`, since that depends on the OS.
+fn get_host_config<'a>(config: &'a Value, hostname: &str) -> Option<&'a toml::value::Table> {
+    if let Some(Value::Table(table)) = config.get(hostname) {
+        return Some(table);
+    }
+    let tables = config.as_table()?;
+    let named = tables.iter().find(|(name, value)| value.is_table() && util::is_same_host(name, hostname));
+    if let Some((_, Value::Table(table))) = named {
+        return Some(table);
+    }
+    tables.values().filter_map(Value::as_table).find(|table| {
+        matches!(table.get("hosts"), Some(Value::Array(hosts)) if hosts.iter().any(|host| host.as_str().is_some_and(|host| util::is_same_host(host, hostname))))
+    })
+}
+
+// Every host that some app has overrides for.
+fn get_known_hostnames(apps: &toml::value::Table) -> BTreeSet<&str> {
+    let mut hostnames = BTreeSet::new();
+    for (name, app_config) in apps.iter() {
+        if name == DEFAULTS_KEY {
+            continue;
+        }
+        for (key, value) in app_config.as_table().into_iter().flatten() {
+            match value.as_table().and_then(|table| table.get("hosts")) {
+                Some(Value::Array(hosts)) => hostnames.extend(hosts.iter().filter_map(Value::as_str)),
+                Some(_) => {},
+                None if value.is_table() => { hostnames.insert(key.as_str()); },
+                None => {},
+            }
+        }
+    }
+    hostnames
 }
 
 fn get_app_config_bool(config: &Value, hostname: &str, key: &str, default: bool) -> bool {
-    let host_config = config.get(hostname);
-    if let Some(Value::Table(table)) = host_config {
+    if let Some(table) = get_host_config(config, hostname) {
         if let Some(Value::Boolean(s)) = table.get(key) {
             return *s;
         }
@@ -44,9 +203,20 @@ fn get_app_config_bool(config: &Value, hostname: &str, key: &str, default: bool)
     default
 }
 
+fn get_app_config_int(config: &Value, hostname: &str, key: &str, default: i64) -> i64 {
+    if let Some(table) = get_host_config(config, hostname) {
+        if let Some(Value::Integer(i)) = table.get(key) {
+            return *i;
+        }
+    }
+    if let Some(Value::Integer(i)) = config.get(key) {
+        return *i;
+    }
+    default
+}
+
 fn get_optional_app_config_str<'a>(config: &'a Value, hostname: &str, key: &str) -> Option<&'a str> {
-    let host_config = config.get(hostname);
-    if let Some(Value::Table(table)) = host_config {
+    if let Some(table) = get_host_config(config, hostname) {
         if let Some(Value::String(s)) = table.get(key) {
             return Some(s);
         }
@@ -57,62 +227,229 @@ fn get_optional_app_config_str<'a>(config: &'a Value, hostname: &str, key: &str)
     None
 }
 
-fn get_app_config_str<'a>(config: &'a Value, app_name: &str, hostname: &str, key: &str) -> &'a str {
-    if let Some(s) = get_optional_app_config_str(config, hostname, key) {
-        s
+fn get_optional_app_config_str_array<'a>(config: &'a Value, hostname: &str, key: &str) -> Option<Vec<&'a str>> {
+    let value = if let Some(table) = get_host_config(config, hostname) {
+        table.get(key).or_else(|| config.get(key))
+    } else {
+        config.get(key)
+    };
+    if let Some(Value::Array(array)) = value {
+        return Some(array.iter().filter_map(|item| item.as_str()).collect());
+    }
+    None
+}
+
+// Whether the app is turned off on this computer, either outright or
+// because it's only meant for (or never meant for) certain computers.
+fn is_app_disabled(config: &Value, hostname: &str) -> bool {
+    let lists_host = |key| get_optional_app_config_str_array(config, hostname, key)
+        .map(|hosts| hosts.iter().any(|host| util::is_same_host(host, hostname)));
+    get_app_config_bool(config, hostname, "disabled", false)
+        || lists_host("enabled_hosts") == Some(false)
+        || lists_host("disabled_hosts") == Some(true)
+}
+
+fn get_app_config_str<'a>(config: &'a Value, app_name: &str, hostname: &str, key: &str) -> Result<&'a str, String> {
+    get_optional_app_config_str(config, hostname, key).ok_or_else(|| {
+        format!("Unable to find config key '{}' for app '{}' and hostname '{}'!", key, app_name, hostname)
+    })
+}
+
+fn parse_pattern(pattern: &str, app_name: &str, key: &str) -> Result<Pattern, String> {
+    Pattern::new(pattern).map_err(|e| format!("Invalid pattern '{}' in {} for app '{}': {}", pattern, key, app_name, e))
+}
+
+// Editors like Notepad start files with a byte order mark, which parsers
+// choke on, and use Windows line endings, which are easier to reason about
+// once they're gone. Returns the cleaned-up text, and whether it had a BOM.
+fn normalize_config_text(text: &str) -> (String, bool) {
+    let without_bom = text.strip_prefix('\u{feff}');
+    (without_bom.unwrap_or(text).replace("\r\n", "\n"), without_bom.is_some())
+}
+
+fn describe_parse_error(error: impl std::fmt::Display, had_bom: bool) -> String {
+    if had_bom {
+        format!("Unable to parse config (a byte order mark was detected at its start and ignored, so it's probably been edited on Windows): {}", error)
+    } else {
+        format!("Unable to parse config: {}", error)
+    }
+}
+
+pub fn load_config(hostname: &str, config_toml: &str, root_dropbox_path: &Path) -> Result<HashMap<String, AppConfig>, String> {
+    let (config_toml, had_bom) = normalize_config_text(config_toml);
+    // The error's description includes the line and column of the problem.
+    let config = config_toml.parse::<Value>().map_err(|e| describe_parse_error(e, had_bom))?;
+    load_config_value(hostname, config, root_dropbox_path)
+}
+
+// Like `load_config`, but for a JSON config with the same structure, e.g.
+// one that was generated by another program.
+pub fn load_json_config(hostname: &str, config_json: &str, root_dropbox_path: &Path) -> Result<HashMap<String, AppConfig>, String> {
+    let (config_json, had_bom) = normalize_config_text(config_json);
+    let config = serde_json::from_str::<Value>(&config_json).map_err(|e| describe_parse_error(e, had_bom))?;
+    load_config_value(hostname, config, root_dropbox_path)
+}
+
+// Loads the config file at the given path, in whichever format its
+// extension says it's in.
+pub fn load_config_file(hostname: &str, path: &Path, root_dropbox_path: &Path) -> Result<HashMap<String, AppConfig>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| format!("Unable to read config: {}", e))?;
+    if path.extension().is_some_and(|extension| extension == "json") {
+        load_json_config(hostname, &contents, root_dropbox_path)
     } else {
-        let err = format!("Unable to find config key '{}' for app '{}' and hostname '{}'!", key, app_name, hostname);
-        panic!("{}", err);
+        load_config(hostname, &contents, root_dropbox_path)
     }
 }
 
-pub fn load_config(hostname: &str, config_toml: &str, root_dropbox_path: &PathBuf) -> HashMap<String, AppConfig> {
-    let config = config_toml.parse::<Value>().unwrap();
+fn load_config_value(hostname: &str, config: Value, root_dropbox_path: &Path) -> Result<HashMap<String, AppConfig>, String> {
     let mut result = HashMap::new();
     if let Value::Table(table) = config {
+        // The top-level `dropbox_dir` setting relocates where all the apps' Dropbox paths live.
+        let root_dropbox_path = match table.get("dropbox_dir").and_then(Value::as_str) {
+            Some(dir) => root_dropbox_path.join(util::expand_path(dir)),
+            None => root_dropbox_path.to_path_buf(),
+        };
+        let defaults = table.get(DEFAULTS_KEY).and_then(Value::as_table);
+        // A typo in a host's name means its overrides quietly never apply.
+        let known_hostnames = get_known_hostnames(&table);
+        if !known_hostnames.is_empty() && !known_hostnames.iter().any(|known| util::is_same_host(known, hostname)) {
+            let known_hostnames: Vec<&str> = known_hostnames.into_iter().collect();
+            complain!("Warning: The config has settings for {}, but none for this computer, {}.", known_hostnames.join(", "), hostname);
+        }
         for entry in table.iter() {
             let (name, app_config) = entry;
-            let path = PathBuf::from(get_app_config_str(app_config, name, hostname, "path"));
-            let norm_dropbox_path = normalize_path_slashes(get_app_config_str(app_config, name, hostname, "dropbox_path"));
+            if !app_config.is_table() || name == DEFAULTS_KEY {
+                continue;
+            }
+            let app_config = &with_defaults(app_config, defaults);
+            let path = PathBuf::from(util::expand_path(get_app_config_str(app_config, name, hostname, "path")?));
+            let norm_dropbox_path = normalize_path_slashes(&util::expand_path(get_app_config_str(app_config, name, hostname, "dropbox_path")?));
             let rel_dropbox_path = PathBuf::from(norm_dropbox_path);
             let dropbox_path = root_dropbox_path.join(rel_dropbox_path);
-            let disabled = get_app_config_bool(app_config, hostname, "disabled", false);
-            let play_root_path = if let Some(play_root_path_str) = get_optional_app_config_str(app_config, hostname, "play_root_path") {
-                Some(PathBuf::from(play_root_path_str))
-            } else {
-                None
+            let disabled = is_app_disabled(app_config, hostname);
+            let group = get_optional_app_config_str(app_config, hostname, "group").map(String::from);
+            let play_root_path = get_optional_app_config_str(app_config, hostname, "play_root_path")
+                .map(|play_root_path_str| PathBuf::from(util::expand_path(play_root_path_str)));
+            let play_path = get_optional_app_config_str(app_config, hostname, "play_path")
+                .map(|play_path_str| maybe_join_paths(&play_root_path, normalize_path_slashes(&util::expand_path(play_path_str)).into()));
+            // If the executable isn't under the root at all, the root can't
+            // tell us anything about whether it's running, so just watch
+            // the executable's own folder.
+            let play_watch_dir = play_root_path.filter(|root| play_path.as_ref().is_none_or(|play_path| play_path.starts_with(root)));
+            let play_args = get_optional_app_config_str_array(app_config, hostname, "play_args")
+                .unwrap_or_default()
+                .iter()
+                .map(|arg| util::expand_path(arg))
+                .collect();
+            let play_watch_seconds = get_app_config_int(app_config, hostname, "play_watch_seconds", DEFAULT_PLAY_WATCH_SECONDS as i64).max(0) as u64;
+            let play_max_wait_seconds = match get_app_config_int(app_config, hostname, "play_max_wait_seconds", -1) {
+                seconds if seconds < 0 => None,
+                seconds => Some(seconds as u64),
+            };
+            let include_only = get_optional_app_config_str(app_config, hostname, "include_only")
+                .map(|include_only_str| parse_pattern(include_only_str, name, "include_only"))
+                .transpose()?;
+            let include_extensions = get_optional_app_config_str_array(app_config, hostname, "include_extensions")
+                .unwrap_or_default()
+                .iter()
+                .map(|extension| String::from(*extension))
+                .collect();
+            let exclude = get_optional_app_config_str_array(app_config, hostname, "exclude")
+                .unwrap_or_default()
+                .iter()
+                .map(|exclude_str| parse_pattern(exclude_str, name, "exclude"))
+                .collect::<Result<Vec<Pattern>, String>>()?;
+            let symlinks = get_optional_app_config_str(app_config, hostname, "symlinks")
+                .map(|symlinks_str| SymlinkMode::parse(symlinks_str).ok_or_else(|| {
+                    format!("symlinks for app '{}' should be \"skip\", \"copy\" or \"follow\"!", name)
+                }))
+                .transpose()?
+                .unwrap_or_default();
+            let exclude_os_files = get_app_config_bool(app_config, hostname, "exclude_os_files", true);
+            let exclude_conflicted_copies = get_app_config_bool(app_config, hostname, "exclude_conflicted_copies", true);
+            let get_non_negative = |key| match get_app_config_int(app_config, hostname, key, -1) {
+                size if size < 0 => None,
+                size => Some(size as u64),
             };
-            let play_path = if let Some(play_path_str) = get_optional_app_config_str(app_config, hostname, "play_path") {
-                Some(maybe_join_paths(&play_root_path, normalize_path_slashes(play_path_str).into()))
-            } else {
-                None
+            let (min_file_size, max_file_size) = (get_non_negative("min_file_size"), get_non_negative("max_file_size"));
+            let min_file_age_secs = get_non_negative("min_file_age_secs");
+            let file_filter = FileFilter::new(include_only, exclude).with_extensions(include_extensions).with_symlinks(symlinks).with_os_files_excluded(exclude_os_files).with_conflicted_copies_excluded(exclude_conflicted_copies).with_size_limits(min_file_size, max_file_size).with_min_age(min_file_age_secs);
+            let delete_authority = get_optional_app_config_str(app_config, hostname, "delete_authority")
+                .map(String::from);
+            let compare = CompareOptions {
+                compare_hashes: get_app_config_bool(app_config, hostname, "compare_hashes", false),
+                paranoid: get_app_config_bool(app_config, hostname, "paranoid_compare", false),
+                mtime_tolerance_secs: get_app_config_int(app_config, hostname, "mtime_tolerance_secs", DEFAULT_MTIME_TOLERANCE_SECS as i64).max(0) as u64,
+                mirror: get_app_config_bool(app_config, hostname, "mirror", true),
             };
-            let include_only = if let Some(include_only_str) = get_optional_app_config_str(app_config, hostname, "include_only") {
-                Some(Pattern::new(include_only_str).unwrap())
-            } else {
-                None
+            let remote = match get_optional_app_config_str(app_config, hostname, "remote_host") {
+                Some(host) => Some(RemoteTarget {
+                    host: String::from(host),
+                    path: PathBuf::from(get_app_config_str(app_config, name, hostname, "remote_path")?),
+                }),
+                None => None,
             };
+            let backup_dir = get_optional_app_config_str(app_config, hostname, "backup_dir")
+                .map(|backup_dir_str| PathBuf::from(util::expand_path(backup_dir_str)));
+            let use_trash = get_app_config_bool(app_config, hostname, "use_trash", false);
+            let archive = get_app_config_bool(app_config, hostname, "archive", false);
+            let verify = get_app_config_bool(app_config, hostname, "verify", false);
+            let after_sync = get_optional_app_config_str(app_config, hostname, "after_sync").map(util::expand_path);
+            let encrypt = get_app_config_bool(app_config, hostname, "encrypt", false);
             result.insert(name.clone(), AppConfig {
                 name: name.clone(),
                 path,
                 dropbox_path,
                 disabled,
+                group,
                 play_path,
-                play_watch_dir: play_root_path,
-                include_only
+                play_args,
+                play_watch_dir,
+                play_watch_seconds,
+                play_max_wait_seconds,
+                file_filter,
+                delete_authority,
+                compare,
+                remote,
+                backup_dir,
+                use_trash,
+                archive,
+                encrypt,
+                verify,
+                after_sync,
             });
         }
     } else {
-        panic!("The top-level value of a config file should be a table!");
+        return Err(String::from("The top-level value of a config file should be a table!"));
     }
-    result
+    check_dropbox_paths(&result)?;
+    Ok(result)
 }
 
+// Two apps syncing into the same Dropbox folder (or one inside the
+// other's) would clobber each other's files.
+fn check_dropbox_paths(configs: &HashMap<String, AppConfig>) -> Result<(), String> {
+    let mut apps: Vec<&AppConfig> = configs.values().filter(|app| !app.disabled).collect();
+    apps.sort_by(|a, b| a.name.cmp(&b.name));
+    for (i, app) in apps.iter().enumerate() {
+        for other in apps[i + 1..].iter() {
+            if app.dropbox_path.starts_with(&other.dropbox_path) || other.dropbox_path.starts_with(&app.dropbox_path) {
+                return Err(format!(
+                    "Apps '{}' and '{}' have overlapping dropbox_paths ('{}' and '{}'), so they'd overwrite each other's files!",
+                    app.name, other.name, app.dropbox_path.to_string_lossy(), other.dropbox_path.to_string_lossy()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// An absolute `second` is used as-is, rather than relying on what joining
+// it does, which varies by platform.
 fn maybe_join_paths(first: &Option<PathBuf>, second: PathBuf) -> PathBuf {
-    if let Some(root_path) = first {
-        root_path.join(second)
-    } else {
-        second
+    match first {
+        Some(root_path) if !second.is_absolute() => root_path.join(second),
+        _ => second,
     }
 }
 
@@ -121,21 +458,299 @@ fn test_load_config() {
     use std::fs;
 
     let toml_str = fs::read_to_string("test-data/sample_config.toml").expect("example config file should exist!");
-    let configs = load_config("my_first_computer", &toml_str, &PathBuf::from("."));
+    let configs = load_config("my_first_computer", &toml_str, &PathBuf::from(".")).unwrap();
 
     let mut expected = HashMap::new();
     expected.insert(
         String::from("app1"),
-        AppConfig { name: String::from("app1"), path: PathBuf::from("C:\\myapp1\\stuff"), dropbox_path: PathBuf::from("./MyAppData/app1"), disabled: false, play_path: None, play_watch_dir: None, include_only: None }
+        AppConfig { name: String::from("app1"), path: PathBuf::from("C:\\myapp1\\stuff"), dropbox_path: PathBuf::from("./MyAppData/app1"), disabled: false, group: None, play_path: None, play_args: vec![], play_watch_dir: None, play_watch_seconds: DEFAULT_PLAY_WATCH_SECONDS, play_max_wait_seconds: None, file_filter: FileFilter::new(None, vec![Pattern::new("Thumbs.db").unwrap(), Pattern::new("cache/").unwrap()]), delete_authority: None, compare: CompareOptions::default(), remote: None, backup_dir: None, use_trash: false, archive: false, encrypt: false, verify: false, after_sync: None }
     );
     expected.insert(
         String::from("app2"),
-        AppConfig { name: String::from("app2"), path: PathBuf::from("F:\\myapp2\\stuff"), dropbox_path: PathBuf::from("./MyAppData/app2"), disabled: false, play_path: None, play_watch_dir: None, include_only: None }
+        AppConfig { name: String::from("app2"), path: PathBuf::from("F:\\myapp2\\stuff"), dropbox_path: PathBuf::from("./MyAppData/app2"), disabled: false, group: None, play_path: None, play_args: vec![], play_watch_dir: None, play_watch_seconds: DEFAULT_PLAY_WATCH_SECONDS, play_max_wait_seconds: None, file_filter: FileFilter::new(Some(Pattern::new("*.sav").unwrap()), vec![]), delete_authority: None, compare: CompareOptions::default(), remote: None, backup_dir: None, use_trash: false, archive: false, encrypt: false, verify: false, after_sync: None }
     );
     expected.insert(
         String::from("app3"),
-        AppConfig { name: String::from("app3"), path: PathBuf::from("G:\\app3"), dropbox_path: PathBuf::from("./MyAppData/app3"), disabled: true, play_path: None, play_watch_dir: None, include_only: None }
+        AppConfig { name: String::from("app3"), path: PathBuf::from("G:\\app3"), dropbox_path: PathBuf::from("./MyAppData/app3"), disabled: true, group: None, play_path: None, play_args: vec![], play_watch_dir: None, play_watch_seconds: DEFAULT_PLAY_WATCH_SECONDS, play_max_wait_seconds: None, file_filter: FileFilter::default(), delete_authority: None, compare: CompareOptions::default(), remote: None, backup_dir: None, use_trash: false, archive: false, encrypt: false, verify: false, after_sync: None }
     );
 
     assert_eq!(expected, configs);
 }
+
+#[test]
+fn test_load_config_dropbox_dir() {
+    let toml_str = r#"
+        dropbox_dir = "staging"
+
+        [game1]
+        path = "/games/game1"
+        dropbox_path = "Games/game1"
+    "#;
+    let configs = load_config("my_computer", toml_str, &PathBuf::from("/dropbox")).unwrap();
+    assert_eq!(configs.len(), 1);
+    assert_eq!(configs["game1"].dropbox_path, PathBuf::from("/dropbox/staging/Games/game1"));
+}
+
+#[test]
+fn test_load_config_extras() {
+    let toml_str = r#"
+        [game1]
+        path = "/games/game1"
+        dropbox_path = "Games/game1"
+        play_watch_seconds = 10
+        play_max_wait_seconds = 600
+        play_args = ["--profile", "atul", "~/game1.ini"]
+        group = "games"
+        include_extensions = ["sav", "cfg"]
+        max_file_size = 1000
+        encrypt = true
+    "#;
+    let configs = load_config("my_computer", toml_str, &PathBuf::from("/dropbox")).unwrap();
+    let ini_path = dirs::home_dir().unwrap().join("game1.ini").to_string_lossy().to_string();
+    assert_eq!(configs["game1"].play_args, vec![String::from("--profile"), String::from("atul"), ini_path]);
+    assert_eq!(configs["game1"].play_watch_seconds, 10);
+    assert_eq!(configs["game1"].play_max_wait_seconds, Some(600));
+    assert_eq!(configs["game1"].group.as_deref(), Some("games"));
+    assert!(configs["game1"].file_filter.is_path_included(Path::new("/games/game1/SLOT1.SAV"), false));
+    assert!(!configs["game1"].file_filter.is_path_included(Path::new("/games/game1/log.txt"), false));
+    assert!(configs["game1"].file_filter.is_size_included(1000));
+    assert!(!configs["game1"].file_filter.is_size_included(1001));
+    assert!(configs["game1"].encrypt);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_load_config_play_paths() {
+    let toml_str = r#"
+        [game1]
+        path = "/games/game1"
+        dropbox_path = "Games/game1"
+        play_root_path = "/games/game1"
+        play_path = "bin/game1"
+
+        [game2]
+        path = "/games/game2"
+        dropbox_path = "Games/game2"
+        play_root_path = "/games/game2"
+        play_path = "/games/game2/bin/game2"
+
+        [game3]
+        path = "/games/game3"
+        dropbox_path = "Games/game3"
+        play_root_path = "/games/launcher"
+        play_path = "/opt/game3/game3"
+    "#;
+    let configs = load_config("my_computer", toml_str, &PathBuf::from("/dropbox")).unwrap();
+    assert_eq!(configs["game1"].play_path, Some(PathBuf::from("/games/game1/bin/game1")));
+    assert_eq!(configs["game1"].play_watch_dir, Some(PathBuf::from("/games/game1")));
+    assert_eq!(configs["game2"].play_path, Some(PathBuf::from("/games/game2/bin/game2")));
+    assert_eq!(configs["game2"].play_watch_dir, Some(PathBuf::from("/games/game2")));
+    assert_eq!(configs["game3"].play_path, Some(PathBuf::from("/opt/game3/game3")));
+    assert_eq!(configs["game3"].play_watch_dir, None);
+}
+
+#[test]
+fn test_load_config_errors() {
+    let root = PathBuf::from("/dropbox");
+    let error = load_config("my_computer", "[game1]\npath = \"/games/game1\"\n", &root).unwrap_err();
+    assert!(error.contains("'dropbox_path'") && error.contains("'game1'"), "{}", error);
+
+    let error = load_config("my_computer", "[game1]\npath = \n", &root).unwrap_err();
+    assert!(error.contains("line 2"), "{}", error);
+
+    let error = load_config("my_computer", "[game1]\npath = \"a\"\ndropbox_path = \"b\"\nsymlinks = \"maybe\"\n", &root).unwrap_err();
+    assert!(error.contains("symlinks"), "{}", error);
+
+    let error = load_config("my_computer", "[game1]\npath = \"a\"\ndropbox_path = \"Games/a\"\n[game2]\npath = \"b\"\ndropbox_path = \"./Games/a\"\n", &root).unwrap_err();
+    assert!(error.contains("'game1' and 'game2'"), "{}", error);
+    let error = load_config("my_computer", "[game1]\npath = \"a\"\ndropbox_path = \"Games\"\n[game2]\npath = \"b\"\ndropbox_path = \"Games/b\"\n", &root).unwrap_err();
+    assert!(error.contains("'game1' and 'game2'"), "{}", error);
+    // Folders that merely start the same way are fine, as are disabled apps.
+    assert!(load_config("my_computer", "[game1]\npath = \"a\"\ndropbox_path = \"Games/a\"\n[game2]\npath = \"b\"\ndropbox_path = \"Games/ab\"\n", &root).is_ok());
+    assert!(load_config("my_computer", "[game1]\npath = \"a\"\ndropbox_path = \"Games/a\"\n[game2]\npath = \"b\"\ndropbox_path = \"Games/a\"\ndisabled = true\n", &root).is_ok());
+}
+
+#[test]
+fn test_load_config_defaults() {
+    let toml_str = r#"
+        [defaults]
+        exclude = ["*.log"]
+        mtime_tolerance_secs = 5
+        use_trash = true
+
+        [game1]
+        path = "/games/game1"
+        dropbox_path = "Games/game1"
+
+        [game2]
+        path = "/games/game2"
+        dropbox_path = "Games/game2"
+        use_trash = false
+        mirror = false
+
+        [game2.my_computer]
+        mtime_tolerance_secs = 0
+    "#;
+    let configs = load_config("my_computer", toml_str, &PathBuf::from("/dropbox")).unwrap();
+    assert_eq!(configs.len(), 2);
+    assert!(!configs["game1"].file_filter.is_path_included(Path::new("foo/debug.log"), false));
+    assert!(!configs["game2"].file_filter.is_path_included(Path::new("foo/debug.log"), false));
+    assert_eq!(configs["game1"].compare.mtime_tolerance_secs, 5);
+    assert_eq!(configs["game2"].compare.mtime_tolerance_secs, 0);
+    assert!(configs["game1"].use_trash);
+    assert!(!configs["game2"].use_trash);
+    assert!(!configs["game1"].compare.compare_hashes);
+    assert!(!configs["game1"].compare.paranoid);
+    assert!(configs["game1"].may_delete_from_app());
+    assert!(!configs["game2"].may_delete_from_app());
+    assert!(!configs["game2"].may_delete_from_dropbox("my_computer"));
+
+    let configs = load_config("other_computer", toml_str, &PathBuf::from("/dropbox")).unwrap();
+    assert_eq!(configs["game2"].compare.mtime_tolerance_secs, 5);
+}
+
+#[test]
+fn test_load_config_shared_hosts() {
+    let toml_str = r#"
+        [game1]
+        path = "/games/game1"
+        dropbox_path = "Games/game1"
+
+        [game1.laptops]
+        hosts = ["laptop-a", "laptop-b"]
+        path = "/home/atul/game1"
+
+        [game1.laptop-b]
+        path = "/special/game1"
+    "#;
+    let path_on = |hostname| load_config(hostname, toml_str, &PathBuf::from("/dropbox")).unwrap().remove("game1").unwrap().path;
+    assert_eq!(path_on("laptop-a"), PathBuf::from("/home/atul/game1"));
+    // A section named after the host takes precedence over a shared one.
+    assert_eq!(path_on("laptop-b"), PathBuf::from("/special/game1"));
+    assert_eq!(path_on("desktop"), PathBuf::from("/games/game1"));
+    assert_eq!(path_on("LAPTOP-A.local"), PathBuf::from("/home/atul/game1"));
+    // Some OSes include a domain in the hostname and others don't.
+    let toml_str = toml_str.replace("game1.laptop-b", "game1.\"laptop-b.local\"");
+    assert_eq!(load_config("laptop-b", &toml_str, &PathBuf::from("/dropbox")).unwrap()["game1"].path, PathBuf::from("/special/game1"));
+}
+
+#[test]
+fn test_load_config_host_lists() {
+    let toml_str = r#"
+        [tool]
+        path = "/tools/tool"
+        dropbox_path = "Tools/tool"
+        enabled_hosts = ["desktop", "laptop"]
+
+        [tool.laptop]
+        disabled = true
+
+        [game1]
+        path = "/games/game1"
+        dropbox_path = "Games/game1"
+        disabled_hosts = ["work-laptop"]
+    "#;
+    let disabled_on = |hostname, app| load_config(hostname, toml_str, &PathBuf::from("/dropbox")).unwrap()[app].disabled;
+    assert!(!disabled_on("desktop", "tool"));
+    assert!(!disabled_on("DESKTOP.local", "tool"));
+    assert!(disabled_on("work-laptop", "tool"));
+    // Being listed doesn't override `disabled`.
+    assert!(disabled_on("laptop", "tool"));
+    assert!(disabled_on("work-laptop", "game1"));
+    assert!(!disabled_on("desktop", "game1"));
+}
+
+#[test]
+fn test_get_known_hostnames() {
+    let table = |toml_str: &str| toml_str.parse::<Value>().unwrap().as_table().unwrap().clone();
+    let apps = table(r#"
+        [defaults.ignored]
+        path = "/blah"
+
+        [game1]
+        path = "/games/game1"
+
+        [game1.laptops]
+        hosts = ["laptop-a", "laptop-b"]
+
+        [game1.desktop]
+        path = "/special/game1"
+
+        [game2.desktop]
+        path = "/special/game2"
+    "#);
+    assert_eq!(get_known_hostnames(&apps).into_iter().collect::<Vec<&str>>(), vec!["desktop", "laptop-a", "laptop-b"]);
+    assert!(get_known_hostnames(&table("[game1]\npath = \"/games/game1\"\n")).is_empty());
+}
+
+#[test]
+fn test_problems() {
+    let app = AppConfig {
+        path: PathBuf::from("test-data/dirstate_test"),
+        dropbox_path: PathBuf::from("test-data/nonexistent"),
+        ..Default::default()
+    };
+    assert!(app.problems().is_empty());
+
+    let app = AppConfig {
+        path: PathBuf::from("test-data/nonexistent"),
+        dropbox_path: PathBuf::from("test-data/dirstate_test/boop"),
+        play_path: Some(PathBuf::from("test-data/dirstate_test")),
+        ..Default::default()
+    };
+    assert_eq!(app.problems().len(), 3);
+    assert!(app.validate().unwrap_err().contains("isn't connected"));
+
+    let app = AppConfig {
+        path: PathBuf::from("test-data/dirstate_test"),
+        dropbox_path: PathBuf::from("test-data/nonexistent"),
+        encrypt: true,
+        archive: true,
+        ..Default::default()
+    };
+    assert_eq!(app.problems(), vec![String::from("encrypt and archive settings can't be used together")]);
+    assert!(app.validate().unwrap_err().contains("can't be used together"));
+}
+
+#[test]
+fn test_get_config_path() {
+    let profiles_dir = PathBuf::from("test-data/profiles");
+    assert_eq!(get_config_path(&profiles_dir, None), profiles_dir.join("dropsync.toml"));
+    assert_eq!(get_config_path(&profiles_dir, Some("work")), profiles_dir.join("dropsync.work.toml"));
+    assert_eq!(get_config_path(&profiles_dir, Some("generated")), profiles_dir.join("dropsync.generated.json"));
+
+    let app_names = |profile| {
+        let mut names: Vec<String> = load_config_file("my_computer", &get_config_path(&profiles_dir, profile), &profiles_dir).unwrap().into_keys().collect();
+        names.sort();
+        names
+    };
+    assert_eq!(app_names(None), vec!["game1", "game2"]);
+    assert_eq!(app_names(Some("work")), vec!["editor"]);
+    assert_eq!(app_names(Some("generated")), vec!["game3"]);
+}
+
+#[test]
+fn test_load_config_with_bom() {
+    let toml_str = "\u{feff}[game1]\r\npath = \"/games/game1\"\r\ndropbox_path = \"game1\"\r\n";
+    let configs = load_config("my_computer", toml_str, &PathBuf::from("/dropbox")).unwrap();
+    assert_eq!(configs["game1"].path, PathBuf::from("/games/game1"));
+
+    let error = load_config("my_computer", "\u{feff}[game1\r\n", &PathBuf::from("/dropbox")).unwrap_err();
+    assert!(error.contains("byte order mark"));
+    assert!(!load_config("my_computer", "[game1\n", &PathBuf::from("/dropbox")).unwrap_err().contains("byte order mark"));
+    assert!(load_json_config("my_computer", "\u{feff}{}", &PathBuf::from("/dropbox")).is_ok());
+}
+
+#[test]
+fn test_load_json_config() {
+    let json_str = fs::read_to_string("test-data/profiles/dropsync.generated.json").unwrap();
+    let configs = load_json_config("my_computer", &json_str, &PathBuf::from("/dropbox")).unwrap();
+    assert_eq!(configs["game3"].path, PathBuf::from("D:\\game3\\saves"));
+    assert_eq!(configs["game3"].dropbox_path, PathBuf::from("/dropbox/Games/game3"));
+    assert!(!configs["game3"].file_filter.is_path_included(Path::new("foo/debug.log"), false));
+    let configs = load_json_config("other_computer", &json_str, &PathBuf::from("/dropbox")).unwrap();
+    assert_eq!(configs["game3"].path, PathBuf::from("C:\\game3\\saves"));
+
+    assert!(load_json_config("my_computer", "{", &PathBuf::from("/dropbox")).unwrap_err().contains("line 1"));
+    assert!(load_json_config("my_computer", "[]", &PathBuf::from("/dropbox")).is_err());
+}
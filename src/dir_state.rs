@@ -1,54 +1,474 @@
 use std::time::SystemTime;
-use std::path::PathBuf;
-use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
+use std::fmt;
+use std::io;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::Duration;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::file_filter::FileFilter;
+use crate::file_filter::{FileFilter, SymlinkMode, RESERVED_PREFIX};
+use crate::{hash, interrupt, SyncResult};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Serialize, Deserialize)]
 struct FileState {
     pub modified: u64,
+    // The fraction of a second on top of `modified`, if we know it. Things
+    // like remote listings, archives and older snapshots only have whole
+    // seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub modified_nanos: Option<u32>,
     pub size: u64,
+    // Only computed if we actually need to compare contents. It's saved
+    // along with snapshots, so files that haven't changed since don't
+    // need to be hashed again.
+    #[serde(default, skip_serializing_if = "is_hash_unknown", with = "serde_hash")]
+    hash: OnceLock<Option<hash::Hash>>,
+    // Where the file points, if it's a symlink that we're copying as-is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    link_target: Option<PathBuf>,
+}
+
+fn is_hash_unknown(hash: &OnceLock<Option<hash::Hash>>) -> bool {
+    !matches!(hash.get(), Some(Some(_)))
+}
+
+mod serde_hash {
+    use std::sync::OnceLock;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::hash::Hash;
+
+    pub fn serialize<S: Serializer>(hash: &OnceLock<Option<Hash>>, serializer: S) -> Result<S::Ok, S::Error> {
+        match hash.get() {
+            Some(Some(hash)) => serializer.serialize_some(hash.to_hex().as_str()),
+            _ => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<OnceLock<Option<Hash>>, D::Error> {
+        let hash = OnceLock::new();
+        // A hash we can't make sense of is one we'll just have to compute again.
+        if let Some(hex) = Option::<String>::deserialize(deserializer)? {
+            if let Ok(parsed) = Hash::from_hex(hex) {
+                let _ = hash.set(Some(parsed));
+            }
+        }
+        Ok(hash)
+    }
+}
+
+impl PartialEq for FileState {
+    fn eq(&self, other: &Self) -> bool {
+        self.modified == other.modified && self.modified_nanos == other.modified_nanos && self.size == other.size && self.link_target == other.link_target
+    }
 }
 
 impl FileState {
-    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+    pub fn from_metadata(metadata: &fs::Metadata) -> io::Result<Self> {
         if metadata.is_dir() {
             panic!("Directories are not supported!");
         }
         let size = metadata.len();
-        let modified = metadata.modified().unwrap().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
-        FileState { size, modified }
+        let modified = metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "modified before 1970"))?;
+        Ok(FileState { size, modified: modified.as_secs(), modified_nanos: Some(modified.subsec_nanos()), hash: OnceLock::new(), link_target: None })
+    }
+
+    // Which file was modified later, where times within `tolerance_secs`
+    // of each other count as the same. If either time is only known to the
+    // second, both are compared that way.
+    fn compare_modified(&self, other: &FileState, tolerance_secs: u64) -> CmpOrdering {
+        let (mine, theirs) = match (self.modified_nanos, other.modified_nanos) {
+            (Some(my_nanos), Some(their_nanos)) => (Duration::new(self.modified, my_nanos), Duration::new(other.modified, their_nanos)),
+            _ => (Duration::from_secs(self.modified), Duration::from_secs(other.modified)),
+        };
+        if mine.abs_diff(theirs) <= Duration::from_secs(tolerance_secs) {
+            CmpOrdering::Equal
+        } else {
+            mine.cmp(&theirs)
+        }
+    }
+}
+
+pub const DEFAULT_MTIME_TOLERANCE_SECS: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CompareOptions {
+    // Whether files with the same size but different modification times
+    // should have their contents compared.
+    pub compare_hashes: bool,
+    // Whether files with the same size and modification time should have
+    // their contents compared too, in case something changed them without
+    // changing either.
+    pub paranoid: bool,
+    // Modification times this close together are considered equal, to
+    // account for clock drift between computers.
+    pub mtime_tolerance_secs: u64,
+    // Whether syncing makes one side just like the other, deleting
+    // whatever the other side has that it doesn't. If not, nothing is
+    // ever deleted, so files that are only on one side are always safe.
+    pub mirror: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        CompareOptions { compare_hashes: false, paranoid: false, mtime_tolerance_secs: DEFAULT_MTIME_TOLERANCE_SECS, mirror: true }
     }
 }
 
-#[derive(Debug, PartialEq)]
+impl CompareOptions {
+    // Whether comparing files ever involves hashing them.
+    pub fn uses_hashes(&self) -> bool {
+        self.compare_hashes || self.paranoid
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct DirState {
+    #[serde(skip)]
     file_filter: FileFilter,
+    #[serde(skip)]
+    compare: CompareOptions,
     path: PathBuf,
     files: HashMap<String, FileState>,
+    // Empty directories are kept track of too, so they're synced like
+    // files are: copied if the other side doesn't have them, and deleted
+    // if the side being copied doesn't.
     subdirs: HashMap<String, DirState>,
+    // Anything we couldn't read while scanning, which means the state may
+    // be missing things that are actually on disk.
+    read_errors: Vec<String>,
+}
+
+// The relative paths that copying one directory into another (and then
+// removing its extraneous files) would touch.
+#[derive(Debug, Default, PartialEq)]
+pub struct CopyDiff {
+    pub to_copy: Vec<PathBuf>,
+    pub to_delete: Vec<PathBuf>,
+}
+
+// How to combine two directories file by file, rather than wholesale.
+// All paths are relative and sorted.
+#[derive(Debug, Default, PartialEq)]
+pub struct MergePlan {
+    // Files to copy from this directory into the other one.
+    pub to_other: Vec<PathBuf>,
+    // Files to copy from the other directory into this one.
+    pub from_other: Vec<PathBuf>,
+    pub delete_from_self: Vec<PathBuf>,
+    pub delete_from_other: Vec<PathBuf>,
+    // Files where it's unclear which side should win, so someone needs to
+    // pick one.
+    pub conflicts: Vec<PathBuf>,
+}
+
+// How every file in one directory compares with its counterpart in
+// another, for tools that want to show or act on the differences
+// themselves. All paths are relative and sorted.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DirDiff {
+    pub only_in_self: Vec<PathBuf>,
+    pub only_in_other: Vec<PathBuf>,
+    pub newer_in_self: Vec<PathBuf>,
+    pub newer_in_other: Vec<PathBuf>,
+    // Files that are different on each side, but were modified at about
+    // the same time, so neither is newer.
+    pub differing: Vec<PathBuf>,
+    pub equal: Vec<PathBuf>,
+}
+
+// A file in a flattened directory tree, along with the directory it's in.
+type FlatFile<'a> = (&'a DirState, &'a String, &'a FileState);
+
+// An entry in a directory listing that doesn't come from the local
+// filesystem, e.g. from a remote host.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: u64,
+}
+
+#[derive(Debug)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.to_string_lossy(), self.error)
+    }
+}
+
+// Keeps track of how far along a copy is, across all the threads doing it.
+struct CopyCounter<'a> {
+    files: AtomicU64,
+    bytes: AtomicU64,
+    on_progress: &'a (dyn Fn(u64, u64) + Sync),
+}
+
+impl CopyCounter<'_> {
+    fn record(&self, size: u64) {
+        let files = self.files.fetch_add(1, Ordering::SeqCst) + 1;
+        let bytes = self.bytes.fetch_add(size, Ordering::SeqCst) + size;
+        (self.on_progress)(files, bytes);
+    }
+}
+
+fn collect_error(path: &Path, result: io::Result<()>, errors: &mut Vec<FileError>) -> bool {
+    if let Err(error) = result {
+        errors.push(FileError { path: path.to_path_buf(), error });
+        false
+    } else {
+        true
+    }
+}
+
+fn errors_to_result(errors: Vec<FileError>) -> Result<(), Vec<FileError>> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
 }
 
 impl DirState {
-    pub fn from_dir(path: &PathBuf, file_filter: &FileFilter) -> Self {
+    pub fn from_dir(path: &Path, file_filter: &FileFilter) -> Self {
+        DirState::from_dir_inside(path, file_filter, &[])
+    }
+
+    // `ancestors` are the canonical paths of the directories we're already
+    // scanning, so that following a symlink can't send us around in circles.
+    fn from_dir_inside(path: &Path, file_filter: &FileFilter, ancestors: &[PathBuf]) -> Self {
         let mut files = HashMap::new();
         let mut subdirs = HashMap::new();
-        for result in fs::read_dir(path).unwrap() {
-            let entry = result.unwrap();
+        let mut read_errors = vec![];
+        let read_dir = match fs::read_dir(path) {
+            Ok(read_dir) => read_dir,
+            Err(e) => {
+                let mut state = DirState::empty(path);
+                state.read_errors.push(format!("Unable to read {}: {}", path.to_string_lossy(), e));
+                return state;
+            }
+        };
+        let mut ancestors = ancestors.to_vec();
+        if file_filter.symlinks == SymlinkMode::Follow {
+            ancestors.extend(fs::canonicalize(path).ok());
+        }
+        // Things can change while we're scanning, so if something can't be
+        // read, we note it and keep going with everything else.
+        for result in read_dir {
+            let entry = match result {
+                Ok(entry) => entry,
+                Err(e) => {
+                    read_errors.push(format!("Unable to read {}: {}", path.to_string_lossy(), e));
+                    continue;
+                }
+            };
             if file_filter.is_file_excluded(&entry) {
                 continue;
             }
             let filename = String::from(entry.file_name().to_string_lossy());
-            let metadata = entry.metadata().unwrap();
+            let entry_path = path.join(&filename);
+            let is_symlink = match entry.file_type() {
+                Ok(file_type) => file_type.is_symlink(),
+                Err(e) => {
+                    read_errors.push(format!("Unable to read {}: {}", entry_path.to_string_lossy(), e));
+                    continue;
+                }
+            };
+            let mut link_target = None;
+            let metadata = if is_symlink && file_filter.symlinks == SymlinkMode::Follow {
+                match fs::metadata(&entry_path) {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        read_errors.push(format!("Unable to follow symlink {}: {}", entry_path.to_string_lossy(), e));
+                        continue;
+                    }
+                }
+            } else {
+                if is_symlink {
+                    match fs::read_link(&entry_path) {
+                        Ok(target) => link_target = Some(target),
+                        Err(e) => {
+                            read_errors.push(format!("Unable to read symlink {}: {}", entry_path.to_string_lossy(), e));
+                            continue;
+                        }
+                    }
+                }
+                match entry.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        read_errors.push(format!("Unable to read {}: {}", entry_path.to_string_lossy(), e));
+                        continue;
+                    }
+                }
+            };
             if metadata.is_dir() {
-                let subdir = path.join(&filename);
-                subdirs.insert(filename, DirState::from_dir(&subdir, &file_filter));
+                if is_symlink && fs::canonicalize(&entry_path).is_ok_and(|target| ancestors.contains(&target)) {
+                    read_errors.push(format!("Not following symlink {}, since it loops back on itself", entry_path.to_string_lossy()));
+                    continue;
+                }
+                subdirs.insert(filename, DirState::from_dir_inside(&entry_path, file_filter, &ancestors));
+            } else {
+                let mut state = match FileState::from_metadata(&metadata) {
+                    Ok(state) => state,
+                    Err(e) => {
+                        read_errors.push(format!("Unable to read {}: {}", entry_path.to_string_lossy(), e));
+                        continue;
+                    }
+                };
+                state.link_target = link_target;
+                files.insert(filename, state);
+            }
+        }
+        DirState { path: path.to_path_buf(), file_filter: file_filter.clone(), compare: CompareOptions::default(), files, subdirs, read_errors }
+    }
+
+    pub fn from_listing<F: Fn(&Path) -> io::Result<Vec<ListedEntry>>>(path: &Path, file_filter: &FileFilter, list_dir: &F) -> Self {
+        let mut state = DirState::empty(path);
+        state.file_filter = file_filter.clone();
+        let entries = match list_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                state.read_errors.push(format!("Unable to read {}: {}", path.to_string_lossy(), e));
+                return state;
+            }
+        };
+        let now = SystemTime::now();
+        for entry in entries {
+            let entry_path = path.join(&entry.name);
+            if !file_filter.is_path_included(&entry_path, entry.is_dir) {
+                continue;
+            }
+            if !entry.is_dir && (!file_filter.is_size_included(entry.size) || !file_filter.is_age_included(SystemTime::UNIX_EPOCH + Duration::from_secs(entry.modified), now)) {
+                continue;
+            }
+            if entry.is_dir {
+                state.subdirs.insert(entry.name, DirState::from_listing(&entry_path, file_filter, list_dir));
             } else {
-                files.insert(filename, FileState::from_metadata(&metadata));
+                state.files.insert(entry.name, FileState { modified: entry.modified, modified_nanos: None, size: entry.size, hash: OnceLock::new(), link_target: None });
+            }
+        }
+        state
+    }
+
+    pub fn with_compare_options(mut self, compare: CompareOptions) -> Self {
+        self.set_compare_options(compare);
+        self
+    }
+
+    fn set_compare_options(&mut self, compare: CompareOptions) {
+        self.compare = compare;
+        for subdir in self.subdirs.values_mut() {
+            subdir.set_compare_options(compare);
+        }
+    }
+
+    fn file_hash(&self, filename: &str, state: &FileState) -> Option<hash::Hash> {
+        *state.hash.get_or_init(|| hash::hash_file(&self.path.join(filename)).ok())
+    }
+
+    // Reuses the hashes the other state knows for any files that look
+    // exactly the same in both, e.g. because they haven't changed since the
+    // other state was saved.
+    pub fn adopt_hashes_from(&self, other: &DirState) {
+        for (filename, state) in self.files.iter() {
+            if let Some(other_state) = other.files.get(filename) {
+                if let Some(Some(hash)) = other_state.hash.get() {
+                    if state == other_state {
+                        let _ = state.hash.set(Some(*hash));
+                    }
+                }
+            }
+        }
+        for (dirname, subdir) in self.subdirs.iter() {
+            if let Some(other_subdir) = other.subdirs.get(dirname) {
+                subdir.adopt_hashes_from(other_subdir);
+            }
+        }
+    }
+
+    // Hashes every file whose hash we don't already know.
+    pub fn compute_hashes(&self) {
+        self.files.par_iter().for_each(|(filename, state)| {
+            self.file_hash(filename, state);
+        });
+        for subdir in self.subdirs.values() {
+            subdir.compute_hashes();
+        }
+    }
+
+    // Whether the given file is the same in both directories.
+    fn file_matches(&self, filename: &str, state: &FileState, other: &DirState, other_state: &FileState) -> bool {
+        // We can't set the modification times of links we copy, so just see where they point.
+        if state.link_target.is_some() || other_state.link_target.is_some() {
+            return state.link_target == other_state.link_target;
+        }
+        let same_size = state.size == other_state.size;
+        let same_time = state.compare_modified(other_state, self.compare.mtime_tolerance_secs) == CmpOrdering::Equal;
+        if same_size && same_time && !self.compare.paranoid {
+            return true;
+        }
+        if same_size && (self.compare.compare_hashes || same_time) {
+            if let Some(hash) = self.file_hash(filename, state) {
+                return other.file_hash(filename, other_state) == Some(hash);
+            }
+        }
+        // If we can't read it, all we can go by is how it looks.
+        same_size && same_time
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // For listings that find problems of their own, beyond not being able
+    // to list a directory at all.
+    pub fn add_read_error(&mut self, error: String) {
+        self.read_errors.push(error);
+    }
+
+    pub fn was_read_cleanly(&self) -> bool {
+        self.read_errors.is_empty() && self.subdirs.values().all(|subdir| subdir.was_read_cleanly())
+    }
+
+    // Everything that went wrong while scanning this directory and
+    // everything in it.
+    pub fn read_errors(&self) -> Vec<&str> {
+        let mut errors: Vec<&str> = self.read_errors.iter().map(String::as_str).collect();
+        for subdir in self.subdirs.values() {
+            errors.extend(subdir.read_errors());
+        }
+        errors
+    }
+
+    // Whether we know for sure if the given file (relative to this
+    // directory) exists, i.e. nothing went wrong while scanning the
+    // directories it would be in. If we don't, it's not safe to assume
+    // that it's been deleted.
+    fn could_read(&self, rel_path: &Path) -> bool {
+        let mut dir = self;
+        for component in rel_path.parent().into_iter().flat_map(|parent| parent.iter()) {
+            if !dir.read_errors.is_empty() {
+                return false;
+            }
+            match dir.subdirs.get(component.to_string_lossy().as_ref()) {
+                Some(subdir) => dir = subdir,
+                None => return true,
             }
         }
-        DirState { path: path.clone(), file_filter: file_filter.clone(), files, subdirs }
+        dir.read_errors.is_empty()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -56,9 +476,15 @@ impl DirState {
     }
 
     pub fn are_contents_equal_to(&self, other: &DirState) -> bool {
-        if self.files != other.files {
+        if self.files.len() != other.files.len() {
             return false;
         }
+        for (filename, state) in self.files.iter() {
+            match other.files.get(filename) {
+                Some(other_state) if self.file_matches(filename, state, other, other_state) => {},
+                _ => { return false; }
+            }
+        }
         if self.subdirs.len() != other.subdirs.len() {
             return false;
         }
@@ -72,13 +498,13 @@ impl DirState {
                 }
             }
         }
-        return true;
+        true
     }
 
     pub fn are_any_contents_newer_than(&self, other: &DirState) -> bool {
         for (filename, state) in self.files.iter() {
             if let Some(other_state) = other.files.get(filename) {
-                if state.modified > other_state.modified {
+                if state.compare_modified(other_state, self.compare.mtime_tolerance_secs) == CmpOrdering::Greater && !self.file_matches(filename, state, other, other_state) {
                     return true;
                 }
             }
@@ -96,7 +522,7 @@ impl DirState {
     pub fn are_any_contents_older_than(&self, other: &DirState) -> bool {
         for (filename, state) in self.files.iter() {
             if let Some(other_state) = other.files.get(filename) {
-                if state.modified < other_state.modified {
+                if state.compare_modified(other_state, self.compare.mtime_tolerance_secs) == CmpOrdering::Less && !self.file_matches(filename, state, other, other_state) {
                     return true;
                 }
             }
@@ -117,81 +543,1594 @@ impl DirState {
         self.are_any_contents_newer_than(other)
     }
 
-    pub fn copy_into(&self, dest: &PathBuf) {
-        fs::create_dir_all(&dest).unwrap();
+    // Whether we have any files or directories that the other side doesn't.
+    pub fn has_contents_missing_from(&self, other: &DirState) -> bool {
+        self.files.keys().any(|filename| !other.files.contains_key(filename)) ||
+        self.subdirs.iter().any(|(dirname, subdir)| match other.subdirs.get(dirname) {
+            Some(other_subdir) => subdir.has_contents_missing_from(other_subdir),
+            None => true,
+        })
+    }
+
+    // Decides how this (app) side should be synchronized with the other
+    // (Dropbox) one, going only by what's in them:
+    //
+    // * If neither has anything in it, there's nothing to do, unless that's
+    //   because we couldn't read them.
+    // * If their contents are the same, they're already synced.
+    // * One side is newer if none of its files are older than the other
+    //   side's, at least one is newer, and the other side doesn't have any
+    //   files of its own. That last part is because we can't tell whether
+    //   such a file was deleted from the newer side or is brand new on the
+    //   older one, so copying the newer side over might lose it.
+    // * Anything else is a conflict.
+    pub fn classify_against(&self, other: &DirState) -> SyncResult {
+        if self.is_empty() && other.is_empty() {
+            // "Empty" might really mean we couldn't read anything.
+            if self.was_read_cleanly() && other.was_read_cleanly() {
+                SyncResult::BothEmpty
+            } else {
+                SyncResult::ReadError
+            }
+        } else if self.are_contents_equal_to(other) {
+            SyncResult::AlreadySynced
+        } else if !self.compare.mirror {
+            self.classify_without_deleting(other)
+        } else if self.are_contents_generally_newer_than(other) && !other.has_contents_missing_from(self) {
+            SyncResult::AppNewerThanDropbox
+        } else if other.are_contents_generally_newer_than(self) && !self.has_contents_missing_from(other) {
+            SyncResult::DropboxNewerThanApp
+        } else {
+            SyncResult::Conflict
+        }
+    }
+
+    // Like `classify_against`, for when nothing is ever deleted. Copying a
+    // side over then only loses something if the other side has newer
+    // versions of some of its files, so one side is newer if that isn't
+    // the case and it has something the other side doesn't. If both sides
+    // have something the other doesn't, they need to be merged instead.
+    fn classify_without_deleting(&self, other: &DirState) -> SyncResult {
+        let (any_newer, any_older) = (self.are_any_contents_newer_than(other), self.are_any_contents_older_than(other));
+        let app_has_more = any_newer || self.has_contents_missing_from(other);
+        let dropbox_has_more = any_older || other.has_contents_missing_from(self);
+        match (app_has_more && !any_older, dropbox_has_more && !any_newer) {
+            (true, false) => SyncResult::AppNewerThanDropbox,
+            (false, true) => SyncResult::DropboxNewerThanApp,
+            _ => SyncResult::Conflict,
+        }
+    }
+
+    pub fn diff_copy_into(&self, dest: &DirState) -> CopyDiff {
+        let mut diff = CopyDiff::default();
+        self.add_to_copy_diff(dest, &PathBuf::new(), &mut diff);
+        diff.to_copy.sort();
+        diff.to_delete.sort();
+        diff
+    }
+
+    fn add_to_copy_diff(&self, dest: &DirState, prefix: &Path, diff: &mut CopyDiff) {
         for filename in self.files.keys() {
-            let src_path = &self.path.join(filename);
-            let dest_path = dest.join(filename);
-            fs::copy(&src_path, &dest_path).unwrap();
+            diff.to_copy.push(prefix.join(filename));
+        }
+        // We can't tell what's really missing from a directory we couldn't
+        // fully read.
+        let may_delete = self.read_errors.is_empty();
+        for filename in dest.files.keys() {
+            if may_delete && !self.files.contains_key(filename) {
+                diff.to_delete.push(prefix.join(filename));
+            }
         }
         for (dirname, dir) in self.subdirs.iter() {
-            let dest_dir = dest.join(dirname);
-            dir.copy_into(&dest_dir);
+            let empty_dir;
+            let dest_dir = match dest.subdirs.get(dirname) {
+                Some(dest_dir) => dest_dir,
+                None => {
+                    empty_dir = DirState::empty(&dest.path.join(dirname));
+                    &empty_dir
+                }
+            };
+            dir.add_to_copy_diff(dest_dir, &prefix.join(dirname), diff);
+        }
+        for dirname in dest.subdirs.keys() {
+            if may_delete && !self.subdirs.contains_key(dirname) {
+                diff.to_delete.push(prefix.join(dirname));
+            }
         }
     }
 
-    pub fn remove_extraneous_files_from(&self, root: &PathBuf) {
-        for result in fs::read_dir(root).unwrap() {
-            let entry = result.unwrap();
-            if self.file_filter.is_file_excluded(&entry) {
+    // Relative paths and sizes of all files bigger than the given size, sorted by path.
+    pub fn files_larger_than(&self, max_size: u64) -> Vec<(PathBuf, u64)> {
+        let mut result = vec![];
+        self.add_files_larger_than(max_size, &PathBuf::new(), &mut result);
+        result.sort();
+        result
+    }
+
+    fn add_files_larger_than(&self, max_size: u64, prefix: &Path, result: &mut Vec<(PathBuf, u64)>) {
+        for (filename, state) in self.files.iter() {
+            if state.size > max_size {
+                result.push((prefix.join(filename), state.size));
+            }
+        }
+        for (dirname, dir) in self.subdirs.iter() {
+            dir.add_files_larger_than(max_size, &prefix.join(dirname), result);
+        }
+    }
+
+    fn flatten<'a>(&'a self, prefix: &Path, result: &mut BTreeMap<PathBuf, FlatFile<'a>>) {
+        for (filename, state) in self.files.iter() {
+            result.insert(prefix.join(filename), (self, filename, state));
+        }
+        for (dirname, dir) in self.subdirs.iter() {
+            dir.flatten(&prefix.join(dirname), result);
+        }
+    }
+
+    // Pairs of names in the same folder that only differ by case, which
+    // case-insensitive filesystems (like most on Windows and macOS, and
+    // Dropbox itself) can't keep apart, so copying both would lose one.
+    pub fn case_collisions(&self) -> Vec<(PathBuf, PathBuf)> {
+        let mut result = vec![];
+        self.add_case_collisions(Path::new(""), &mut result);
+        result.sort();
+        result
+    }
+
+    fn add_case_collisions(&self, prefix: &Path, result: &mut Vec<(PathBuf, PathBuf)>) {
+        let mut names: Vec<&String> = self.files.keys().chain(self.subdirs.keys()).collect();
+        names.sort();
+        let mut seen: HashMap<String, &String> = HashMap::new();
+        for name in names {
+            if let Some(other) = seen.insert(name.to_lowercase(), name) {
+                result.push((prefix.join(other), prefix.join(name)));
+            }
+        }
+        for (dirname, dir) in self.subdirs.iter() {
+            dir.add_case_collisions(&prefix.join(dirname), result);
+        }
+    }
+
+    // The opposite of `from_listing`: everything in the directory, paired
+    // with its path relative to it. Each directory comes before its contents.
+    pub fn to_listing(&self) -> Vec<(PathBuf, ListedEntry)> {
+        let mut result = vec![];
+        self.add_to_listing(Path::new(""), &mut result);
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        result
+    }
+
+    fn add_to_listing(&self, prefix: &Path, result: &mut Vec<(PathBuf, ListedEntry)>) {
+        for (filename, state) in self.files.iter() {
+            let entry = ListedEntry { name: filename.clone(), is_dir: false, size: state.size, modified: state.modified };
+            result.push((prefix.join(filename), entry));
+        }
+        for (dirname, dir) in self.subdirs.iter() {
+            let entry = ListedEntry { name: dirname.clone(), is_dir: true, size: 0, modified: 0 };
+            result.push((prefix.join(dirname), entry));
+            dir.add_to_listing(&prefix.join(dirname), result);
+        }
+    }
+
+    fn find_file(&self, rel_path: &Path) -> Option<&FileState> {
+        let mut dir = self;
+        let mut components = rel_path.iter().peekable();
+        while let Some(component) = components.next() {
+            let name = component.to_string_lossy();
+            if components.peek().is_none() {
+                return dir.files.get(name.as_ref());
+            }
+            dir = dir.subdirs.get(name.as_ref())?;
+        }
+        None
+    }
+
+    pub fn has_file(&self, rel_path: &Path) -> bool {
+        self.find_file(rel_path).is_some()
+    }
+
+    // Figures out, file by file, which side's version of each file should
+    // win. If we know what both sides looked like the last time they were
+    // synced, a file that only changed on one side comes from that side,
+    // and one that changed on both is a conflict. Otherwise, the newer
+    // version of a file wins, and a file that's only on one side is a
+    // conflict, since we can't tell if it was added there or deleted from
+    // the other side.
+    pub fn merge_plan(&self, other: &DirState, last_sync: Option<&DirState>) -> MergePlan {
+        let (mut mine, mut theirs, mut ancestors) = (BTreeMap::new(), BTreeMap::new(), BTreeMap::new());
+        self.flatten(Path::new(""), &mut mine);
+        other.flatten(Path::new(""), &mut theirs);
+        if let Some(last_sync) = last_sync {
+            last_sync.flatten(Path::new(""), &mut ancestors);
+        }
+        let same = |a: Option<&FlatFile>, b: Option<&FlatFile>| match (a, b) {
+            (Some((a_dir, name, a_state)), Some((b_dir, _, b_state))) => a_dir.file_matches(name, a_state, b_dir, b_state),
+            (None, None) => true,
+            _ => false,
+        };
+        let mut plan = MergePlan::default();
+        let paths: BTreeSet<&PathBuf> = mine.keys().chain(theirs.keys()).collect();
+        for path in paths {
+            let (mine, theirs) = (mine.get(path), theirs.get(path));
+            if same(mine, theirs) {
                 continue;
             }
-            let filepath = entry.path();
-            let filename = String::from(entry.file_name().to_string_lossy());
-            let metadata = entry.metadata().unwrap();
-            if metadata.is_dir() {
-                if let Some(subdir) = self.subdirs.get(&filename) {
-                    subdir.remove_extraneous_files_from(&filepath);
-                } else {
-                    fs::remove_dir_all(&filepath).unwrap();
+            let mine_wins = if last_sync.is_some() {
+                let ancestor = ancestors.get(path);
+                // The snapshot goes first, since it has nothing on disk to hash.
+                match (!same(ancestor, mine), !same(ancestor, theirs)) {
+                    (true, false) => Some(true),
+                    (false, true) => Some(false),
+                    _ => None,
                 }
             } else {
-                if !self.files.contains_key(&filename) {
-                    fs::remove_file(&filepath).unwrap();
+                match (mine, theirs) {
+                    (Some((_, _, my_state)), Some((_, _, their_state))) => match my_state.compare_modified(their_state, self.compare.mtime_tolerance_secs) {
+                        CmpOrdering::Greater => Some(true),
+                        CmpOrdering::Less => Some(false),
+                        CmpOrdering::Equal => None,
+                    },
+                    _ => None,
                 }
+            };
+            let path = path.clone();
+            match (mine_wins, mine.is_some(), theirs.is_some()) {
+                (Some(true), true, _) => plan.to_other.push(path),
+                (Some(true), false, _) if self.could_read(&path) => plan.delete_from_other.push(path),
+                (Some(false), _, true) => plan.from_other.push(path),
+                (Some(false), _, false) if other.could_read(&path) => plan.delete_from_self.push(path),
+                _ => plan.conflicts.push(path),
             }
         }
+        plan
     }
-}
 
-#[test]
-fn test_dirstate() {
-    let file_filter = FileFilter::default();
+    // A simpler kind of merge, for when each side has been changing
+    // different files: the newer version of each file wins, and files that
+    // are only on one side are copied to the other, so nothing is ever
+    // deleted. Only files that differ but were modified at about the same
+    // time are conflicts.
+    pub fn newest_wins_plan(&self, other: &DirState) -> MergePlan {
+        let (mut mine, mut theirs) = (BTreeMap::new(), BTreeMap::new());
+        self.flatten(Path::new(""), &mut mine);
+        other.flatten(Path::new(""), &mut theirs);
+        let mut plan = MergePlan::default();
+        let paths: BTreeSet<&PathBuf> = mine.keys().chain(theirs.keys()).collect();
+        for path in paths {
+            let path_buf = path.clone();
+            match (mine.get(path), theirs.get(path)) {
+                (Some((my_dir, name, my_state)), Some((their_dir, _, their_state))) => {
+                    if my_dir.file_matches(name, my_state, their_dir, their_state) {
+                        continue;
+                    }
+                    match my_state.compare_modified(their_state, self.compare.mtime_tolerance_secs) {
+                        CmpOrdering::Equal => plan.conflicts.push(path_buf),
+                        CmpOrdering::Greater => plan.to_other.push(path_buf),
+                        CmpOrdering::Less => plan.from_other.push(path_buf),
+                    }
+                },
+                (Some(_), None) => plan.to_other.push(path_buf),
+                (None, _) => plan.from_other.push(path_buf),
+            }
+        }
+        plan
+    }
 
-    // Setup: create/clear the temporary test dir.
-    let tmp_dir = PathBuf::from(".test_dirstate");
-    if tmp_dir.exists() {
-        fs::remove_dir_all(&tmp_dir).unwrap();
+    // Sorts every file on either side by how it compares with the other
+    // side, without deciding what should be done about it.
+    pub fn diff(&self, other: &DirState) -> DirDiff {
+        let (mut mine, mut theirs) = (BTreeMap::new(), BTreeMap::new());
+        self.flatten(Path::new(""), &mut mine);
+        other.flatten(Path::new(""), &mut theirs);
+        let mut diff = DirDiff::default();
+        let paths: BTreeSet<&PathBuf> = mine.keys().chain(theirs.keys()).collect();
+        for path in paths {
+            let path_buf = path.clone();
+            match (mine.get(path), theirs.get(path)) {
+                (Some((my_dir, name, my_state)), Some((their_dir, _, their_state))) => {
+                    if my_dir.file_matches(name, my_state, their_dir, their_state) {
+                        diff.equal.push(path_buf);
+                    } else {
+                        match my_state.compare_modified(their_state, self.compare.mtime_tolerance_secs) {
+                            CmpOrdering::Greater => diff.newer_in_self.push(path_buf),
+                            CmpOrdering::Less => diff.newer_in_other.push(path_buf),
+                            CmpOrdering::Equal => diff.differing.push(path_buf),
+                        }
+                    }
+                },
+                (Some(_), None) => diff.only_in_self.push(path_buf),
+                (None, _) => diff.only_in_other.push(path_buf),
+            }
+        }
+        diff
     }
-    fs::create_dir(&tmp_dir).unwrap();
 
-    // Copy the source test dir into the temp test dir.
-    let src_dir = PathBuf::from("test-data/dirstate_test");
-    let src_state = DirState::from_dir(&src_dir, &file_filter);
-    assert!(!src_state.is_empty());
-    src_state.copy_into(&tmp_dir);
+    // Copies just the given files (relative to this directory) into the same
+    // places under `dest`.
+    pub fn copy_files_into(&self, rel_paths: &[PathBuf], dest: &Path) -> Result<(), Vec<FileError>> {
+        let mut errors = vec![];
+        for rel_path in rel_paths {
+            if interrupt::is_interrupted() {
+                break;
+            }
+            let dest_path = dest.join(rel_path);
+            let state = self.find_file(rel_path);
+            let result = dest_path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| match state.and_then(|state| state.link_target.as_ref()) {
+                Some(link_target) => copy_symlink(&self.path.join(rel_path), link_target, &dest_path),
+                None => copy_file(&self.path.join(rel_path), &dest_path, state.map(|state| state.size)),
+            });
+            collect_error(&dest_path, result, &mut errors);
+        }
+        errors_to_result(errors)
+    }
 
-    let mut tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
-    assert!(src_state.are_contents_equal_to(&tmp_state));
-    assert!(tmp_state.are_contents_equal_to(&src_state));
+    pub fn empty(path: &Path) -> Self {
+        DirState { path: path.to_path_buf(), file_filter: FileFilter::default(), compare: CompareOptions::default(), files: HashMap::new(), subdirs: HashMap::new(), read_errors: vec![] }
+    }
 
-    // Make a subdirectory in the temp test dir.
-    let tmp_subdir = tmp_dir.join("another_subdir");
-    fs::create_dir(&tmp_subdir).unwrap();
-    assert!(DirState::from_dir(&tmp_subdir, &file_filter).is_empty());
+    // A hash of the names and contents of everything in the directory,
+    // which fails if any of the files can no longer be read.
+    pub fn tree_hash(&self) -> io::Result<hash::Hash> {
+        let mut entries: Vec<(&str, bool, hash::Hash)> = vec![];
+        for filename in self.files.keys() {
+            let path = self.path.join(filename);
+            let file_hash = hash::hash_file(&path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.to_string_lossy(), e)))?;
+            entries.push((filename, false, file_hash));
+        }
+        for (dirname, dir) in self.subdirs.iter() {
+            entries.push((dirname, true, dir.tree_hash()?));
+        }
+        entries.sort_by(|a, b| a.0.cmp(b.0).then(a.1.cmp(&b.1)));
+        Ok(hash::hash_dir_entries(entries.into_iter()))
+    }
 
-    tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
-    assert!(!src_state.are_contents_equal_to(&tmp_state));
+    pub fn total_file_count(&self) -> u64 {
+        self.files.len() as u64 + self.subdirs.values().map(|dir| dir.total_file_count()).sum::<u64>()
+    }
 
-    // Add a file to the temp test dir.
-    let tmp_file = tmp_dir.join("somefile");
-    fs::write(&tmp_file, "blarg").unwrap();
+    pub fn total_size(&self) -> u64 {
+        self.files.values().map(|state| state.size).sum::<u64>() + self.subdirs.values().map(|dir| dir.total_size()).sum::<u64>()
+    }
 
-    // Remove files from the temp test dir not in the source test dir.
-    src_state.remove_extraneous_files_from(&tmp_dir);
-    tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
-    assert!(src_state.are_contents_equal_to(&tmp_state));
+    // Copies everything into the given directory, continuing past any
+    // files that can't be copied.
+    pub fn copy_into(&self, dest: &Path) -> Result<(), Vec<FileError>> {
+        self.copy_into_with_progress(dest, &|_, _| {})
+    }
 
-    // Teardown: remove the temporary test dir.
-    fs::remove_dir_all(&tmp_dir).unwrap();
+    // Like `copy_into`, but calls `on_progress` with the number of files and
+    // bytes copied so far every time a file is finished.
+    pub fn copy_into_with_progress(&self, dest: &Path, on_progress: &(dyn Fn(u64, u64) + Sync)) -> Result<(), Vec<FileError>> {
+        let counter = CopyCounter { files: AtomicU64::new(0), bytes: AtomicU64::new(0), on_progress };
+        let mut errors = vec![];
+        self.copy_into_collecting_errors(dest, None, &counter, &mut errors);
+        // Files are copied in parallel, so put the errors in a predictable order.
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        errors_to_result(errors)
+    }
+
+    // Relative paths and sizes of all files that don't match their
+    // counterparts in `dest` (or don't exist there at all), sorted by path.
+    pub fn files_differing_from(&self, dest: &DirState) -> Vec<(PathBuf, u64)> {
+        let mut result = vec![];
+        self.add_files_differing_from(Some(dest), &PathBuf::new(), &mut result);
+        result.sort();
+        result
+    }
+
+    fn add_files_differing_from(&self, dest: Option<&DirState>, prefix: &Path, result: &mut Vec<(PathBuf, u64)>) {
+        for (filename, state) in self.files.iter() {
+            if !self.matches_file_in(dest, filename, state) {
+                result.push((prefix.join(filename), state.size));
+            }
+        }
+        for (dirname, dir) in self.subdirs.iter() {
+            dir.add_files_differing_from(dest.and_then(|dest| dest.subdirs.get(dirname)), &prefix.join(dirname), result);
+        }
+    }
+
+    fn matches_file_in(&self, dest: Option<&DirState>, filename: &str, state: &FileState) -> bool {
+        match dest.and_then(|dest| dest.files.get(filename).map(|dest_state| (dest, dest_state))) {
+            Some((dest, dest_state)) => self.file_matches(filename, state, dest, dest_state),
+            None => false,
+        }
+    }
+
+    // Like `copy_into_with_progress`, but only copies the files that don't
+    // already match their counterparts in `dest`.
+    pub fn copy_changed_into_with_progress(&self, dest: &DirState, on_progress: &(dyn Fn(u64, u64) + Sync)) -> Result<(), Vec<FileError>> {
+        let counter = CopyCounter { files: AtomicU64::new(0), bytes: AtomicU64::new(0), on_progress };
+        let mut errors = vec![];
+        self.copy_into_collecting_errors(dest.path(), Some(dest), &counter, &mut errors);
+        // Files are copied in parallel, so put the errors in a predictable order.
+        errors.sort_by(|a, b| a.path.cmp(&b.path));
+        errors_to_result(errors)
+    }
+
+    // If `dest_state` is given, files that already match it aren't copied.
+    fn copy_into_collecting_errors(&self, dest: &Path, dest_state: Option<&DirState>, counter: &CopyCounter, errors: &mut Vec<FileError>) {
+        if !collect_error(dest, fs::create_dir_all(dest), errors) {
+            return;
+        }
+        let file_errors: Vec<FileError> = self.files.par_iter().filter_map(|(filename, state)| {
+            // If we've been interrupted, leave the rest of the files alone.
+            if interrupt::is_interrupted() || (dest_state.is_some() && self.matches_file_in(dest_state, filename, state)) {
+                return None;
+            }
+            let src_path = self.path.join(filename);
+            let dest_path = dest.join(filename);
+            let result = match &state.link_target {
+                Some(link_target) => copy_symlink(&src_path, link_target, &dest_path),
+                None => copy_file(&src_path, &dest_path, Some(state.size)),
+            };
+            counter.record(state.size);
+            result.err().map(|error| FileError { path: dest_path, error })
+        }).collect();
+        errors.extend(file_errors);
+        let subdir_errors: Vec<FileError> = self.subdirs.par_iter().flat_map_iter(|(dirname, dir)| {
+            let mut subdir_errors = vec![];
+            let subdir_state = dest_state.and_then(|dest_state| dest_state.subdirs.get(dirname));
+            dir.copy_into_collecting_errors(&dest.join(dirname), subdir_state, counter, &mut subdir_errors);
+            subdir_errors
+        }).collect();
+        errors.extend(subdir_errors);
+    }
+
+    // Copies the given files and directories (relative to this one) into the
+    // same relative locations under `backup_dir`, skipping any that don't exist.
+    pub fn back_up_into(&self, rel_paths: &[PathBuf], backup_dir: &Path) -> Result<(), Vec<FileError>> {
+        let mut errors = vec![];
+        for rel_path in rel_paths {
+            let src_path = self.path.join(rel_path);
+            let dest_path = backup_dir.join(rel_path);
+            if src_path.is_dir() {
+                if let Err(dir_errors) = DirState::from_dir(&src_path, &FileFilter::default()).copy_into(&dest_path) {
+                    errors.extend(dir_errors);
+                }
+            } else if src_path.is_file() {
+                let result = dest_path.parent().map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| copy_file(&src_path, &dest_path, None));
+                collect_error(&dest_path, result, &mut errors);
+            }
+        }
+        errors_to_result(errors)
+    }
+
+    // Re-reads the given files (relative to this directory) both here and
+    // in `dest`, to make sure the copies there actually match, in case
+    // something like a flaky network drive mangled them.
+    pub fn verify_copies_in(&self, rel_paths: &[PathBuf], dest: &Path) -> Result<(), Vec<FileError>> {
+        let errors: Vec<FileError> = rel_paths.par_iter().filter_map(|rel_path| {
+            let src_path = self.path.join(rel_path);
+            // Links are copied as-is, so there's nothing to read.
+            if fs::symlink_metadata(&src_path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+                return None;
+            }
+            let dest_path = dest.join(rel_path);
+            let result = hash::hash_file(&src_path).and_then(|expected| {
+                if hash::hash_file(&dest_path)? == expected {
+                    Ok(())
+                } else {
+                    Err(io::Error::other("the copy doesn't match the original"))
+                }
+            });
+            result.err().map(|error| FileError { path: dest_path, error })
+        }).collect();
+        errors_to_result(errors)
+    }
+
+    pub fn is_subset_of(&self, other: &DirState) -> bool {
+        for (filename, state) in self.files.iter() {
+            match other.files.get(filename) {
+                Some(other_state) if self.file_matches(filename, state, other, other_state) => {},
+                _ => { return false; }
+            }
+        }
+        for (dirname, state) in self.subdirs.iter() {
+            match other.subdirs.get(dirname) {
+                Some(other_state) if state.is_subset_of(other_state) => {},
+                _ => { return false; }
+            }
+        }
+        true
+    }
+
+    // Rescans our directory and the given one, returning whether their contents
+    // are now identical (or, if extra files are allowed, whether everything
+    // we have is also in the destination).
+    pub fn is_synced_with(&self, dest: &Path, allow_extra_files: bool) -> bool {
+        let source = DirState::from_dir(&self.path, &self.file_filter).with_compare_options(self.compare);
+        let dest = DirState::from_dir(dest, &self.file_filter).with_compare_options(self.compare);
+        if allow_extra_files {
+            source.is_subset_of(&dest)
+        } else {
+            source.are_contents_equal_to(&dest)
+        }
+    }
+
+    pub fn remove_extraneous_files_from(&self, root: &Path) -> Result<(), Vec<FileError>> {
+        let mut errors = vec![];
+        self.remove_extraneous_files_collecting_errors(root, &remove_permanently, &mut errors);
+        errors_to_result(errors)
+    }
+
+    // Like `remove_extraneous_files_from`, but sends the files to the
+    // system trash instead of deleting them permanently.
+    pub fn trash_extraneous_files_from(&self, root: &Path) -> Result<(), Vec<FileError>> {
+        let mut errors = vec![];
+        self.remove_extraneous_files_collecting_errors(root, &move_to_trash, &mut errors);
+        errors_to_result(errors)
+    }
+
+    fn remove_extraneous_files_collecting_errors(&self, root: &Path, remove: &dyn Fn(&Path, bool) -> io::Result<()>, errors: &mut Vec<FileError>) {
+        // If we couldn't fully read this directory, things that seem to be
+        // missing from it might not be.
+        let may_delete = self.read_errors.is_empty();
+        let read_dir = match fs::read_dir(root) {
+            Ok(read_dir) => read_dir,
+            Err(error) => {
+                errors.push(FileError { path: root.to_path_buf(), error });
+                return;
+            }
+        };
+        for result in read_dir {
+            let entry = match result {
+                Ok(entry) => entry,
+                Err(error) => {
+                    errors.push(FileError { path: root.to_path_buf(), error });
+                    continue;
+                }
+            };
+            if self.file_filter.is_file_excluded(&entry) {
+                continue;
+            }
+            let filepath = entry.path();
+            let filename = String::from(entry.file_name().to_string_lossy());
+            let is_symlink = entry.file_type().is_ok_and(|t| t.is_symlink());
+            let metadata = if is_symlink && self.file_filter.symlinks == SymlinkMode::Follow {
+                fs::metadata(&filepath)
+            } else {
+                entry.metadata()
+            };
+            let metadata = match metadata {
+                Ok(metadata) => metadata,
+                Err(error) => {
+                    errors.push(FileError { path: filepath, error });
+                    continue;
+                }
+            };
+            if metadata.is_dir() {
+                if let Some(subdir) = self.subdirs.get(&filename) {
+                    subdir.remove_extraneous_files_collecting_errors(&filepath, remove, errors);
+                } else if may_delete {
+                    collect_error(&filepath, remove(&filepath, true), errors);
+                }
+            } else if may_delete && !self.files.contains_key(&filename) {
+                collect_error(&filepath, remove(&filepath, false), errors);
+            }
+        }
+    }
+}
+
+// Recreates the symbolic link at `src_path` at `dest_path`, replacing
+// whatever's there, rather than copying what it points to.
+#[cfg_attr(not(windows), allow(unused_variables))]
+fn copy_symlink(src_path: &Path, link_target: &Path, dest_path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(dest_path).is_ok() {
+        fs::remove_file(dest_path)?;
+    }
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(link_target, dest_path);
+    // Windows needs to know whether it's a link to a directory, and what
+    // it points to might not have been copied over yet.
+    #[cfg(windows)]
+    if fs::metadata(src_path).is_ok_and(|metadata| metadata.is_dir()) {
+        return std::os::windows::fs::symlink_dir(link_target, dest_path);
+    }
+    #[cfg(windows)]
+    return std::os::windows::fs::symlink_file(link_target, dest_path);
+}
+
+// Deletes just the given files (relative to `root`).
+pub fn remove_files_from(root: &Path, rel_paths: &[PathBuf], use_trash: bool) -> Result<(), Vec<FileError>> {
+    let mut errors = vec![];
+    for rel_path in rel_paths {
+        let path = root.join(rel_path);
+        let is_dir = fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir());
+        let result = if use_trash { move_to_trash(&path, is_dir) } else { remove_permanently(&path, is_dir) };
+        collect_error(&path, result, &mut errors);
+    }
+    errors_to_result(errors)
+}
+
+fn remove_permanently(path: &Path, is_dir: bool) -> io::Result<()> {
+    if is_dir {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn move_to_trash(path: &Path, _is_dir: bool) -> io::Result<()> {
+    trash::delete(path).map_err(|e| io::Error::other(e.to_string()))
+}
+
+// A cheap way of catching truncated copies.
+fn verify_size(path: &Path, expected_size: u64) -> io::Result<()> {
+    let size = fs::metadata(path)?.len();
+    if size == expected_size {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("expected {} bytes after copying, but found {}", expected_size, size)))
+    }
+}
+
+// How many more times to try copying a file that's temporarily locked
+// (e.g. by a game that only just quit), and how long to wait before the
+// first retry. Each retry waits twice as long as the one before.
+pub const DEFAULT_COPY_RETRIES: u32 = 3;
+pub const DEFAULT_COPY_RETRY_DELAY_MS: u64 = 500;
+static COPY_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_COPY_RETRIES);
+static COPY_RETRY_DELAY_MS: AtomicU64 = AtomicU64::new(DEFAULT_COPY_RETRY_DELAY_MS);
+
+pub fn set_copy_retries(retries: u32, delay_ms: u64) {
+    COPY_RETRIES.store(retries, Ordering::SeqCst);
+    COPY_RETRY_DELAY_MS.store(delay_ms, Ordering::SeqCst);
+}
+
+// Errors that might go away if we just wait a bit.
+fn is_transient_error(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::ResourceBusy | io::ErrorKind::ExecutableFileBusy => true,
+        // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION.
+        _ => cfg!(windows) && matches!(error.raw_os_error(), Some(32) | Some(33)),
+    }
+}
+
+fn with_retries<F: FnMut() -> io::Result<()>>(retries: u32, delay: Duration, mut f: F) -> io::Result<()> {
+    let mut attempt = 0;
+    loop {
+        match f() {
+            Err(e) if attempt < retries && is_transient_error(&e) && !interrupt::is_interrupted() => {
+                std::thread::sleep(delay * 2u32.pow(attempt));
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+fn copy_file(src_path: &Path, dest_path: &Path, expected_size: Option<u64>) -> io::Result<()> {
+    let retries = COPY_RETRIES.load(Ordering::SeqCst);
+    let delay = Duration::from_millis(COPY_RETRY_DELAY_MS.load(Ordering::SeqCst));
+    with_retries(retries, delay, || copy_file_once(src_path, dest_path, expected_size))
+}
+
+// Copies into a temporary file next to the destination first, and only
+// renames it into place once it's complete (and, if we know what size it
+// should be, the right size), so an interrupted copy never leaves a
+// truncated file behind where it could be mistaken for the real thing.
+fn copy_file_once(src_path: &Path, dest_path: &Path, expected_size: Option<u64>) -> io::Result<()> {
+    let filename = dest_path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = dest_path.with_file_name(format!("{}tmp-{}", RESERVED_PREFIX, filename));
+    let result = copy_file_contents(src_path, &tmp_path, expected_size).and_then(|_| fs::rename(&tmp_path, dest_path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn copy_file_contents(src_path: &Path, dest_path: &Path, expected_size: Option<u64>) -> io::Result<()> {
+    fs::copy(src_path, dest_path)?;
+    if let Some(expected_size) = expected_size {
+        verify_size(dest_path, expected_size)?;
+    }
+    // Not every platform's `fs::copy` carries the modification time over,
+    // and we rely on it to compare the two directories later.
+    let metadata = fs::metadata(src_path)?;
+    let mut times = fs::FileTimes::new().set_modified(metadata.modified()?);
+    if let Ok(accessed) = metadata.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    // We need to be able to write to the file to set its times, even if
+    // the original is read-only.
+    #[cfg(unix)]
+    set_mode(dest_path, metadata.permissions().mode() | 0o200)?;
+    fs::File::options().write(true).open(dest_path)?.set_times(times)?;
+    // Make sure things like the executable bit come along, too.
+    #[cfg(unix)]
+    set_mode(dest_path, metadata.permissions().mode())?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[test]
+fn test_dirstate() {
+    let file_filter = FileFilter::default();
+
+    // Setup: create/clear the temporary test dir.
+    let tmp_dir = PathBuf::from(".test_dirstate");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    fs::create_dir(&tmp_dir).unwrap();
+
+    // Copy the source test dir into the temp test dir.
+    let src_dir = PathBuf::from("test-data/dirstate_test");
+    let src_state = DirState::from_dir(&src_dir, &file_filter);
+    assert!(!src_state.is_empty());
+    src_state.copy_into(&tmp_dir).unwrap();
+
+    let mut tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert!(src_state.are_contents_equal_to(&tmp_state));
+    assert!(tmp_state.are_contents_equal_to(&src_state));
+
+    // Make a subdirectory in the temp test dir.
+    let tmp_subdir = tmp_dir.join("another_subdir");
+    fs::create_dir(&tmp_subdir).unwrap();
+    assert!(DirState::from_dir(&tmp_subdir, &file_filter).is_empty());
+
+    tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert!(!src_state.are_contents_equal_to(&tmp_state));
+
+    // Add a file to the temp test dir.
+    let tmp_file = tmp_dir.join("somefile");
+    fs::write(&tmp_file, "blarg").unwrap();
+
+    // Remove files from the temp test dir not in the source test dir.
+    src_state.remove_extraneous_files_from(&tmp_dir).unwrap();
+    tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert!(src_state.are_contents_equal_to(&tmp_state));
+
+    // Teardown: remove the temporary test dir.
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_empty_subdirs() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_empty_subdirs");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app_dir = tmp_dir.join("app");
+    let dropbox_dir = tmp_dir.join("dropbox");
+    fs::create_dir_all(app_dir.join("empty").join("nested")).unwrap();
+    fs::write(app_dir.join("save"), "blarg").unwrap();
+    fs::create_dir_all(&dropbox_dir).unwrap();
+
+    // From the app into Dropbox.
+    let app_state = DirState::from_dir(&app_dir, &file_filter);
+    app_state.copy_into(&dropbox_dir).unwrap();
+    assert!(dropbox_dir.join("empty").join("nested").is_dir());
+    assert!(app_state.are_contents_equal_to(&DirState::from_dir(&dropbox_dir, &file_filter)));
+    assert!(app_state.is_synced_with(&dropbox_dir, false));
+
+    // From Dropbox back into the app, after another computer replaced the
+    // empty directory with a different one.
+    fs::remove_dir_all(dropbox_dir.join("empty")).unwrap();
+    fs::create_dir(dropbox_dir.join("other_empty")).unwrap();
+    let dropbox_state = DirState::from_dir(&dropbox_dir, &file_filter);
+    let app_state = DirState::from_dir(&app_dir, &file_filter);
+    assert!(!dropbox_state.are_contents_equal_to(&app_state));
+    dropbox_state.copy_changed_into_with_progress(&app_state, &|_, _| {}).unwrap();
+    dropbox_state.remove_extraneous_files_from(&app_dir).unwrap();
+    assert!(app_dir.join("other_empty").is_dir());
+    assert!(!app_dir.join("empty").exists());
+    assert!(dropbox_state.is_synced_with(&app_dir, false));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_is_synced_with() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_is_synced_with");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter);
+    src_state.copy_into(&tmp_dir).unwrap();
+    src_state.remove_extraneous_files_from(&tmp_dir).unwrap();
+    assert!(src_state.is_synced_with(&tmp_dir, false));
+    assert!(src_state.is_synced_with(&tmp_dir, true));
+
+    // Extra files are only okay if we didn't delete extraneous files.
+    fs::write(tmp_dir.join("extra"), "blarg").unwrap();
+    assert!(!src_state.is_synced_with(&tmp_dir, false));
+    assert!(src_state.is_synced_with(&tmp_dir, true));
+
+    // Simulate the copy going wrong.
+    fs::write(tmp_dir.join("subdir").join("shoop"), "something else entirely").unwrap();
+    assert!(!src_state.is_synced_with(&tmp_dir, false));
+    assert!(!src_state.is_synced_with(&tmp_dir, true));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_diff_copy_into() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_diff_copy_into");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    fs::create_dir_all(tmp_dir.join("old_subdir")).unwrap();
+    fs::write(tmp_dir.join("boop"), "blarg").unwrap();
+    fs::write(tmp_dir.join("somefile"), "blarg").unwrap();
+
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter);
+    let dest_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert_eq!(src_state.diff_copy_into(&dest_state), CopyDiff {
+        to_copy: vec![PathBuf::from("boop"), PathBuf::from("subdir").join("shoop")],
+        to_delete: vec![PathBuf::from("old_subdir"), PathBuf::from("somefile")],
+    });
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_tree_hash() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_tree_hash");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter);
+    src_state.copy_into(&tmp_dir).unwrap();
+    assert_eq!(src_state.tree_hash().unwrap(), DirState::from_dir(&tmp_dir, &file_filter).tree_hash().unwrap());
+
+    fs::write(tmp_dir.join("subdir").join("shoop"), "modified").unwrap();
+    assert_ne!(src_state.tree_hash().unwrap(), DirState::from_dir(&tmp_dir, &file_filter).tree_hash().unwrap());
+
+    // A file that's gone by the time we hash it is an error, not a panic.
+    let tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
+    fs::remove_file(tmp_dir.join("subdir").join("shoop")).unwrap();
+    assert!(tmp_state.tree_hash().unwrap_err().to_string().contains("shoop"));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_was_read_cleanly() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_was_read_cleanly");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    fs::create_dir(&tmp_dir).unwrap();
+
+    let empty_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert!(empty_state.is_empty());
+    assert!(empty_state.was_read_cleanly());
+
+    let unreadable_state = DirState::from_dir(&tmp_dir.join("nonexistent"), &file_filter);
+    assert!(unreadable_state.is_empty());
+    assert!(!unreadable_state.was_read_cleanly());
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_copy_into_errors() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_copy_into_errors");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    // A directory where a file should go can't be copied over.
+    fs::create_dir_all(tmp_dir.join("boop")).unwrap();
+
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter);
+    let errors = src_state.copy_into(&tmp_dir).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, tmp_dir.join("boop"));
+
+    // Everything else should still have been copied.
+    assert!(tmp_dir.join("subdir").join("shoop").exists());
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_compare_hashes() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_compare_hashes");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_dir = PathBuf::from("test-data/dirstate_test");
+    DirState::from_dir(&src_dir, &file_filter).copy_into(&tmp_dir).unwrap();
+
+    // Rewrite a file with the same contents, giving it a newer modification time.
+    let newer = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    fs::File::options().write(true).open(tmp_dir.join("boop")).unwrap().set_modified(newer).unwrap();
+
+    let src_state = DirState::from_dir(&src_dir, &file_filter);
+    let tmp_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert!(!tmp_state.are_contents_equal_to(&src_state));
+    assert!(tmp_state.are_contents_generally_newer_than(&src_state));
+
+    let compare = CompareOptions { compare_hashes: true, ..Default::default() };
+    let src_state = DirState::from_dir(&src_dir, &file_filter).with_compare_options(compare);
+    let tmp_state = DirState::from_dir(&tmp_dir, &file_filter).with_compare_options(compare);
+    assert!(tmp_state.are_contents_equal_to(&src_state));
+    assert!(!tmp_state.are_any_contents_newer_than(&src_state));
+
+    // Actually changing the contents should still make it newer.
+    fs::write(tmp_dir.join("boop"), "changed").unwrap();
+    let tmp_state = DirState::from_dir(&tmp_dir, &file_filter).with_compare_options(compare);
+    assert!(!tmp_state.are_contents_equal_to(&src_state));
+    assert!(tmp_state.are_contents_generally_newer_than(&src_state));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_copy_into_verifies_size() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_copy_into_verifies_size");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_dir = tmp_dir.join("src");
+    DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter).copy_into(&src_dir).unwrap();
+
+    // Change the file's size after it's been scanned, so the copy won't
+    // match what we expect.
+    let src_state = DirState::from_dir(&src_dir, &file_filter);
+    fs::write(src_dir.join("boop"), "a different length").unwrap();
+
+    let errors = src_state.copy_into(&tmp_dir.join("dest")).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, tmp_dir.join("dest").join("boop"));
+    assert!(errors[0].error.to_string().contains("bytes"));
+    // The wrongly-sized copy shouldn't have been left in place, either.
+    assert!(!tmp_dir.join("dest").join("boop").exists());
+    assert_eq!(fs::read_dir(tmp_dir.join("dest")).unwrap().count(), 1);
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_mtime_tolerance() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_mtime_tolerance");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_dir = PathBuf::from("test-data/dirstate_test");
+    DirState::from_dir(&src_dir, &file_filter).copy_into(&tmp_dir).unwrap();
+
+    let set_boop_offset = |secs: u64| {
+        let modified = fs::metadata(src_dir.join("boop")).unwrap().modified().unwrap() + std::time::Duration::from_secs(secs);
+        fs::File::options().write(true).open(tmp_dir.join("boop")).unwrap().set_modified(modified).unwrap();
+    };
+    let compare = |mtime_tolerance_secs| CompareOptions { mtime_tolerance_secs, ..Default::default() };
+
+    set_boop_offset(2);
+    let src_state = DirState::from_dir(&src_dir, &file_filter).with_compare_options(compare(2));
+    let tmp_state = DirState::from_dir(&tmp_dir, &file_filter).with_compare_options(compare(2));
+    assert!(tmp_state.are_contents_equal_to(&src_state));
+    assert!(!tmp_state.are_any_contents_newer_than(&src_state));
+    assert!(!src_state.are_any_contents_older_than(&tmp_state));
+
+    let src_state = DirState::from_dir(&src_dir, &file_filter).with_compare_options(compare(0));
+    let tmp_state = DirState::from_dir(&tmp_dir, &file_filter).with_compare_options(compare(0));
+    assert!(!tmp_state.are_contents_equal_to(&src_state));
+    assert!(tmp_state.are_any_contents_newer_than(&src_state));
+    assert!(src_state.are_any_contents_older_than(&tmp_state));
+
+    set_boop_offset(3);
+    let src_state = DirState::from_dir(&src_dir, &file_filter).with_compare_options(compare(2));
+    let tmp_state = DirState::from_dir(&tmp_dir, &file_filter).with_compare_options(compare(2));
+    assert!(tmp_state.are_contents_generally_newer_than(&src_state));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_verify_copies_in() {
+    let tmp_dir = PathBuf::from(".test_verify_copies_in");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &FileFilter::default());
+    src_state.copy_into(&tmp_dir).unwrap();
+    let rel_paths = vec![PathBuf::from("boop"), PathBuf::from("subdir").join("shoop")];
+    assert!(src_state.verify_copies_in(&rel_paths, &tmp_dir).is_ok());
+
+    fs::write(tmp_dir.join("subdir").join("shoop"), "mangled!").unwrap();
+    let errors = src_state.verify_copies_in(&rel_paths, &tmp_dir).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].path, tmp_dir.join("subdir").join("shoop"));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_paranoid_compare() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_paranoid_compare");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let (a_dir, b_dir) = (tmp_dir.join("a"), tmp_dir.join("b"));
+    fs::create_dir_all(&a_dir).unwrap();
+    fs::create_dir_all(&b_dir).unwrap();
+    let modified = SystemTime::now();
+    for (dir, contents) in [(&a_dir, "boop"), (&b_dir, "bonk")] {
+        fs::write(dir.join("save"), contents).unwrap();
+        fs::File::options().write(true).open(dir.join("save")).unwrap().set_modified(modified).unwrap();
+    }
+
+    // The same size and modification time normally means the same file.
+    let a_state = DirState::from_dir(&a_dir, &file_filter);
+    assert!(a_state.are_contents_equal_to(&DirState::from_dir(&b_dir, &file_filter)));
+
+    let paranoid = CompareOptions { paranoid: true, ..Default::default() };
+    let a_state = DirState::from_dir(&a_dir, &file_filter).with_compare_options(paranoid);
+    let b_state = DirState::from_dir(&b_dir, &file_filter).with_compare_options(paranoid);
+    assert!(!a_state.are_contents_equal_to(&b_state));
+    assert_eq!(a_state.classify_against(&b_state), SyncResult::Conflict);
+
+    fs::write(b_dir.join("save"), "boop").unwrap();
+    fs::File::options().write(true).open(b_dir.join("save")).unwrap().set_modified(modified).unwrap();
+    let b_state = DirState::from_dir(&b_dir, &file_filter).with_compare_options(paranoid);
+    assert!(a_state.are_contents_equal_to(&b_state));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_compare_modified() {
+    let file_state = |modified, modified_nanos| FileState { modified, modified_nanos, size: 4, hash: OnceLock::new(), link_target: None };
+    let earlier = file_state(1000, Some(100_000_000));
+    let later = file_state(1000, Some(900_000_000));
+    assert_eq!(later.compare_modified(&earlier, 0), CmpOrdering::Greater);
+    assert_eq!(earlier.compare_modified(&later, 0), CmpOrdering::Less);
+    assert_eq!(later.compare_modified(&earlier, 1), CmpOrdering::Equal);
+    assert_eq!(file_state(1002, Some(0)).compare_modified(&earlier, 2), CmpOrdering::Equal);
+    assert_eq!(file_state(1002, Some(200_000_000)).compare_modified(&earlier, 2), CmpOrdering::Greater);
+
+    // Whole seconds are all we can go by if one side doesn't know better.
+    let whole_second = file_state(1000, None);
+    assert_eq!(later.compare_modified(&whole_second, 0), CmpOrdering::Equal);
+    assert_eq!(file_state(1001, None).compare_modified(&later, 0), CmpOrdering::Greater);
+}
+
+#[test]
+fn test_files_larger_than() {
+    let dir_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &FileFilter::default());
+    assert_eq!(dir_state.files_larger_than(u64::MAX), vec![]);
+    let all_files: Vec<PathBuf> = dir_state.files_larger_than(0).into_iter().map(|(path, _)| path).collect();
+    assert_eq!(all_files, vec![PathBuf::from("boop"), PathBuf::from("subdir").join("shoop")]);
+    assert_eq!(dir_state.files_larger_than(7), vec![(PathBuf::from("subdir").join("shoop"), 8)]);
+}
+
+// Most other filesystems wouldn't let us make the test files.
+#[cfg(target_os = "linux")]
+#[test]
+fn test_case_collisions() {
+    let tmp_dir = PathBuf::from(".test_case_collisions");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    fs::create_dir_all(tmp_dir.join("subdir")).unwrap();
+    fs::create_dir_all(tmp_dir.join("SubDir")).unwrap();
+    fs::write(tmp_dir.join("boop"), "boop").unwrap();
+    fs::write(tmp_dir.join("subdir").join("Save.dat"), "1").unwrap();
+    fs::write(tmp_dir.join("subdir").join("save.dat"), "2").unwrap();
+
+    let dir_state = DirState::from_dir(&tmp_dir, &FileFilter::default());
+    assert_eq!(dir_state.case_collisions(), vec![
+        (PathBuf::from("SubDir"), PathBuf::from("subdir")),
+        (PathBuf::from("subdir").join("Save.dat"), PathBuf::from("subdir").join("save.dat")),
+    ]);
+    assert_eq!(DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &FileFilter::default()).case_collisions(), vec![]);
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_copy_into_preserves_mtimes() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_copy_into_preserves_mtimes");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_dir = tmp_dir.join("src");
+    let dest_dir = tmp_dir.join("dest");
+    fs::create_dir_all(&src_dir).unwrap();
+    let src_file = src_dir.join("old_save");
+    fs::write(&src_file, "hi").unwrap();
+    let long_ago = SystemTime::UNIX_EPOCH + std::time::Duration::from_millis(1_000_000_000_123);
+    fs::File::options().write(true).open(&src_file).unwrap().set_modified(long_ago).unwrap();
+
+    let src_state = DirState::from_dir(&src_dir, &file_filter);
+    src_state.copy_into(&dest_dir).unwrap();
+    assert_eq!(fs::metadata(dest_dir.join("old_save")).unwrap().modified().unwrap(), long_ago);
+
+    // Even with no tolerance at all, a freshly synced pair is equal.
+    let strict = CompareOptions { mtime_tolerance_secs: 0, ..Default::default() };
+    let src_state = src_state.with_compare_options(strict);
+    let dest_state = DirState::from_dir(&dest_dir, &file_filter).with_compare_options(strict);
+    assert!(src_state.are_contents_equal_to(&dest_state));
+    assert!(!dest_state.are_any_contents_newer_than(&src_state));
+    assert!(!src_state.are_any_contents_newer_than(&dest_state));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_copy_into_with_progress() {
+    use std::sync::Mutex;
+
+    let tmp_dir = PathBuf::from(".test_copy_into_with_progress");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &FileFilter::default());
+    assert_eq!(src_state.total_file_count(), 2);
+    assert_eq!(src_state.total_size(), 15);
+
+    let updates = Mutex::new(vec![]);
+    src_state.copy_into_with_progress(&tmp_dir, &|files, bytes| updates.lock().unwrap().push((files, bytes))).unwrap();
+    // Files are copied in parallel, so the updates may arrive out of order.
+    let mut updates = updates.into_inner().unwrap();
+    updates.sort();
+    assert_eq!(updates.len(), 2);
+    assert_eq!(updates.iter().map(|(files, _)| *files).collect::<Vec<_>>(), vec![1, 2]);
+    assert_eq!(updates.last(), Some(&(2, 15)));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_back_up_into() {
+    let tmp_dir = PathBuf::from(".test_back_up_into");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &FileFilter::default());
+    let backup_dir = tmp_dir.join("backup");
+    let to_back_up = vec![PathBuf::from("subdir"), PathBuf::from("nonexistent")];
+    src_state.back_up_into(&to_back_up, &backup_dir).unwrap();
+    assert_eq!(fs::read_to_string(backup_dir.join("subdir").join("shoop")).unwrap(), fs::read_to_string("test-data/dirstate_test/subdir/shoop").unwrap());
+    assert!(!backup_dir.join("boop").exists());
+    assert!(!backup_dir.join("nonexistent").exists());
+
+    src_state.back_up_into(&[PathBuf::from("boop")], &backup_dir).unwrap();
+    assert!(backup_dir.join("boop").is_file());
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_partial_scan() {
+    use std::os::unix::fs::symlink;
+
+    let tmp_dir = PathBuf::from(".test_partial_scan");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_dir = tmp_dir.join("src");
+    let dest_dir = tmp_dir.join("dest");
+    fs::create_dir_all(src_dir.join("subdir")).unwrap();
+    fs::write(src_dir.join("save"), "hi").unwrap();
+    fs::write(src_dir.join("subdir").join("save"), "hi").unwrap();
+    // A symlink that can't be followed can't be read.
+    symlink("nonexistent", src_dir.join("subdir").join("broken")).unwrap();
+    let file_filter = FileFilter::default().with_symlinks(SymlinkMode::Follow);
+
+    let src_state = DirState::from_dir(&src_dir, &file_filter);
+    assert!(!src_state.was_read_cleanly());
+    assert_eq!(src_state.read_errors().len(), 1);
+    assert!(src_state.read_errors()[0].contains("broken"));
+    assert!(src_state.has_file(&PathBuf::from("subdir").join("save")));
+
+    // Nothing in the directory we couldn't fully read should be deleted,
+    // since it might just be what we couldn't read.
+    src_state.copy_into(&dest_dir).unwrap();
+    fs::write(dest_dir.join("extra"), "hi").unwrap();
+    fs::write(dest_dir.join("subdir").join("broken"), "hi").unwrap();
+    let dest_state = DirState::from_dir(&dest_dir, &file_filter);
+    assert_eq!(src_state.diff_copy_into(&dest_state).to_delete, vec![PathBuf::from("extra")]);
+    src_state.remove_extraneous_files_from(&dest_dir).unwrap();
+    assert!(!dest_dir.join("extra").exists());
+    assert!(dest_dir.join("subdir").join("broken").exists());
+
+    let plan = src_state.merge_plan(&dest_state, Some(&dest_state));
+    assert_eq!(plan.delete_from_other, vec![PathBuf::from("extra")]);
+    assert_eq!(plan.conflicts, vec![PathBuf::from("subdir").join("broken")]);
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_mtime_before_epoch() {
+    let tmp_dir = PathBuf::from(".test_mtime_before_epoch");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    fs::create_dir_all(&tmp_dir).unwrap();
+    fs::write(tmp_dir.join("save"), "hi").unwrap();
+    fs::write(tmp_dir.join("ancient"), "hi").unwrap();
+    let before_epoch = SystemTime::UNIX_EPOCH - Duration::from_secs(60);
+    fs::File::options().write(true).open(tmp_dir.join("ancient")).unwrap().set_modified(before_epoch).unwrap();
+
+    let state = DirState::from_dir(&tmp_dir, &FileFilter::default());
+    assert!(!state.was_read_cleanly());
+    assert!(state.read_errors()[0].contains("ancient"));
+    assert!(state.has_file(&PathBuf::from("save")));
+    assert!(!state.has_file(&PathBuf::from("ancient")));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let tmp_dir = PathBuf::from(".test_symlinks");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_dir = tmp_dir.join("src");
+    fs::create_dir_all(src_dir.join("subdir")).unwrap();
+    fs::write(src_dir.join("subdir").join("save"), "hi").unwrap();
+    symlink("subdir/save", src_dir.join("link_to_save")).unwrap();
+    symlink("..", src_dir.join("subdir").join("loop")).unwrap();
+
+    let names = |state: &DirState| {
+        let mut names: Vec<String> = state.diff_copy_into(&DirState::empty(&tmp_dir)).to_copy.iter()
+            .map(|path| path.to_string_lossy().to_string()).collect();
+        names.sort();
+        names
+    };
+
+    let skip_state = DirState::from_dir(&src_dir, &FileFilter::default());
+    assert!(skip_state.was_read_cleanly());
+    assert_eq!(names(&skip_state), vec!["subdir/save"]);
+
+    let follow_state = DirState::from_dir(&src_dir, &FileFilter::default().with_symlinks(SymlinkMode::Follow));
+    assert!(!follow_state.was_read_cleanly());
+    assert_eq!(names(&follow_state), vec!["link_to_save", "subdir/save"]);
+
+    let copy_filter = FileFilter::default().with_symlinks(SymlinkMode::Copy);
+    let copy_state = DirState::from_dir(&src_dir, &copy_filter);
+    assert_eq!(names(&copy_state), vec!["link_to_save", "subdir/loop", "subdir/save"]);
+    let dest_dir = tmp_dir.join("dest");
+    copy_state.copy_into(&dest_dir).unwrap();
+    assert_eq!(fs::read_link(dest_dir.join("link_to_save")).unwrap(), PathBuf::from("subdir/save"));
+    assert!(DirState::from_dir(&dest_dir, &copy_filter).are_contents_equal_to(&copy_state));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_copy_changed_into() {
+    use std::sync::Mutex;
+
+    let file_filter = FileFilter::default();
+    let tmp_dir = PathBuf::from(".test_copy_changed_into");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter);
+    src_state.copy_into(&tmp_dir).unwrap();
+    let dest_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert_eq!(src_state.files_differing_from(&dest_state), vec![]);
+
+    // Change one file, and make sure only that one gets copied back.
+    let boop = tmp_dir.join("boop");
+    fs::write(&boop, "changed!").unwrap();
+    let dest_state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert_eq!(src_state.files_differing_from(&dest_state), vec![(PathBuf::from("boop"), 7)]);
+    assert_eq!(dest_state.files_differing_from(&src_state), vec![(PathBuf::from("boop"), 8)]);
+
+    let copied = Mutex::new(0);
+    src_state.copy_changed_into_with_progress(&dest_state, &|files, _| *copied.lock().unwrap() = files).unwrap();
+    assert_eq!(copied.into_inner().unwrap(), 1);
+    assert!(src_state.are_contents_equal_to(&DirState::from_dir(&tmp_dir, &file_filter)));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_size_limits() {
+    let file_filter = FileFilter::default().with_size_limits(None, Some(7));
+    let tmp_dir = PathBuf::from(".test_size_limits");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    // Only `boop` is small enough; `subdir/shoop` is 8 bytes.
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter);
+    assert_eq!(src_state.files_larger_than(0).into_iter().map(|(path, _)| path).collect::<Vec<_>>(), vec![PathBuf::from("boop")]);
+    src_state.copy_into(&tmp_dir).unwrap();
+    assert!(tmp_dir.join("boop").exists());
+    assert!(!tmp_dir.join("subdir").join("shoop").exists());
+
+    // Files over the limit shouldn't count as extraneous, either.
+    fs::write(tmp_dir.join("subdir").join("huge"), "way too big").unwrap();
+    src_state.remove_extraneous_files_from(&tmp_dir).unwrap();
+    assert!(tmp_dir.join("subdir").join("huge").exists());
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_copy_into_preserves_permissions() {
+    let file_filter = FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_copy_into_preserves_permissions");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let src_dir = tmp_dir.join("src");
+    let dest_dir = tmp_dir.join("dest");
+    fs::create_dir_all(&src_dir).unwrap();
+    fs::create_dir_all(&dest_dir).unwrap();
+    fs::write(src_dir.join("game"), "#!/bin/sh").unwrap();
+    fs::write(src_dir.join("readonly"), "hi").unwrap();
+    set_mode(&src_dir.join("game"), 0o755).unwrap();
+    set_mode(&src_dir.join("readonly"), 0o444).unwrap();
+    // An existing destination file with different permissions should get the new ones.
+    fs::write(dest_dir.join("game"), "old").unwrap();
+    set_mode(&dest_dir.join("game"), 0o600).unwrap();
+
+    DirState::from_dir(&src_dir, &file_filter).copy_into(&dest_dir).unwrap();
+    let mode = |name: &str| fs::metadata(dest_dir.join(name)).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode("game"), 0o755);
+    assert_eq!(mode("readonly"), 0o444);
+
+    set_mode(&src_dir.join("readonly"), 0o644).unwrap();
+    set_mode(&dest_dir.join("readonly"), 0o644).unwrap();
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_adopt_hashes_from() {
+    let file_filter = FileFilter::default();
+    let tmp_dir = PathBuf::from(".test_adopt_hashes_from");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter).copy_into(&tmp_dir).unwrap();
+    let known_hashes = |state: &DirState| {
+        let mut files = BTreeMap::new();
+        state.flatten(Path::new(""), &mut files);
+        files.into_iter().filter(|(_, (_, _, file))| !is_hash_unknown(&file.hash)).map(|(path, _)| path).collect::<Vec<PathBuf>>()
+    };
+    let shoop = PathBuf::from("subdir").join("shoop");
+
+    let state = DirState::from_dir(&tmp_dir, &file_filter);
+    assert_eq!(known_hashes(&state), Vec::<PathBuf>::new());
+    state.compute_hashes();
+    assert_eq!(known_hashes(&state), vec![PathBuf::from("boop"), shoop.clone()]);
+
+    // Hashes survive being saved, e.g. in a snapshot.
+    let saved: DirState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+    assert_eq!(known_hashes(&saved), vec![PathBuf::from("boop"), shoop.clone()]);
+
+    // Only files that haven't changed since get the saved hashes.
+    let later = SystemTime::now() + std::time::Duration::from_secs(10);
+    fs::File::options().write(true).open(tmp_dir.join("boop")).unwrap().set_modified(later).unwrap();
+    let rescanned = DirState::from_dir(&tmp_dir, &file_filter);
+    rescanned.adopt_hashes_from(&saved);
+    assert_eq!(known_hashes(&rescanned), vec![shoop.clone()]);
+    assert_eq!(rescanned.find_file(&shoop).unwrap().hash.get(), saved.find_file(&shoop).unwrap().hash.get());
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_classify_against() {
+    let file_filter = FileFilter::default();
+    let tmp_dir = PathBuf::from(".test_classify_against");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app_dir = tmp_dir.join("app");
+    let dropbox_dir = tmp_dir.join("dropbox");
+    let reset = || {
+        if tmp_dir.exists() {
+            fs::remove_dir_all(&tmp_dir).unwrap();
+        }
+        for dir in [&app_dir, &dropbox_dir] {
+            fs::create_dir_all(dir.join("subdir")).unwrap();
+            fs::write(dir.join("a"), "original").unwrap();
+            fs::write(dir.join("subdir").join("b"), "original").unwrap();
+        }
+    };
+    let later = SystemTime::now() + std::time::Duration::from_secs(10);
+    let change = |path: PathBuf| {
+        fs::write(&path, "changed!").unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+    };
+    let classify = || DirState::from_dir(&app_dir, &file_filter).classify_against(&DirState::from_dir(&dropbox_dir, &file_filter));
+
+    reset();
+    assert_eq!(classify(), SyncResult::AlreadySynced);
+
+    // One side has a newer file, and maybe some brand-new ones too.
+    change(app_dir.join("a"));
+    assert_eq!(classify(), SyncResult::AppNewerThanDropbox);
+    fs::write(app_dir.join("subdir").join("new"), "new").unwrap();
+    assert_eq!(classify(), SyncResult::AppNewerThanDropbox);
+
+    // Each side has a file that's newer than the other's.
+    change(dropbox_dir.join("subdir").join("b"));
+    assert_eq!(classify(), SyncResult::Conflict);
+
+    // The newer side doesn't have a file that the older side does. It
+    // might have been deleted from the newer side, or be brand new.
+    reset();
+    change(dropbox_dir.join("a"));
+    assert_eq!(classify(), SyncResult::DropboxNewerThanApp);
+    fs::write(app_dir.join("new"), "new").unwrap();
+    assert_eq!(classify(), SyncResult::Conflict);
+    fs::remove_file(app_dir.join("new")).unwrap();
+    fs::create_dir(app_dir.join("new_dir")).unwrap();
+    assert_eq!(classify(), SyncResult::Conflict);
+
+    // Having new files isn't enough to make a side newer.
+    reset();
+    fs::write(app_dir.join("new"), "new").unwrap();
+    assert_eq!(classify(), SyncResult::Conflict);
+
+    // Unless nothing's ever deleted, in which case the other side's files
+    // are safe no matter what.
+    let copy_only = CompareOptions { mirror: false, ..Default::default() };
+    let classify = || DirState::from_dir(&app_dir, &file_filter).with_compare_options(copy_only).classify_against(&DirState::from_dir(&dropbox_dir, &file_filter));
+    assert_eq!(classify(), SyncResult::AppNewerThanDropbox);
+    change(dropbox_dir.join("a"));
+    assert_eq!(classify(), SyncResult::DropboxNewerThanApp);
+    change(app_dir.join("subdir").join("b"));
+    assert_eq!(classify(), SyncResult::Conflict);
+
+    // Both sides have files the other doesn't, so neither can be copied
+    // over in one go.
+    reset();
+    fs::write(app_dir.join("new"), "new").unwrap();
+    fs::write(dropbox_dir.join("other_new"), "new").unwrap();
+    assert_eq!(classify(), SyncResult::Conflict);
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_diff() {
+    let file_filter = FileFilter::default();
+    let file = |name: &str, size: u64, modified: u64| ListedEntry { name: String::from(name), is_dir: false, size, modified };
+    let dir = |name: &str| ListedEntry { name: String::from(name), is_dir: true, size: 0, modified: 0 };
+    let mine = DirState::from_listing(Path::new("mine"), &file_filter, &|path: &Path| Ok(match path.to_string_lossy().as_ref() {
+        "mine" => vec![file("same", 1, 1000), file("newer_here", 1, 2000), file("newer_there", 1, 1000), file("only_here", 1, 1000), dir("subdir")],
+        _ => vec![file("resized", 2, 1000)],
+    }));
+    let theirs = DirState::from_listing(Path::new("theirs"), &file_filter, &|path: &Path| Ok(match path.to_string_lossy().as_ref() {
+        "theirs" => vec![file("same", 1, 1000), file("newer_here", 1, 1000), file("newer_there", 1, 2000), file("only_there", 1, 1000), dir("subdir")],
+        _ => vec![file("resized", 3, 1000), file("also_only_there", 1, 1000)],
+    }));
+    let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    assert_eq!(mine.diff(&theirs), DirDiff {
+        only_in_self: paths(&["only_here"]),
+        only_in_other: paths(&["only_there", "subdir/also_only_there"]),
+        newer_in_self: paths(&["newer_here"]),
+        newer_in_other: paths(&["newer_there"]),
+        differing: paths(&["subdir/resized"]),
+        equal: paths(&["same"]),
+    });
+    assert_eq!(theirs.diff(&mine).newer_in_self, paths(&["newer_there"]));
+}
+
+#[test]
+fn test_merge_plan() {
+    let file_filter = FileFilter::default();
+    let tmp_dir = PathBuf::from(".test_merge_plan");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app_dir = tmp_dir.join("app");
+    let dropbox_dir = tmp_dir.join("dropbox");
+    for dir in [&app_dir, &dropbox_dir] {
+        fs::create_dir_all(dir.join("subdir")).unwrap();
+        for name in ["same", "app_changed", "dropbox_changed", "both_changed", "app_deleted", "subdir/dropbox_deleted"] {
+            fs::write(dir.join(name), "original").unwrap();
+        }
+    }
+    let last_sync = DirState::from_dir(&dropbox_dir, &file_filter);
+
+    let later = SystemTime::now() + std::time::Duration::from_secs(10);
+    let change = |path: PathBuf, contents: &str| {
+        fs::write(&path, contents).unwrap();
+        fs::File::options().write(true).open(&path).unwrap().set_modified(later).unwrap();
+    };
+    change(app_dir.join("app_changed"), "changed!");
+    change(dropbox_dir.join("dropbox_changed"), "changed!");
+    change(app_dir.join("both_changed"), "changed in app");
+    change(dropbox_dir.join("both_changed"), "changed in dropbox");
+    fs::remove_file(app_dir.join("app_deleted")).unwrap();
+    fs::remove_file(dropbox_dir.join("subdir").join("dropbox_deleted")).unwrap();
+    fs::write(app_dir.join("app_added"), "new").unwrap();
+
+    let app_state = DirState::from_dir(&app_dir, &file_filter);
+    let dropbox_state = DirState::from_dir(&dropbox_dir, &file_filter);
+    let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    assert_eq!(app_state.merge_plan(&dropbox_state, Some(&last_sync)), MergePlan {
+        to_other: paths(&["app_added", "app_changed"]),
+        from_other: paths(&["dropbox_changed"]),
+        delete_from_self: paths(&["subdir/dropbox_deleted"]),
+        delete_from_other: paths(&["app_deleted"]),
+        conflicts: paths(&["both_changed"]),
+    });
+
+    // Without knowing what things looked like before, only files that
+    // are newer on one side are clear-cut.
+    assert_eq!(app_state.merge_plan(&dropbox_state, None), MergePlan {
+        to_other: paths(&["app_changed"]),
+        from_other: paths(&["dropbox_changed"]),
+        conflicts: paths(&["app_added", "app_deleted", "both_changed", "subdir/dropbox_deleted"]),
+        ..Default::default()
+    });
+
+    // Keeping the newest of everything never deletes anything, and only
+    // files that changed at the same time are unclear.
+    assert_eq!(app_state.newest_wins_plan(&dropbox_state), MergePlan {
+        to_other: paths(&["app_added", "app_changed", "subdir/dropbox_deleted"]),
+        from_other: paths(&["app_deleted", "dropbox_changed"]),
+        conflicts: paths(&["both_changed"]),
+        ..Default::default()
+    });
+
+    let plan = app_state.merge_plan(&dropbox_state, Some(&last_sync));
+    app_state.copy_files_into(&plan.to_other, &dropbox_dir).unwrap();
+    remove_files_from(&dropbox_dir, &plan.delete_from_other, false).unwrap();
+    assert_eq!(fs::read_to_string(dropbox_dir.join("app_changed")).unwrap(), "changed!");
+    assert!(dropbox_dir.join("app_added").exists());
+    assert!(!dropbox_dir.join("app_deleted").exists());
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_with_retries() {
+    let busy = || io::Error::from(io::ErrorKind::ResourceBusy);
+    let mut calls = 0;
+    let result = with_retries(3, Duration::ZERO, || {
+        calls += 1;
+        if calls < 3 { Err(busy()) } else { Ok(()) }
+    });
+    assert!(result.is_ok());
+    assert_eq!(calls, 3);
+
+    let mut calls = 0;
+    assert!(with_retries(2, Duration::ZERO, || { calls += 1; Err(busy()) }).is_err());
+    assert_eq!(calls, 3);
+
+    // Errors that won't go away aren't retried.
+    let mut calls = 0;
+    assert!(with_retries(3, Duration::ZERO, || { calls += 1; Err(io::Error::from(io::ErrorKind::NotFound)) }).is_err());
+    assert_eq!(calls, 1);
 }
@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use dropsync::{config, dropbox, explorer, util};
+
+// Checks for the most common reasons dropsync doesn't do what you'd
+// expect, without changing anything. Returns whether everything passed.
+pub fn run(hostname: &str, profile: Option<&str>, config_file: Option<&Path>) -> bool {
+    let mut report = Report::default();
+    report.info(&format!("This computer's name is {}, so that's what host-specific sections in the config should be called.", hostname));
+
+    let (dropbox_dir, source) = dropbox::locate_dropbox_dir();
+    let dropbox_dir_exists = dropbox_dir.is_dir();
+    report.check(dropbox_dir_exists, &format!("Dropbox folder {} ({}) {}.", dropbox_dir.to_string_lossy(), source.describe(), if dropbox_dir_exists { "exists" } else { "doesn't exist" }));
+    match dropbox::get_health() {
+        dropbox::Health::Healthy => report.check(true, "Dropbox is running."),
+        dropbox::Health::Idle(_) => report.warn("Dropbox is running, but hasn't done anything in a while. Is it paused?"),
+        dropbox::Health::Unknown => report.warn("Dropbox is running, but I can't tell when it last did anything."),
+        dropbox::Health::NotRunning => report.warn("Dropbox doesn't appear to be running, so changes won't reach other computers."),
+    }
+    if dropbox_dir_exists {
+        let config_file = config_file.map(Path::to_path_buf).unwrap_or_else(|| config::get_config_path(&dropbox_dir, profile));
+        check_config(&mut report, hostname, &config_file, &dropbox_dir);
+    }
+
+    match explorer::get_open_program() {
+        Some(program) if util::find_in_path(&program).is_some() => report.check(true, &format!("Folders will be opened with {}.", program)),
+        Some(program) => report.warn(&format!("{} isn't installed, so `dropsync explore` won't work. Try setting DROPSYNC_OPEN_CMD.", program)),
+        None => report.warn("I don't know how to open folders on this OS, so `dropsync explore` won't work. Try setting DROPSYNC_OPEN_CMD."),
+    }
+
+    if explorer::is_wsl() {
+        report.info("Running under WSL. Remember that app paths on Windows drives look like /mnt/c/..., not C:\\....");
+    }
+    if cfg!(target_os = "windows") {
+        report.info("Games can keep their files locked for a bit after quitting; if copies fail, try a larger --copy-retry-delay.");
+    }
+    if cfg!(target_os = "macos") {
+        report.info("If an app's folder can't be read, your terminal may need Full Disk Access in System Settings.");
+    }
+    if cfg!(target_os = "linux") && util::find_in_path("notify-send").is_none() {
+        report.info("notify-send isn't installed, so --notify won't show anything.");
+    }
+
+    report.passed
+}
+
+fn check_config(report: &mut Report, hostname: &str, cfg_file: &Path, dropbox_dir: &Path) {
+    if !cfg_file.exists() {
+        report.check(false, &format!("Config file {} doesn't exist.", cfg_file.to_string_lossy()));
+        return;
+    }
+    let app_configs = match config::load_config_file(hostname, cfg_file, dropbox_dir) {
+        Ok(app_configs) => app_configs,
+        Err(e) => {
+            report.check(false, &format!("Config file {} has a problem: {}", cfg_file.to_string_lossy(), e));
+            return;
+        }
+    };
+    report.check(true, &format!("Config file {} defines {} app(s).", cfg_file.to_string_lossy(), app_configs.len()));
+    let mut apps: Vec<&config::AppConfig> = app_configs.values().collect();
+    apps.sort_by(|a, b| a.name.cmp(&b.name));
+    for app in apps {
+        if app.disabled {
+            report.info(&format!("{} is disabled on this computer.", app.name));
+            continue;
+        }
+        let problems = app.problems();
+        if problems.is_empty() {
+            report.check(true, &format!("{}: {} exists.", app.name, app.path.to_string_lossy()));
+        }
+        for problem in problems {
+            report.check(false, &format!("{}: {}.", app.name, problem));
+        }
+    }
+}
+
+struct Report {
+    passed: bool,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Report { passed: true }
+    }
+}
+
+impl Report {
+    fn check(&mut self, ok: bool, message: &str) {
+        println!("[{}] {}", if ok { "PASS" } else { "FAIL" }, message);
+        self.passed &= ok;
+    }
+
+    fn warn(&self, message: &str) {
+        println!("[WARN] {}", message);
+    }
+
+    fn info(&self, message: &str) {
+        println!("[INFO] {}", message);
+    }
+}
@@ -1,10 +1,180 @@
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use sysinfo::{System, SystemExt, ProcessExt};
 
 use self::super::util;
 
-pub fn get_dropbox_dir() -> PathBuf {
-    let mut home_dir = dirs::home_dir().expect("User should have a home directory!");
-    home_dir.push("Dropbox");
-    util::ensure_path_exists(&home_dir);
-    home_dir
+// If Dropbox hasn't touched its own state directory in this long, it's
+// probably paused or stuck.
+const MAX_IDLE_SECONDS: u64 = 60 * 60;
+
+// Dropbox keeps its recent activity near the top of its state directory,
+// so there's no need to walk all of a big cache to find it.
+const MAX_STATE_DIR_DEPTH: usize = 3;
+const MAX_STATE_DIR_ENTRIES: usize = 10_000;
+
+const DROPBOX_DIR_VAR: &str = "DROPSYNC_DROPBOX_DIR";
+
+// How we figured out where the Dropbox folder is.
+#[derive(Debug, PartialEq)]
+pub enum DropboxDirSource {
+    EnvVar,
+    InfoJson(PathBuf),
+    Default,
+}
+
+impl DropboxDirSource {
+    pub fn describe(&self) -> String {
+        match self {
+            DropboxDirSource::EnvVar => format!("from the {} environment variable", DROPBOX_DIR_VAR),
+            DropboxDirSource::InfoJson(path) => format!("from {}", path.to_string_lossy()),
+            DropboxDirSource::Default => String::from("by default"),
+        }
+    }
+}
+
+// Where the Dropbox folder should be, whether or not it actually exists.
+pub fn locate_dropbox_dir() -> (PathBuf, DropboxDirSource) {
+    if let Some(dir) = std::env::var_os(DROPBOX_DIR_VAR) {
+        return (PathBuf::from(dir), DropboxDirSource::EnvVar);
+    }
+    for path in find_info_json_paths() {
+        if let Some(dropbox_dir) = fs::read_to_string(&path).ok().and_then(|json| parse_info_json(&json)) {
+            return (dropbox_dir, DropboxDirSource::InfoJson(path));
+        }
+    }
+    let dropbox_dir = dirs::home_dir().expect("User should have a home directory!").join("Dropbox");
+    (dropbox_dir, DropboxDirSource::Default)
+}
+
+pub fn get_dropbox_dir() -> Result<PathBuf, String> {
+    let (dropbox_dir, _) = locate_dropbox_dir();
+    util::ensure_path_exists(&dropbox_dir)?;
+    Ok(dropbox_dir)
+}
+
+// The Dropbox client records where its folder actually is in an
+// info.json file, whose location depends on the platform.
+fn find_info_json_paths() -> Vec<PathBuf> {
+    let mut paths = vec![];
+    if cfg!(windows) {
+        for var in ["APPDATA", "LOCALAPPDATA"] {
+            if let Some(dir) = std::env::var_os(var) {
+                paths.push(PathBuf::from(dir).join("Dropbox").join("info.json"));
+            }
+        }
+    }
+    if let Some(home_dir) = dirs::home_dir() {
+        paths.push(home_dir.join(".dropbox").join("info.json"));
+    }
+    paths
+}
+
+// If both a personal and a business account are linked, we use the
+// personal one.
+fn parse_info_json(json: &str) -> Option<PathBuf> {
+    let info: serde_json::Value = serde_json::from_str(json).ok()?;
+    ["personal", "business"].iter()
+        .find_map(|account| info.get(account)?.get("path")?.as_str())
+        .map(PathBuf::from)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Health {
+    Healthy,
+    NotRunning,
+    Idle(u64),
+    // Running, but we couldn't tell when it last did anything.
+    Unknown,
+}
+
+pub fn check_health(is_daemon_running: bool, seconds_since_last_activity: Option<u64>) -> Health {
+    if !is_daemon_running {
+        return Health::NotRunning;
+    }
+    match seconds_since_last_activity {
+        Some(seconds) if seconds <= MAX_IDLE_SECONDS => Health::Healthy,
+        Some(seconds) => Health::Idle(seconds),
+        None => Health::Unknown,
+    }
+}
+
+pub fn get_health() -> Health {
+    let state_dir = dirs::home_dir().expect("User should have a home directory!").join(".dropbox");
+    let mut entries_left = MAX_STATE_DIR_ENTRIES;
+    let seconds_since_last_activity = get_last_modified(&state_dir, MAX_STATE_DIR_DEPTH, &mut entries_left).map(|modified| {
+        SystemTime::now().duration_since(modified).map(|d| d.as_secs()).unwrap_or(0)
+    });
+    check_health(is_daemon_running(), seconds_since_last_activity)
+}
+
+fn is_daemon_running() -> bool {
+    let mut sys = System::new();
+    sys.refresh_processes();
+    sys.processes().values().any(|process| process.name().to_lowercase().contains("dropbox"))
+}
+
+// Looks at most `depth` levels below `path`, and at most `entries_left`
+// entries in total.
+fn get_last_modified(path: &Path, depth: usize, entries_left: &mut usize) -> Option<SystemTime> {
+    let metadata = fs::metadata(path).ok()?;
+    let mut latest = metadata.modified().ok();
+    if metadata.is_dir() && depth > 0 {
+        for entry in fs::read_dir(path).ok()?.flatten() {
+            if *entries_left == 0 {
+                break;
+            }
+            *entries_left -= 1;
+            if let Some(modified) = get_last_modified(&entry.path(), depth - 1, entries_left) {
+                if latest.is_none_or(|l| modified > l) {
+                    latest = Some(modified);
+                }
+            }
+        }
+    }
+    latest
+}
+
+#[test]
+fn test_check_health() {
+    assert_eq!(check_health(false, Some(5)), Health::NotRunning);
+    assert_eq!(check_health(false, None), Health::NotRunning);
+    assert_eq!(check_health(true, Some(5)), Health::Healthy);
+    assert_eq!(check_health(true, Some(MAX_IDLE_SECONDS)), Health::Healthy);
+    assert_eq!(check_health(true, Some(MAX_IDLE_SECONDS + 1)), Health::Idle(MAX_IDLE_SECONDS + 1));
+    assert_eq!(check_health(true, None), Health::Unknown);
+}
+
+#[test]
+fn test_get_last_modified_limits() {
+    let tmp_dir = PathBuf::from(".test_get_last_modified_limits");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let deep_dir = tmp_dir.join("a").join("b");
+    fs::create_dir_all(&deep_dir).unwrap();
+    let long_ago = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    let later = long_ago + std::time::Duration::from_secs(60);
+    for dir in [&tmp_dir, &tmp_dir.join("a"), &deep_dir] {
+        fs::File::open(dir).unwrap().set_modified(long_ago).unwrap();
+    }
+    fs::write(deep_dir.join("marker"), "hi").unwrap();
+    fs::File::options().write(true).open(deep_dir.join("marker")).unwrap().set_modified(later).unwrap();
+    fs::File::open(&deep_dir).unwrap().set_modified(long_ago).unwrap();
+
+    assert_eq!(get_last_modified(&tmp_dir, 3, &mut 100), Some(later));
+    assert_eq!(get_last_modified(&tmp_dir, 2, &mut 100), Some(long_ago));
+    assert_eq!(get_last_modified(&tmp_dir, 3, &mut 2), Some(long_ago));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_parse_info_json() {
+    assert_eq!(parse_info_json(r#"{"personal": {"path": "/mnt/big/Dropbox", "host": 123}}"#), Some(PathBuf::from("/mnt/big/Dropbox")));
+    assert_eq!(parse_info_json(r#"{"business": {"path": "/work/Dropbox"}, "personal": {"path": "/home/me/Dropbox"}}"#), Some(PathBuf::from("/home/me/Dropbox")));
+    assert_eq!(parse_info_json(r#"{"business": {"path": "/work/Dropbox"}}"#), Some(PathBuf::from("/work/Dropbox")));
+    assert_eq!(parse_info_json("{}"), None);
+    assert_eq!(parse_info_json("not json"), None);
 }
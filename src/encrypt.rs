@@ -0,0 +1,520 @@
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::dir_state::{self, CopyDiff, DirState, FileError, ListedEntry};
+use crate::file_filter::{FileFilter, RESERVED_PREFIX};
+use crate::output::{complain, say};
+use crate::plan::AppComparison;
+use crate::report::CopyReport;
+use crate::{add_file_errors, apply_direction, ask, check_case_collisions, interrupt, rollback, snapshot, SyncOptions, SyncResult};
+
+// Where the passphrase comes from when nobody's around to type it.
+pub const PASSPHRASE_ENV_VAR: &str = "DROPSYNC_PASSPHRASE";
+
+// Every encrypted file starts with this, followed by the original file's
+// size and modification time and the nonce it was encrypted with. None of
+// that is secret, and it lets us compare files without decrypting them,
+// but it's authenticated along with the contents, so it can't be changed
+// without us noticing.
+const MAGIC: &[u8; 8] = b"DSYNCEN1";
+const NONCE_SIZE: usize = 24;
+const HEADER_SIZE: usize = MAGIC.len() + 8 + 8 + 4 + NONCE_SIZE;
+const TAG_SIZE: u64 = 16;
+
+// How much work deriving a key from the passphrase takes, as a power of
+// two. Each app's Dropbox folder remembers the value it was set up with.
+const DEFAULT_LOG_N: u8 = 17;
+
+// What's encrypted in the key file, to tell whether a passphrase is right.
+const CHECK_CONTENTS: &[u8] = b"dropsync";
+
+// What to do when the app and its encrypted copy conflict: keep one side, or neither.
+static CONFLICT_CHOICES: [ask::Choice<Option<SyncResult>>; 3] = [
+    ask::Choice { name: "app", value: Some(SyncResult::AppNewerThanDropbox) },
+    ask::Choice { name: "dropbox", value: Some(SyncResult::DropboxNewerThanApp) },
+    ask::Choice { name: "skip", value: None },
+];
+
+// The passphrase typed in earlier in this run, so it's only asked for once.
+static PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+// How the app's key is derived from the passphrase, along with something
+// encrypted with it, so that a mistyped passphrase is caught before any
+// files are encrypted with the wrong key.
+#[derive(Serialize, Deserialize)]
+struct KeyFile {
+    log_n: u8,
+    salt: Vec<u8>,
+    check: Vec<u8>,
+}
+
+fn get_key_file_path(dropbox_path: &Path) -> PathBuf {
+    dropbox_path.join(format!("{}encryption.json", RESERVED_PREFIX))
+}
+
+struct Header {
+    size: u64,
+    modified: u64,
+    modified_nanos: u32,
+    nonce: XNonce,
+}
+
+impl Header {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+        bytes.extend_from_slice(&self.modified.to_le_bytes());
+        bytes.extend_from_slice(&self.modified_nanos.to_le_bytes());
+        bytes.extend_from_slice(&self.nonce);
+        bytes
+    }
+
+    fn parse(bytes: &[u8]) -> io::Result<Header> {
+        if bytes.len() < HEADER_SIZE || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "it isn't encrypted by dropsync"));
+        }
+        let u64_at = |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        Ok(Header {
+            size: u64_at(8),
+            modified: u64_at(16),
+            modified_nanos: u32::from_le_bytes(bytes[24..28].try_into().unwrap()),
+            nonce: *XNonce::from_slice(&bytes[28..HEADER_SIZE]),
+        })
+    }
+
+    fn modified_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(self.modified, self.modified_nanos)
+    }
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], log_n: u8) -> io::Result<Key> {
+    let params = scrypt::Params::new(log_n, 8, 1, 32).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    let mut key = Key::default();
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut key).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(key)
+}
+
+fn encrypt_bytes(key: &Key, contents: &[u8], modified: SystemTime) -> io::Result<Vec<u8>> {
+    let since_epoch = modified.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let header = Header {
+        size: contents.len() as u64,
+        modified: since_epoch.as_secs(),
+        modified_nanos: since_epoch.subsec_nanos(),
+        nonce: XChaCha20Poly1305::generate_nonce(&mut OsRng),
+    };
+    let mut encrypted = header.to_bytes();
+    let ciphertext = XChaCha20Poly1305::new(key).encrypt(&header.nonce, Payload { msg: contents, aad: &encrypted })
+        .map_err(|_| io::Error::other("it couldn't be encrypted"))?;
+    encrypted.extend_from_slice(&ciphertext);
+    Ok(encrypted)
+}
+
+fn decrypt_bytes(key: &Key, encrypted: &[u8]) -> io::Result<(Header, Vec<u8>)> {
+    let header = Header::parse(encrypted)?;
+    let (aad, ciphertext) = encrypted.split_at(HEADER_SIZE);
+    let contents = XChaCha20Poly1305::new(key).decrypt(&header.nonce, Payload { msg: ciphertext, aad })
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "it couldn't be decrypted, so it's either corrupt or was encrypted with a different passphrase"))?;
+    if contents.len() as u64 != header.size {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "it isn't the size it's supposed to be"));
+    }
+    Ok((header, contents))
+}
+
+// Sets up encryption for the app's Dropbox folder with the given passphrase.
+fn create_key(dropbox_path: &Path, passphrase: &str, log_n: u8) -> io::Result<Key> {
+    let mut salt = vec![0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt, log_n)?;
+    let check = encrypt_bytes(&key, CHECK_CONTENTS, SystemTime::UNIX_EPOCH)?;
+    let json = serde_json::to_string_pretty(&KeyFile { log_n, salt, check })?;
+    fs::create_dir_all(dropbox_path)?;
+    fs::write(get_key_file_path(dropbox_path), json)?;
+    Ok(key)
+}
+
+// The key the app's Dropbox folder is encrypted with, setting one up if
+// it isn't encrypted yet.
+fn load_key(dropbox_path: &Path, passphrase: &str) -> Result<Key, String> {
+    let key_file_path = get_key_file_path(dropbox_path);
+    if !key_file_path.exists() {
+        return create_key(dropbox_path, passphrase, DEFAULT_LOG_N).map_err(|e| format!("Unable to set up encryption: {}", e));
+    }
+    let key_file = fs::read_to_string(&key_file_path).map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str::<KeyFile>(&json).map_err(|e| e.to_string()))
+        .map_err(|e| format!("Unable to read {}: {}", key_file_path.to_string_lossy(), e))?;
+    let key = derive_key(passphrase, &key_file.salt, key_file.log_n).map_err(|e| format!("Unable to derive the key: {}", e))?;
+    match decrypt_bytes(&key, &key_file.check) {
+        Ok((_, contents)) if contents == CHECK_CONTENTS => Ok(key),
+        _ => Err(String::from("That isn't the passphrase this app's Dropbox folder was encrypted with.")),
+    }
+}
+
+fn get_passphrase(options: &SyncOptions) -> Result<String, String> {
+    if let Some(passphrase) = &options.passphrase {
+        return Ok(passphrase.clone());
+    }
+    let mut remembered = PASSPHRASE.lock().unwrap();
+    if let Some(passphrase) = remembered.as_ref() {
+        return Ok(passphrase.clone());
+    }
+    if options.non_interactive {
+        return Err(format!("Encrypting needs a passphrase, but we're running non-interactively and {} isn't set.", PASSPHRASE_ENV_VAR));
+    }
+    let passphrase = rpassword::prompt_password("  Passphrase for encrypted apps: ").map_err(|e| format!("Unable to read the passphrase: {}", e))?;
+    *remembered = Some(passphrase.clone());
+    Ok(passphrase)
+}
+
+fn forget_passphrase() {
+    *PASSPHRASE.lock().unwrap() = None;
+}
+
+// Reads the size and modification time of the file an encrypted one
+// holds, making sure it's all there.
+fn read_header(path: &Path) -> io::Result<Header> {
+    let mut file = fs::File::open(path)?;
+    let mut bytes = vec![0; HEADER_SIZE];
+    file.read_exact(&mut bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "it isn't encrypted by dropsync"))?;
+    let header = Header::parse(&bytes)?;
+    if file.metadata()?.len() != HEADER_SIZE as u64 + header.size + TAG_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "it's been truncated"));
+    }
+    Ok(header)
+}
+
+// Anything that isn't encrypted is left out of the listing, and noted in
+// `unreadable` instead.
+fn list_dir(dir: &Path, file_filter: &FileFilter, unreadable: &RefCell<Vec<String>>) -> io::Result<Vec<ListedEntry>> {
+    let mut entries = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        let is_dir = fs::metadata(entry.path())?.is_dir();
+        // Things like Dropbox's own files and our key file aren't encrypted.
+        if !file_filter.is_path_included(&entry.path(), is_dir) {
+            continue;
+        }
+        if is_dir {
+            entries.push(ListedEntry { name, is_dir, size: 0, modified: 0 });
+        } else {
+            match read_header(&entry.path()) {
+                Ok(header) => entries.push(ListedEntry { name, is_dir, size: header.size, modified: header.modified }),
+                Err(e) => unreadable.borrow_mut().push(format!("Unable to read {}: {}", entry.path().to_string_lossy(), e)),
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// Scans the app's encrypted Dropbox folder as if it were decrypted. A
+// missing folder looks just like an empty one.
+pub fn scan_encrypted(app: &AppConfig) -> DirState {
+    let exists = app.dropbox_path.exists();
+    let unreadable = RefCell::new(vec![]);
+    let mut state = DirState::from_listing(&app.dropbox_path, &app.file_filter, &|dir: &Path| {
+        if exists { list_dir(dir, &app.file_filter, &unreadable) } else { Ok(vec![]) }
+    });
+    for error in unreadable.into_inner() {
+        state.add_read_error(error);
+    }
+    // Hashing the encrypted files wouldn't tell us anything about what's
+    // in them, so they can only be compared by modification time and size.
+    state.with_compare_options(dir_state::CompareOptions { compare_hashes: false, paranoid: false, ..app.compare })
+}
+
+pub fn compare_app_with_encrypted(app: &AppConfig, hostname: &str) -> AppComparison {
+    let app_state = app.scan_path().with_compare_options(dir_state::CompareOptions { compare_hashes: false, paranoid: false, ..app.compare });
+    let dropbox_state = scan_encrypted(app);
+    let last_sync = snapshot::load(app, hostname);
+    // Anything we couldn't read might be something we'd otherwise
+    // overwrite with a copy encrypted with a different passphrase.
+    let result = if dropbox_state.was_read_cleanly() {
+        snapshot::classify_with_snapshot(&app_state, &dropbox_state, last_sync.as_ref())
+    } else {
+        SyncResult::ReadError
+    };
+    let diff = match result {
+        SyncResult::AppNewerThanDropbox => {
+            let mut diff = app_state.diff_copy_into(&dropbox_state);
+            if !app.may_delete_from_dropbox(hostname) {
+                diff.to_delete.clear();
+            }
+            diff
+        },
+        SyncResult::DropboxNewerThanApp => {
+            let mut diff = dropbox_state.diff_copy_into(&app_state);
+            if !app.may_delete_from_app() {
+                diff.to_delete.clear();
+            }
+            diff
+        },
+        _ => Default::default(),
+    };
+    AppComparison { result, diff, app_state, dropbox_state }
+}
+
+pub fn sync_app_with_encryption(app: &AppConfig, confirm_if_app_is_newer: bool, options: &SyncOptions) -> (SyncResult, CopyReport) {
+    let mut report = CopyReport::default();
+    let comparison = compare_app_with_encrypted(app, &options.hostname);
+    for error in comparison.app_state.read_errors().into_iter().chain(comparison.dropbox_state.read_errors()) {
+        complain!("  Warning: {}", error);
+    }
+    let (app_state, dropbox_state) = (&comparison.app_state, &comparison.dropbox_state);
+    let (result, forced) = apply_direction(comparison.result, options);
+    let (result, should_ask) = match result {
+        SyncResult::AppNewerThanDropbox => {
+            if !forced {
+                say!("  App state is newer than Dropbox.");
+            }
+            (SyncResult::AppNewerThanDropbox, confirm_if_app_is_newer)
+        },
+        SyncResult::DropboxNewerThanApp => {
+            if !forced {
+                say!("  Dropbox state is newer than app.");
+            }
+            (SyncResult::DropboxNewerThanApp, true)
+        },
+        SyncResult::AlreadySynced => {
+            say!("  App state matches Dropbox. Nothing to do!");
+            report.completed = true;
+            return (comparison.result, report);
+        },
+        SyncResult::BothEmpty => {
+            say!("  Both Dropbox and app state are empty. Nothing to do!");
+            report.completed = true;
+            return (comparison.result, report);
+        },
+        SyncResult::Conflict => {
+            complain!("  App and Dropbox state are in conflict; manual resolution required.");
+            if options.non_interactive {
+                complain!("  Skipping it, since we're running non-interactively.");
+                return (SyncResult::Skipped, report);
+            }
+            match ask::ask_with_choices("  ", "Which side do you want to keep? ", &CONFLICT_CHOICES) {
+                Some(result) => (result, false),
+                None => {
+                    say!("  Okay, leaving both sides alone for now.");
+                    return (SyncResult::Skipped, report);
+                },
+            }
+        },
+        SyncResult::ReadError => {
+            complain!("  Unable to read the app or its encrypted Dropbox state, so not doing anything.");
+            return (comparison.result, report);
+        },
+        SyncResult::Skipped | SyncResult::Merged => unreachable!("classification never skips or merges an app"),
+    };
+    let to_dropbox = result == SyncResult::AppNewerThanDropbox;
+    let (from_state, to_state) = if to_dropbox { (app_state, dropbox_state) } else { (dropbox_state, app_state) };
+    let mut diff = from_state.diff_copy_into(to_state);
+    diff.to_copy = from_state.files_differing_from(to_state).into_iter().map(|(path, _)| path).collect();
+    let may_delete = if to_dropbox { app.may_delete_from_dropbox(&options.hostname) } else { app.may_delete_from_app() };
+    if !may_delete {
+        diff.to_delete.clear();
+    }
+    if options.dry_run {
+        say!("  Dry run: would copy {} file(s) and delete {}.", diff.to_copy.len(), diff.to_delete.len());
+        return (result, report);
+    }
+    if should_ask && !options.non_interactive && !options.already_confirmed {
+        if !ask::ask_yes_or_no("  Proceed with synchronization (y/n) ? ") {
+            say!("  Okay, not doing anything.");
+            return (result, report);
+        }
+    } else {
+        say!("  Synchronizing files.");
+    }
+    if !check_case_collisions(from_state, &mut report) {
+        return (result, report);
+    }
+    let key = match get_passphrase(options).and_then(|passphrase| load_key(&app.dropbox_path, &passphrase)) {
+        Ok(key) => key,
+        Err(e) => {
+            // It might have been mistyped, so ask again next time.
+            forget_passphrase();
+            complain!("  {} Not doing anything.", e);
+            report.errors.push(e);
+            return (SyncResult::ReadError, report);
+        },
+    };
+    let _changing_files = interrupt::ChangingFiles::start();
+    let use_trash = options.use_trash || app.use_trash;
+    let outcome = if to_dropbox {
+        copy_across(app, from_state, to_state, &diff, rollback::Side::Dropbox, use_trash, &|src, dest| encrypt_file(&key, src, dest))
+    } else {
+        copy_across(app, from_state, to_state, &diff, rollback::Side::App, use_trash, &|src, dest| decrypt_file(&key, src, dest))
+    };
+    match outcome {
+        Ok(()) => {
+            report.files_copied = diff.to_copy.len();
+            report.files_deleted = diff.to_delete.len();
+            report.completed = true;
+            snapshot::save(app, &options.hostname, &scan_encrypted(app));
+        },
+        Err(errors) => add_file_errors(&mut report, "sync", &errors),
+    }
+    (result, report)
+}
+
+// Copies what the diff says from one side to the other, encrypting or
+// decrypting each file along the way, then deletes what it says to.
+fn copy_across(app: &AppConfig, from_state: &DirState, to_state: &DirState, diff: &CopyDiff, side: rollback::Side, use_trash: bool, copy: &dyn Fn(&Path, &Path) -> io::Result<()>) -> Result<(), Vec<FileError>> {
+    if let Some(backup_path) = app.new_backup_path() {
+        let to_back_up = [diff.to_copy.clone(), diff.to_delete.clone()].concat();
+        to_state.back_up_into(&to_back_up, &backup_path)?;
+        rollback::record(&backup_path, &[rollback::Change::new(side, "", &diff.to_copy, &diff.to_delete)])?;
+    }
+    let mut errors = vec![];
+    // Empty directories are synced too.
+    for (rel_path, entry) in from_state.to_listing() {
+        if entry.is_dir {
+            let dir_path = to_state.path().join(rel_path);
+            if let Err(error) = fs::create_dir_all(&dir_path) {
+                errors.push(FileError { path: dir_path, error });
+            }
+        }
+    }
+    for rel_path in diff.to_copy.iter() {
+        if interrupt::is_interrupted() {
+            break;
+        }
+        let dest_path = to_state.path().join(rel_path);
+        if let Err(error) = copy(&from_state.path().join(rel_path), &dest_path) {
+            errors.push(FileError { path: dest_path, error });
+        }
+    }
+    if !errors.is_empty() || interrupt::is_interrupted() {
+        return Err(errors);
+    }
+    dir_state::remove_files_from(to_state.path(), &diff.to_delete, use_trash)
+}
+
+fn encrypt_file(key: &Key, src_path: &Path, dest_path: &Path) -> io::Result<()> {
+    let modified = fs::metadata(src_path)?.modified()?;
+    let encrypted = encrypt_bytes(key, &fs::read(src_path)?, modified)?;
+    write_through_temp_file(dest_path, &encrypted, None)
+}
+
+fn decrypt_file(key: &Key, src_path: &Path, dest_path: &Path) -> io::Result<()> {
+    let (header, contents) = decrypt_bytes(key, &fs::read(src_path)?)?;
+    write_through_temp_file(dest_path, &contents, Some(header.modified_time()))
+}
+
+// Writes the file next to where it belongs and then moves it into place,
+// so an interrupted write never leaves half a file behind.
+fn write_through_temp_file(dest_path: &Path, contents: &[u8], modified: Option<SystemTime>) -> io::Result<()> {
+    if let Some(parent) = dest_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let filename = dest_path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp_path = dest_path.with_file_name(format!("{}tmp-{}", RESERVED_PREFIX, filename));
+    let result = fs::File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        if let Some(modified) = modified {
+            file.set_modified(modified)?;
+        }
+        file.sync_all()
+    }).and_then(|_| fs::rename(&tmp_path, dest_path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+#[test]
+fn test_encrypt_bytes() {
+    let key = derive_key("hunter2", b"salty", 4).unwrap();
+    let modified = SystemTime::UNIX_EPOCH + Duration::new(1614834368, 123);
+    let encrypted = encrypt_bytes(&key, b"boop", modified).unwrap();
+    assert_eq!(encrypted.len() as u64, HEADER_SIZE as u64 + 4 + TAG_SIZE);
+    assert!(!encrypted.windows(4).any(|window| window == b"boop"));
+    let (header, contents) = decrypt_bytes(&key, &encrypted).unwrap();
+    assert_eq!(contents, b"boop");
+    assert_eq!(header.modified_time(), modified);
+
+    // The wrong key, or changing anything, even the header, is caught.
+    assert!(decrypt_bytes(&derive_key("hunter3", b"salty", 4).unwrap(), &encrypted).is_err());
+    let mut tampered = encrypted.clone();
+    tampered[16] ^= 1;
+    assert!(decrypt_bytes(&key, &tampered).is_err());
+    assert!(decrypt_bytes(&key, b"boop").is_err());
+}
+
+#[test]
+fn test_sync_app_with_encryption() {
+    let tmp_dir = PathBuf::from(".test_sync_app_with_encryption");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app = AppConfig {
+        name: String::from("boop"),
+        path: tmp_dir.join("app"),
+        dropbox_path: tmp_dir.join("dropbox"),
+        encrypt: true,
+        ..Default::default()
+    };
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &app.file_filter);
+    src_state.copy_into(&app.path).unwrap();
+    fs::create_dir_all(app.path.join("empty")).unwrap();
+    // Deriving keys the usual way takes a while, so set it up more cheaply.
+    create_key(&app.dropbox_path, "hunter2", 4).unwrap();
+    let make_options = |hostname: &str, passphrase: Option<&str>| SyncOptions { non_interactive: true, passphrase: passphrase.map(String::from), ..SyncOptions::new(hostname) };
+    let (options, other_options) = (make_options("my_computer", Some("hunter2")), make_options("other_computer", Some("hunter2")));
+    snapshot::save(&app, "my_computer", &scan_encrypted(&app));
+    snapshot::save(&app, "other_computer", &scan_encrypted(&app));
+
+    // Without a passphrase, nothing happens.
+    assert_eq!(sync_app_with_encryption(&app, false, &make_options("my_computer", None)).0, SyncResult::ReadError);
+    assert!(!app.dropbox_path.join("boop").exists());
+
+    let (result, report) = sync_app_with_encryption(&app, false, &options);
+    assert_eq!(result, SyncResult::AppNewerThanDropbox);
+    assert!(report.completed);
+    let original = fs::read(app.path.join("subdir").join("shoop")).unwrap();
+    assert_ne!(fs::read(app.dropbox_path.join("subdir").join("shoop")).unwrap(), original);
+    assert!(scan_encrypted(&app).are_contents_equal_to(&app.scan_path()));
+    assert_eq!(sync_app_with_encryption(&app, false, &options).0, SyncResult::AlreadySynced);
+
+    // Another computer syncing into a fresh folder gets everything back.
+    let other_app = AppConfig { path: tmp_dir.join("other_app"), ..Default::default() };
+    let other_app = AppConfig { name: app.name.clone(), dropbox_path: app.dropbox_path.clone(), encrypt: true, ..other_app };
+    fs::create_dir_all(&other_app.path).unwrap();
+    let (result, report) = sync_app_with_encryption(&other_app, false, &other_options);
+    assert_eq!(result, SyncResult::DropboxNewerThanApp);
+    assert!(report.completed);
+    assert_eq!(fs::read(other_app.path.join("subdir").join("shoop")).unwrap(), original);
+    assert!(other_app.path.join("empty").is_dir());
+    assert!(other_app.scan_path().are_contents_equal_to(&app.scan_path()));
+
+    // Deleting a file from the app deletes it from Dropbox, and then from
+    // the other computer.
+    fs::remove_file(app.path.join("boop")).unwrap();
+    assert_eq!(sync_app_with_encryption(&app, false, &options).0, SyncResult::AppNewerThanDropbox);
+    assert!(!app.dropbox_path.join("boop").exists());
+    assert_eq!(sync_app_with_encryption(&other_app, false, &other_options).0, SyncResult::DropboxNewerThanApp);
+    assert!(!other_app.path.join("boop").exists());
+
+    // The wrong passphrase is caught before anything is changed.
+    fs::write(app.path.join("subdir").join("shoop"), "changed").unwrap();
+    assert_eq!(sync_app_with_encryption(&app, false, &make_options("my_computer", Some("hunter3"))).0, SyncResult::ReadError);
+
+    // A file that isn't encrypted is reported, without hiding everything else.
+    fs::write(app.dropbox_path.join("subdir").join("plain"), "hi").unwrap();
+    let dropbox_state = scan_encrypted(&app);
+    assert_eq!(dropbox_state.read_errors().len(), 1);
+    assert!(dropbox_state.read_errors()[0].contains("plain"));
+    assert!(dropbox_state.has_file(&PathBuf::from("subdir").join("shoop")));
+    assert!(!dropbox_state.has_file(&PathBuf::from("subdir").join("plain")));
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
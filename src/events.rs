@@ -0,0 +1,109 @@
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::Path;
+use serde::Serialize;
+
+use crate::output::complain;
+use crate::SyncResult;
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    AppStarted { app: &'a str },
+    Conflict { app: &'a str },
+    AppFinished { app: &'a str, result: SyncResult },
+}
+
+impl Event<'_> {
+    pub fn to_json_line(&self) -> String {
+        let mut line = serde_json::to_string(self).unwrap();
+        line.push('\n');
+        line
+    }
+}
+
+// Writes events as JSON lines to a listening socket. If nothing is
+// listening (or it goes away), events are silently dropped.
+pub struct EventSink {
+    stream: RefCell<Option<Box<dyn Write>>>,
+}
+
+impl EventSink {
+    pub fn none() -> Self {
+        EventSink { stream: RefCell::new(None) }
+    }
+
+    pub fn connect(path: &Path) -> Self {
+        match open_stream(path) {
+            Ok(stream) => EventSink { stream: RefCell::new(Some(stream)) },
+            Err(e) => {
+                complain!("Unable to connect to event socket {} ({}), not sending events.", path.to_string_lossy(), e);
+                Self::none()
+            }
+        }
+    }
+
+    pub fn emit(&self, event: &Event) {
+        let mut stream = self.stream.borrow_mut();
+        if let Some(writer) = stream.as_mut() {
+            let line = event.to_json_line();
+            if writer.write_all(line.as_bytes()).and_then(|_| writer.flush()).is_err() {
+                *stream = None;
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn open_stream(path: &Path) -> std::io::Result<Box<dyn Write>> {
+    Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn open_stream(path: &Path) -> std::io::Result<Box<dyn Write>> {
+    // On Windows, named pipes (e.g. `\\.\pipe\dropsync`) can be opened like files.
+    Ok(Box::new(std::fs::OpenOptions::new().write(true).open(path)?))
+}
+
+#[test]
+fn test_event_to_json_line() {
+    assert_eq!(Event::AppStarted { app: "boop" }.to_json_line(), "{\"event\":\"app_started\",\"app\":\"boop\"}\n");
+    assert_eq!(Event::Conflict { app: "boop" }.to_json_line(), "{\"event\":\"conflict\",\"app\":\"boop\"}\n");
+    assert_eq!(
+        Event::AppFinished { app: "boop", result: SyncResult::AppNewerThanDropbox }.to_json_line(),
+        "{\"event\":\"app_finished\",\"app\":\"boop\",\"result\":\"app_newer_than_dropbox\"}\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_event_sink_socket() {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::path::PathBuf;
+
+    let socket_path = PathBuf::from(".test_event_socket");
+    if socket_path.exists() {
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+    let listener = UnixListener::bind(&socket_path).unwrap();
+
+    let sink = EventSink::connect(&socket_path);
+    let (stream, _) = listener.accept().unwrap();
+    sink.emit(&Event::AppStarted { app: "boop" });
+    sink.emit(&Event::AppFinished { app: "boop", result: SyncResult::AlreadySynced });
+
+    let lines: Vec<String> = BufReader::new(stream).lines().take(2).map(|l| l.unwrap()).collect();
+    assert_eq!(lines, vec![
+        "{\"event\":\"app_started\",\"app\":\"boop\"}",
+        "{\"event\":\"app_finished\",\"app\":\"boop\",\"result\":\"already_synced\"}",
+    ]);
+
+    std::fs::remove_file(&socket_path).unwrap();
+}
+
+#[test]
+fn test_event_sink_without_socket_is_noop() {
+    let sink = EventSink::connect(Path::new(".test_nonexistent_event_socket"));
+    sink.emit(&Event::AppStarted { app: "boop" });
+}
@@ -1,20 +1,147 @@
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::Path;
 use std::process::Command;
 
-pub fn open_in_explorer(path: &PathBuf) {
-    println!("Opening {}.", path.to_string_lossy());
+use crate::output::say;
+
+// Set this to use a different program to open things, e.g.
+// `DROPSYNC_OPEN_CMD="thunar {path}"`. If `{path}` isn't in it, the path
+// is added to the end.
+const OPEN_CMD_VAR: &str = "DROPSYNC_OPEN_CMD";
+
+fn get_file_manager() -> Option<&'static str> {
     if cfg!(target_os = "windows") {
-        Command::new("explorer")
-          .arg(&path.as_os_str())
-          .spawn()
-          .unwrap();
+        Some("explorer")
     } else if cfg!(target_os = "macos") {
-        Command::new("open")
-          .arg(&path.as_os_str())
-          .spawn()
-          .unwrap();
+        Some("open")
+    } else if cfg!(any(target_os = "linux", target_os = "freebsd", target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly")) {
+        Some("xdg-open")
     } else {
-        println!("Oops, I don't know how to open it on this OS.");
-        println!("Please open it yourself. Sorry!");
+        None
+    }
+}
+
+// Under WSL, `xdg-open` usually isn't around, but Windows' own Explorer
+// is, as long as we give it a Windows path.
+pub fn is_wsl() -> bool {
+    cfg!(target_os = "linux") && fs::read_to_string("/proc/version").is_ok_and(|version| is_wsl_version(&version))
+}
+
+fn is_wsl_version(version: &str) -> bool {
+    version.to_lowercase().contains("microsoft")
+}
+
+#[test]
+fn test_is_wsl_version() {
+    assert!(is_wsl_version("Linux version 5.15.90.1-microsoft-standard-WSL2 (root@1ab2c3) (gcc version 11.2.0)"));
+    assert!(is_wsl_version("Linux version 4.4.0-19041-Microsoft (Microsoft@Microsoft.com) (gcc version 5.4.0)"));
+    assert!(!is_wsl_version("Linux version 6.1.0-18-amd64 (debian-kernel@lists.debian.org)"));
+}
+
+fn to_windows_path(path: &Path) -> io::Result<OsString> {
+    let output = Command::new("wslpath").arg("-w").arg(path).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("wslpath couldn't convert it: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(OsString::from(String::from_utf8_lossy(&output.stdout).trim()))
+}
+
+// The program we'd use to open things, if we know of one.
+pub fn get_open_program() -> Option<String> {
+    if let Some(command) = std::env::var(OPEN_CMD_VAR).ok().and_then(|command| parse_open_command(&command, Path::new(""))) {
+        return Some(command.0);
+    }
+    if is_wsl() {
+        return Some(String::from("explorer.exe"));
     }
+    get_file_manager().map(String::from)
+}
+
+// Splits a command like `nautilus --browser {path}` into the program and
+// its arguments.
+fn parse_open_command(command: &str, path: &Path) -> Option<(String, Vec<OsString>)> {
+    let mut words = command.split_whitespace();
+    let program = String::from(words.next()?);
+    let mut args = vec![];
+    let mut has_path = false;
+    for word in words {
+        if word.contains("{path}") {
+            has_path = true;
+            args.push(OsString::from(word.replace("{path}", &path.to_string_lossy())));
+        } else {
+            args.push(OsString::from(word));
+        }
+    }
+    if !has_path {
+        args.push(path.as_os_str().to_os_string());
+    }
+    Some((program, args))
+}
+
+#[test]
+fn test_parse_open_command() {
+    let path = Path::new("/games/boop");
+    assert_eq!(parse_open_command("", path), None);
+    assert_eq!(parse_open_command("thunar", path), Some((String::from("thunar"), vec![OsString::from("/games/boop")])));
+    assert_eq!(
+        parse_open_command("nautilus --browser {path} --new-window", path),
+        Some((String::from("nautilus"), vec![OsString::from("--browser"), OsString::from("/games/boop"), OsString::from("--new-window")]))
+    );
+    assert_eq!(parse_open_command("fm --dir={path}", path), Some((String::from("fm"), vec![OsString::from("--dir=/games/boop")])));
+}
+
+pub fn open_in_explorer(path: &Path) -> io::Result<()> {
+    say!("Opening {}.", path.to_string_lossy());
+    let custom_command = std::env::var(OPEN_CMD_VAR).ok().and_then(|command| parse_open_command(&command, path));
+    let (file_manager, args) = match custom_command {
+        Some(command) => command,
+        None if is_wsl() => (String::from("explorer.exe"), vec![to_windows_path(path)?]),
+        None => {
+            let file_manager = get_file_manager().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Unsupported, format!("I don't know how to open things on this OS; try setting {}", OPEN_CMD_VAR))
+            })?;
+            (String::from(file_manager), vec![path.as_os_str().to_os_string()])
+        }
+    };
+    run_file_manager(&file_manager, &args)
+}
+
+// Opens the folder containing the given file, highlighting the file
+// itself if the OS's file manager knows how to.
+pub fn reveal_in_explorer(path: &Path) -> io::Result<()> {
+    let parent = path.parent().unwrap_or(path);
+    if std::env::var(OPEN_CMD_VAR).is_ok() {
+        return open_in_explorer(parent);
+    }
+    let args = if cfg!(target_os = "windows") {
+        let mut arg = OsString::from("/select,");
+        arg.push(path.as_os_str());
+        vec![arg]
+    } else if cfg!(target_os = "macos") {
+        vec![OsString::from("-R"), path.as_os_str().to_os_string()]
+    } else if is_wsl() {
+        let mut arg = OsString::from("/select,");
+        arg.push(to_windows_path(path)?);
+        say!("Revealing {}.", path.to_string_lossy());
+        return run_file_manager("explorer.exe", &[arg]);
+    } else {
+        return open_in_explorer(parent);
+    };
+    say!("Revealing {}.", path.to_string_lossy());
+    run_file_manager(get_file_manager().unwrap(), &args)
+}
+
+fn run_file_manager(file_manager: &str, args: &[OsString]) -> io::Result<()> {
+    // Note that we don't check the exit status, since Windows Explorer
+    // returns a non-zero one even when it works.
+    Command::new(file_manager).args(args).status().map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            io::Error::new(e.kind(), format!("{} isn't installed", file_manager))
+        } else {
+            e
+        }
+    })?;
+    Ok(())
 }
@@ -1,31 +1,271 @@
 use std::fs::DirEntry;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 use glob::Pattern;
 
+// Files whose names start with this are dropsync's own bookkeeping, and
+// are never synchronized.
+pub const RESERVED_PREFIX: &str = ".dropsync-";
+
+// Files that operating systems and file managers leave lying around,
+// which never belong to an app and just cause spurious differences
+// between computers. Compared case-insensitively.
+const OS_FILES: &[&str] = &[
+    ".ds_store",
+    ".spotlight-v100",
+    ".trashes",
+    ".fseventsd",
+    ".directory",
+    "thumbs.db",
+    "ehthumbs.db",
+    "desktop.ini",
+    "$recycle.bin",
+];
+
+// macOS stores extended attributes in files like `._save.sav` on some
+// filesystems.
+const APPLE_DOUBLE_PREFIX: &str = "._";
+
+fn is_os_file(filename: &str) -> bool {
+    filename.starts_with(APPLE_DOUBLE_PREFIX) || OS_FILES.contains(&filename.to_lowercase().as_str())
+}
+
+// When two computers change a file at the same time, Dropbox keeps one of
+// them as something like `save (Laptop's conflicted copy 2023-01-01).sav`.
+// Those are for a person to sort out, rather than for the app to see.
+fn is_dropbox_conflicted_copy(filename: &str) -> bool {
+    let filename = filename.to_lowercase();
+    filename.match_indices("conflicted copy ").any(|(i, marker)| {
+        filename[..i].contains(" (") && filename[i + marker.len()..].starts_with(|c: char| c.is_ascii_digit())
+    })
+}
+
+// What to do with symbolic links.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum SymlinkMode {
+    // Act as though they don't exist.
+    #[default]
+    Skip,
+    // Copy the links themselves.
+    Copy,
+    // Treat them like whatever they point at.
+    Follow,
+}
+
+impl SymlinkMode {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "skip" => Some(SymlinkMode::Skip),
+            "copy" => Some(SymlinkMode::Copy),
+            "follow" => Some(SymlinkMode::Follow),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct FileFilter {
-    include_only: Option<Pattern>
+    include_only: Option<Pattern>,
+    // If non-empty, only files with one of these (lowercase) extensions
+    // are included.
+    include_extensions: Vec<String>,
+    // Matched against file and directory names. Patterns ending with
+    // a slash, like `cache/`, only match directories.
+    exclude: Vec<Pattern>,
+    pub symlinks: SymlinkMode,
+    exclude_os_files: bool,
+    exclude_conflicted_copies: bool,
+    // Files smaller or larger than these many bytes are left alone.
+    min_file_size: Option<u64>,
+    max_file_size: Option<u64>,
+    // Files modified less than this many seconds ago are probably still
+    // in use, like lock files, so they're left alone.
+    min_file_age_secs: Option<u64>,
+}
+
+impl Default for FileFilter {
+    fn default() -> Self {
+        FileFilter::new(None, vec![])
+    }
 }
 
 impl FileFilter {
-    pub fn new(include_only: Option<Pattern>) -> Self {
-        FileFilter { include_only }
+    pub fn new(include_only: Option<Pattern>, exclude: Vec<Pattern>) -> Self {
+        FileFilter { include_only, include_extensions: vec![], exclude, symlinks: SymlinkMode::default(), exclude_os_files: true, exclude_conflicted_copies: true, min_file_size: None, max_file_size: None, min_file_age_secs: None }
+    }
+
+    pub fn with_extensions(mut self, extensions: Vec<String>) -> Self {
+        self.include_extensions = extensions.iter()
+            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+            .collect();
+        self
+    }
+
+    pub fn with_size_limits(mut self, min_file_size: Option<u64>, max_file_size: Option<u64>) -> Self {
+        self.min_file_size = min_file_size;
+        self.max_file_size = max_file_size;
+        self
+    }
+
+    pub fn with_min_age(mut self, min_file_age_secs: Option<u64>) -> Self {
+        self.min_file_age_secs = min_file_age_secs;
+        self
+    }
+
+    pub fn with_os_files_excluded(mut self, exclude_os_files: bool) -> Self {
+        self.exclude_os_files = exclude_os_files;
+        self
+    }
+
+    pub fn with_conflicted_copies_excluded(mut self, exclude_conflicted_copies: bool) -> Self {
+        self.exclude_conflicted_copies = exclude_conflicted_copies;
+        self
+    }
+
+    pub fn with_symlinks(mut self, symlinks: SymlinkMode) -> Self {
+        self.symlinks = symlinks;
+        self
     }
 
     pub fn is_file_included(&self, entry: &DirEntry) -> bool {
-        if let Some(pattern) = &self.include_only {
-            pattern.matches_path(&entry.path())
-        } else {
-            true
+        let file_type = entry.file_type().ok();
+        if self.symlinks == SymlinkMode::Skip && file_type.is_some_and(|t| t.is_symlink()) {
+            return false;
+        }
+        let is_dir = file_type.is_some_and(|t| t.is_dir());
+        if !is_dir && (self.min_file_size.is_some() || self.max_file_size.is_some() || self.min_file_age_secs.is_some()) {
+            if let Ok(metadata) = entry.metadata() {
+                if !self.is_size_included(metadata.len()) {
+                    return false;
+                }
+                if let Ok(modified) = metadata.modified() {
+                    if !self.is_age_included(modified, SystemTime::now()) {
+                        return false;
+                    }
+                }
+            }
         }
+        self.is_path_included(&entry.path(), is_dir)
+    }
+
+    pub fn is_size_included(&self, size: u64) -> bool {
+        self.min_file_size.is_none_or(|min| size >= min) && self.max_file_size.is_none_or(|max| size <= max)
+    }
+
+    pub fn is_age_included(&self, modified: SystemTime, now: SystemTime) -> bool {
+        // A file from the future was, as far as we can tell, just modified.
+        let age = now.duration_since(modified).unwrap_or_default();
+        self.min_file_age_secs.is_none_or(|min_age| age >= Duration::from_secs(min_age))
     }
 
     pub fn is_file_excluded(&self, entry: &DirEntry) -> bool {
         !self.is_file_included(entry)
     }
-}
 
-impl Default for FileFilter {
-    fn default() -> Self {
-        Self { include_only: None }
+    pub fn is_path_included(&self, path: &Path, is_dir: bool) -> bool {
+        if let Some(filename) = path.file_name() {
+            let filename = filename.to_string_lossy();
+            if filename.starts_with(RESERVED_PREFIX) || (self.exclude_os_files && is_os_file(&filename)) {
+                return false;
+            }
+            if self.exclude_conflicted_copies && is_dropbox_conflicted_copy(&filename) {
+                return false;
+            }
+            let dirname = format!("{}/", filename);
+            for pattern in self.exclude.iter() {
+                if pattern.matches(&filename) || (is_dir && pattern.matches(&dirname)) {
+                    return false;
+                }
+            }
+        }
+        if !is_dir && !self.include_extensions.is_empty() {
+            let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+            if !extension.is_some_and(|e| self.include_extensions.contains(&e)) {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.include_only {
+            pattern.matches_path(path)
+        } else {
+            true
+        }
     }
 }
+
+#[test]
+fn test_is_size_included() {
+    let filter = FileFilter::default();
+    assert!(filter.is_size_included(0));
+    assert!(filter.is_size_included(u64::MAX));
+
+    let filter = FileFilter::default().with_size_limits(Some(10), Some(100));
+    assert!(!filter.is_size_included(9));
+    assert!(filter.is_size_included(10));
+    assert!(filter.is_size_included(100));
+    assert!(!filter.is_size_included(101));
+}
+
+#[test]
+fn test_is_age_included() {
+    let now = SystemTime::now();
+    let filter = FileFilter::default();
+    assert!(filter.is_age_included(now, now));
+
+    let filter = FileFilter::default().with_min_age(Some(10));
+    assert!(!filter.is_age_included(now, now));
+    assert!(!filter.is_age_included(now - Duration::from_secs(9), now));
+    assert!(filter.is_age_included(now - Duration::from_secs(10), now));
+    assert!(!filter.is_age_included(now + Duration::from_secs(60), now));
+}
+
+#[test]
+fn test_is_path_included() {
+    let patterns = |strs: &[&str]| strs.iter().map(|s| Pattern::new(s).unwrap()).collect::<Vec<Pattern>>();
+
+    let filter = FileFilter::default();
+    assert!(!filter.is_path_included(Path::new("foo/Thumbs.db"), false));
+    assert!(!filter.is_path_included(Path::new("foo/.DS_Store"), false));
+    assert!(!filter.is_path_included(Path::new("foo/._save.sav"), false));
+    assert!(!filter.is_path_included(Path::new("foo/$RECYCLE.BIN"), true));
+    assert!(!filter.is_path_included(Path::new("foo/.dropsync-snapshot-boop.json"), false));
+    assert!(filter.is_path_included(Path::new("foo/save.sav"), false));
+
+    let filter = FileFilter::default().with_os_files_excluded(false);
+    assert!(filter.is_path_included(Path::new("foo/Thumbs.db"), false));
+    assert!(!filter.is_path_included(Path::new("foo/.dropsync-snapshot-boop.json"), false));
+
+    let filter = FileFilter::default();
+    assert!(!filter.is_path_included(Path::new("foo/save (conflicted copy 2023-01-01).sav"), false));
+    assert!(!filter.is_path_included(Path::new("foo/save (Laptop's Conflicted Copy 2023-01-01 (1)).sav"), false));
+    assert!(!filter.is_path_included(Path::new("foo/saves (Laptop's conflicted copy 2023-01-01)"), true));
+    assert!(filter.is_path_included(Path::new("foo/conflicted copy 2.sav"), false));
+    assert!(filter.is_path_included(Path::new("foo/save (conflicted copy).sav"), false));
+
+    let filter = FileFilter::default().with_conflicted_copies_excluded(false);
+    assert!(filter.is_path_included(Path::new("foo/save (conflicted copy 2023-01-01).sav"), false));
+
+    let filter = FileFilter::new(None, patterns(&["Thumbs.db", "*.tmp", "cache/"]));
+    assert!(!filter.is_path_included(Path::new("foo/Thumbs.db"), false));
+    assert!(!filter.is_path_included(Path::new("foo/blah.tmp"), false));
+    assert!(!filter.is_path_included(Path::new("foo/cache"), true));
+    assert!(filter.is_path_included(Path::new("foo/cache"), false));
+    assert!(filter.is_path_included(Path::new("foo/save.sav"), false));
+
+    let filter = FileFilter::new(Some(Pattern::new("*.sav").unwrap()), patterns(&["temp*"]));
+    assert!(filter.is_path_included(Path::new("foo/save.sav"), false));
+    assert!(!filter.is_path_included(Path::new("foo/temp.sav"), false));
+    assert!(!filter.is_path_included(Path::new("foo/log.txt"), false));
+
+    let filter = FileFilter::new(None, patterns(&["temp*"])).with_extensions(vec![String::from("sav"), String::from(".CFG")]);
+    assert!(filter.is_path_included(Path::new("foo/save.SAV"), false));
+    assert!(filter.is_path_included(Path::new("foo/game.cfg"), false));
+    assert!(filter.is_path_included(Path::new("foo/saves"), true));
+    assert!(!filter.is_path_included(Path::new("foo/temp.sav"), false));
+    assert!(!filter.is_path_included(Path::new("foo/log.txt"), false));
+    assert!(!filter.is_path_included(Path::new("foo/sav"), false));
+
+    let filter = FileFilter::new(Some(Pattern::new("*slot*").unwrap()), vec![]).with_extensions(vec![String::from("sav")]);
+    assert!(filter.is_path_included(Path::new("foo/slot1.sav"), false));
+    assert!(!filter.is_path_included(Path::new("foo/slot1.txt"), false));
+    assert!(!filter.is_path_included(Path::new("foo/other.sav"), false));
+}
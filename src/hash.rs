@@ -0,0 +1,34 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+pub use blake3::Hash;
+
+pub fn hash_file(path: &Path) -> io::Result<Hash> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
+
+// Combines the hashes of a directory's entries into a single hash. The
+// entries must already be sorted by name.
+pub fn hash_dir_entries<'a, I: Iterator<Item = (&'a str, bool, Hash)>>(entries: I) -> Hash {
+    let mut hasher = blake3::Hasher::new();
+    for (name, is_dir, hash) in entries {
+        hasher.update(if is_dir { b"d" } else { b"f" });
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(hash.as_bytes());
+    }
+    hasher.finalize()
+}
+
+#[test]
+fn test_hash_dir_entries() {
+    let a = blake3::hash(b"a");
+    let b = blake3::hash(b"b");
+    assert_eq!(hash_dir_entries(vec![("x", false, a)].into_iter()), hash_dir_entries(vec![("x", false, a)].into_iter()));
+    assert_ne!(hash_dir_entries(vec![("x", false, a)].into_iter()), hash_dir_entries(vec![("x", false, b)].into_iter()));
+    assert_ne!(hash_dir_entries(vec![("x", false, a)].into_iter()), hash_dir_entries(vec![("x", true, a)].into_iter()));
+    assert_ne!(hash_dir_entries(vec![("x", false, a)].into_iter()), hash_dir_entries(vec![("y", false, a)].into_iter()));
+}
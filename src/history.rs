@@ -0,0 +1,52 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use serde::Serialize;
+
+use crate::file_filter::RESERVED_PREFIX;
+use crate::report::AppReport;
+
+// An append-only record of every sync this computer has done, one JSON
+// object per line.
+pub fn get_default_history_path(dropbox_dir: &Path, hostname: &str) -> PathBuf {
+    dropbox_dir.join(format!("{}history-{}.jsonl", RESERVED_PREFIX, hostname))
+}
+
+#[derive(Serialize)]
+struct HistoryEntry<'a> {
+    timestamp: u64,
+    #[serde(flatten)]
+    report: &'a AppReport,
+}
+
+pub fn append(path: &Path, report: &AppReport) -> io::Result<()> {
+    let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+    let mut line = serde_json::to_string(&HistoryEntry { timestamp, report }).unwrap();
+    line.push('\n');
+    fs::OpenOptions::new().create(true).append(true).open(path)?.write_all(line.as_bytes())
+}
+
+#[test]
+fn test_append() {
+    use crate::report::CopyReport;
+    use crate::SyncResult;
+
+    let path = PathBuf::from(".test_history.jsonl");
+    if path.exists() {
+        fs::remove_file(&path).unwrap();
+    }
+    let report = |app: &str, result| AppReport { app: String::from(app), result, copy: CopyReport { files_copied: 3, ..Default::default() } };
+    append(&path, &report("boop", SyncResult::AppNewerThanDropbox)).unwrap();
+    append(&path, &report("blap", SyncResult::AlreadySynced)).unwrap();
+
+    let lines = fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect::<Vec<serde_json::Value>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["app"], "boop");
+    assert_eq!(lines[0]["result"], "app_newer_than_dropbox");
+    assert_eq!(lines[0]["files_copied"], 3);
+    assert!(lines[0]["timestamp"].as_u64().unwrap() > 0);
+    assert_eq!(lines[1]["app"], "blap");
+
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,77 @@
+use std::process::Command;
+
+use crate::config::AppConfig;
+use crate::output::{complain, say};
+use crate::report::CopyReport;
+use crate::SyncResult;
+
+// The shell that runs hook commands, and the flag that tells it to run
+// the rest of its arguments as a command.
+fn get_shell() -> (&'static str, &'static str) {
+    if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    }
+}
+
+// The name a result goes by in JSON, e.g. `app_newer_than_dropbox`.
+fn result_name(result: SyncResult) -> String {
+    serde_json::to_value(result).ok().and_then(|value| value.as_str().map(String::from)).unwrap_or_default()
+}
+
+// Runs the app's `after_sync` command, if it has one and the sync
+// actually changed something. A hook that fails is worth mentioning,
+// but the sync itself still succeeded.
+pub fn run_after_sync(app: &AppConfig, result: SyncResult, copy: &CopyReport) {
+    let command = match &app.after_sync {
+        Some(command) if copy.did_anything() && copy.errors.is_empty() => command,
+        _ => return,
+    };
+    say!("  Running {}.", command);
+    let (shell, flag) = get_shell();
+    let status = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("DROPSYNC_APP", &app.name)
+        .env("DROPSYNC_RESULT", result_name(result))
+        .env("DROPSYNC_FILES_COPIED", copy.files_copied.to_string())
+        .env("DROPSYNC_FILES_DELETED", copy.files_deleted.to_string())
+        .status();
+    match status {
+        Ok(status) if status.success() => {},
+        Ok(status) => complain!("  Warning: after_sync command failed ({}).", status),
+        Err(e) => complain!("  Warning: unable to run after_sync command: {}", e),
+    }
+}
+
+#[test]
+fn test_result_name() {
+    assert_eq!(result_name(SyncResult::AppNewerThanDropbox), "app_newer_than_dropbox");
+    assert_eq!(result_name(SyncResult::Merged), "merged");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_after_sync() {
+    use std::fs;
+    use std::path::PathBuf;
+
+    let tmp_dir = PathBuf::from(".test_run_after_sync");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    fs::create_dir_all(&tmp_dir).unwrap();
+    let marker = tmp_dir.join("marker");
+    let app = AppConfig {
+        name: String::from("boop"),
+        after_sync: Some(format!("echo \"$DROPSYNC_APP $DROPSYNC_RESULT $DROPSYNC_FILES_COPIED\" > {}", marker.to_string_lossy())),
+        ..Default::default()
+    };
+    run_after_sync(&app, SyncResult::AlreadySynced, &CopyReport::default());
+    assert!(!marker.exists());
+    run_after_sync(&app, SyncResult::DropboxNewerThanApp, &CopyReport { files_copied: 2, ..Default::default() });
+    assert_eq!(fs::read_to_string(&marker).unwrap(), "boop dropbox_newer_than_app 2\n");
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
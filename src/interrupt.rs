@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set once Ctrl-C is pressed while we're in the middle of changing files,
+// so we can stop at the next safe point instead of halfway through.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Whether we're in the middle of changing files. The rest of the time
+// (e.g. while waiting at a prompt), Ctrl-C just quits like it always has.
+static CHANGING_FILES: AtomicBool = AtomicBool::new(false);
+
+pub fn is_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+// Returns whether the interrupt was handled, rather than needing to quit
+// right away.
+fn on_interrupt() -> bool {
+    // Pressing Ctrl-C a second time means the user really wants out.
+    CHANGING_FILES.load(Ordering::SeqCst) && !INTERRUPTED.swap(true, Ordering::SeqCst)
+}
+
+// While this is alive, Ctrl-C sets a flag instead of quitting.
+pub struct ChangingFiles;
+
+impl ChangingFiles {
+    pub fn start() -> Self {
+        CHANGING_FILES.store(true, Ordering::SeqCst);
+        ChangingFiles
+    }
+}
+
+impl Drop for ChangingFiles {
+    fn drop(&mut self) {
+        CHANGING_FILES.store(false, Ordering::SeqCst);
+    }
+}
+
+#[cfg(unix)]
+pub fn install_handler() {
+    extern "C" fn handler(_: libc::c_int) {
+        if !on_interrupt() {
+            // The usual exit status for being killed by SIGINT.
+            unsafe { libc::_exit(130) };
+        }
+    }
+    unsafe {
+        libc::signal(libc::SIGINT, handler as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+}
+
+#[cfg(windows)]
+pub fn install_handler() {
+    use winapi::shared::minwindef::{BOOL, DWORD, FALSE, TRUE};
+    use winapi::um::wincon::CTRL_C_EVENT;
+
+    unsafe extern "system" fn handler(ctrl_type: DWORD) -> BOOL {
+        // Returning false lets the default handler quit.
+        if ctrl_type == CTRL_C_EVENT && on_interrupt() { TRUE } else { FALSE }
+    }
+    unsafe {
+        winapi::um::consoleapi::SetConsoleCtrlHandler(Some(handler), TRUE);
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn install_handler() {}
@@ -0,0 +1,723 @@
+// The sync engine behind the `dropsync` command, for embedding it in
+// other programs. Load apps with `config::load_config_file`, then sync
+// each of them with `sync_app_and_report`.
+
+use std::path::{Path, PathBuf};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::{Deserialize, Serialize};
+
+pub mod dir_state;
+pub mod dropbox;
+pub mod encrypt;
+pub mod util;
+pub mod ask;
+pub mod config;
+pub mod archive;
+pub mod explorer;
+pub mod file_filter;
+pub mod events;
+pub mod plan;
+pub mod hash;
+pub mod history;
+pub mod hook;
+pub mod interrupt;
+pub mod lock;
+pub mod notify;
+pub mod progress;
+pub mod remote;
+pub mod output;
+pub mod report;
+pub mod rollback;
+pub mod snapshot;
+
+use dir_state::DirState;
+use events::{Event, EventSink};
+use report::{AppReport, CopyReport};
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncResult {
+    AlreadySynced,
+    AppNewerThanDropbox,
+    DropboxNewerThanApp,
+    BothEmpty,
+    Conflict,
+    ReadError,
+    Skipped,
+    Merged,
+}
+
+// Which way to sync every app regardless of which side seems newer, e.g.
+// pulling everything from Dropbox after reinstalling a computer.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Direction {
+    Push,
+    Pull,
+}
+
+impl Direction {
+    // How an app that compared the given way should be synced instead.
+    // There's nothing to force for apps that are already synced or empty,
+    // and it isn't safe to for apps we couldn't read.
+    pub fn apply(self, result: SyncResult) -> SyncResult {
+        match result {
+            SyncResult::AppNewerThanDropbox | SyncResult::DropboxNewerThanApp | SyncResult::Conflict => match self {
+                Direction::Push => SyncResult::AppNewerThanDropbox,
+                Direction::Pull => SyncResult::DropboxNewerThanApp,
+            },
+            _ => result,
+        }
+    }
+
+    pub fn flag(self) -> &'static str {
+        match self {
+            Direction::Push => "--push",
+            Direction::Pull => "--pull",
+        }
+    }
+
+    pub fn describe(self) -> &'static str {
+        match self {
+            Direction::Push => "copying the app's state into Dropbox",
+            Direction::Pull => "copying Dropbox's state into the app",
+        }
+    }
+}
+
+// The way an app that compared the given way should be synced, taking
+// `--push` or `--pull` into account, along with whether that changed
+// anything.
+pub fn apply_direction(result: SyncResult, options: &SyncOptions) -> (SyncResult, bool) {
+    let forced = options.direction.map_or(result, |direction| direction.apply(result));
+    if let (Some(direction), true) = (options.direction, forced != result) {
+        say!("  Since {} was given, {} regardless of which is newer.", direction.flag(), direction.describe());
+    }
+    (forced, forced != result)
+}
+
+#[derive(PartialEq, Copy, Clone)]
+enum ConflictChoice {
+    UseApp,
+    UseDropbox,
+    Explore,
+    Skip,
+    PerFile,
+    Newest,
+}
+
+static CONFLICT_CHOICES: [ask::Choice<ConflictChoice>; 6] = [
+    ask::Choice { name: "use app", value: ConflictChoice::UseApp },
+    ask::Choice { name: "use dropbox", value: ConflictChoice::UseDropbox },
+    ask::Choice { name: "explore", value: ConflictChoice::Explore },
+    ask::Choice { name: "skip", value: ConflictChoice::Skip },
+    ask::Choice { name: "per file", value: ConflictChoice::PerFile },
+    ask::Choice { name: "newest", value: ConflictChoice::Newest },
+];
+
+// Whose version of a single conflicting file to keep.
+static FILE_CONFLICT_CHOICES: [ask::Choice<bool>; 2] = [
+    ask::Choice { name: "app", value: true },
+    ask::Choice { name: "dropbox", value: false },
+];
+
+pub struct SyncOptions {
+    pub hostname: String,
+    pub events: EventSink,
+    pub self_check: bool,
+    // Whether to re-read every copied file to make sure it arrived intact.
+    pub verify: bool,
+    pub dry_run: bool,
+    pub max_file_size_warn: Option<u64>,
+    pub use_trash: bool,
+    pub non_interactive: bool,
+    // Whether the user already said yes to syncing everything, so we
+    // shouldn't ask again before copying (conflicts still need resolving).
+    pub already_confirmed: bool,
+    // Where to log what happened to each app, if anywhere.
+    pub history_file: Option<PathBuf>,
+    pub notify: bool,
+    // Which way to sync every app, if we were told to.
+    pub direction: Option<Direction>,
+    // The passphrase for encrypted apps, if it was given up front.
+    pub passphrase: Option<String>,
+}
+
+impl SyncOptions {
+    // Syncing for real as the given computer, asking whenever there's a
+    // question, and without anything extra like logging or notifications.
+    pub fn new(hostname: &str) -> Self {
+        SyncOptions {
+            hostname: String::from(hostname),
+            events: EventSink::none(),
+            self_check: false,
+            verify: false,
+            dry_run: false,
+            max_file_size_warn: None,
+            use_trash: false,
+            non_interactive: false,
+            already_confirmed: false,
+            history_file: None,
+            notify: false,
+            direction: None,
+            passphrase: None,
+        }
+    }
+}
+
+// What comparing an app found, e.g. for `dropsync status`.
+pub fn describe_comparison(comparison: &plan::AppComparison) -> String {
+    let mut description = String::from(describe_result(comparison.result));
+    if !comparison.diff.to_copy.is_empty() || !comparison.diff.to_delete.is_empty() {
+        description.push_str(&format!(" ({} file(s) to copy, {} to delete)", comparison.diff.to_copy.len(), comparison.diff.to_delete.len()));
+    }
+    description
+}
+
+pub fn describe_result(result: SyncResult) -> &'static str {
+    match result {
+        SyncResult::AlreadySynced => "in sync",
+        SyncResult::AppNewerThanDropbox => "app is newer than Dropbox",
+        SyncResult::DropboxNewerThanApp => "Dropbox is newer than app",
+        SyncResult::BothEmpty => "both sides are empty",
+        SyncResult::Conflict => "in conflict",
+        SyncResult::ReadError => "unable to read the app or Dropbox state",
+        SyncResult::Skipped => "skipped",
+        SyncResult::Merged => "merged file by file",
+    }
+}
+
+pub fn sync_app_and_report(app: &config::AppConfig, confirm_if_app_is_newer: bool, options: &SyncOptions) -> AppReport {
+    sync_app_and_report_reusing(app, confirm_if_app_is_newer, options, None).0
+}
+
+// Like `sync_app_and_report`, but reuses the given scan of the app's
+// Dropbox folder instead of scanning it again, and also returns what the
+// folder looks like afterwards, if we know.
+pub fn sync_app_and_report_reusing(app: &config::AppConfig, confirm_if_app_is_newer: bool, options: &SyncOptions, dropbox_dir_state: Option<DirState>) -> (AppReport, Option<DirState>) {
+    options.events.emit(&Event::AppStarted { app: &app.name });
+    let (result, copy, dropbox_dir_state) = sync_app(app, confirm_if_app_is_newer, options, dropbox_dir_state);
+    options.events.emit(&Event::AppFinished { app: &app.name, result });
+    if copy.did_anything() {
+        say!("  Done: {}.", report::describe_copy_counts(copy.files_copied, copy.files_deleted));
+    }
+    hook::run_after_sync(app, result, &copy);
+    let report = AppReport { app: app.name.clone(), result, copy };
+    if options.notify && (result == SyncResult::Conflict || result == SyncResult::Skipped) {
+        notify::notify("dropsync", &format!("{} needs its conflict resolved manually.", app.name));
+    }
+    if let Some(path) = &options.history_file {
+        if let Err(e) = history::append(path, &report) {
+            complain!("  Warning: unable to write to {}: {}", path.to_string_lossy(), e);
+        }
+    }
+    (report, dropbox_dir_state)
+}
+
+pub fn sync_app(app: &config::AppConfig, confirm_if_app_is_newer: bool, options: &SyncOptions, dropbox_dir_state: Option<DirState>) -> (SyncResult, CopyReport, Option<DirState>) {
+    if let Some(remote) = &app.remote {
+        let (result, copy) = remote::sync_app_with_remote(app, remote, confirm_if_app_is_newer, options);
+        return (result, copy, None);
+    }
+    if app.archive {
+        let (result, copy) = archive::sync_app_with_archive(app, confirm_if_app_is_newer, options);
+        return (result, copy, None);
+    }
+    if app.encrypt {
+        let (result, copy) = encrypt::sync_app_with_encryption(app, confirm_if_app_is_newer, options);
+        return (result, copy, None);
+    }
+    let comparison = plan::compare_app_with_dropbox_state(app, &options.hostname, dropbox_dir_state);
+    let dir_state = comparison.app_state;
+    let dropbox_dir_state = comparison.dropbox_state;
+    for error in dir_state.read_errors().into_iter().chain(dropbox_dir_state.read_errors()) {
+        complain!("  Warning: {}", error);
+    }
+    if let Some(max_size) = options.max_file_size_warn {
+        warn_about_large_files(&dir_state, max_size);
+    }
+    let may_delete_from_dropbox = app.may_delete_from_dropbox(&options.hostname);
+    let mut copy = CopyReport::default();
+    let (result, forced) = apply_direction(comparison.result, options);
+    let result = match result {
+        SyncResult::AlreadySynced => {
+            say!("  App state matches Dropbox. Nothing to do!");
+            copy.completed = true;
+            SyncResult::AlreadySynced
+        },
+        SyncResult::AppNewerThanDropbox => {
+            if !forced {
+                say!("  App state is newer than Dropbox.");
+            }
+            copy = copy_files_with_maybe_confirmation(&dir_state, &dropbox_dir_state, confirm_if_app_is_newer, may_delete_from_dropbox, app, options);
+            SyncResult::AppNewerThanDropbox
+        },
+        SyncResult::DropboxNewerThanApp => {
+            if !forced {
+                say!("  Dropbox state is newer than app.");
+            }
+            copy = copy_files_with_maybe_confirmation(&dropbox_dir_state, &dir_state, true, app.may_delete_from_app(), app, options);
+            SyncResult::DropboxNewerThanApp
+        },
+        SyncResult::BothEmpty => {
+            say!("  Both Dropbox and app state are empty. Nothing to do!");
+            SyncResult::BothEmpty
+        },
+        SyncResult::ReadError => {
+            complain!("  Unable to read the app or Dropbox state, so not doing anything.");
+            SyncResult::ReadError
+        },
+        SyncResult::Skipped | SyncResult::Merged => unreachable!("classification never skips or merges an app"),
+        SyncResult::Conflict => {
+            complain!("  App and Dropbox state are in conflict; manual resolution required.");
+            options.events.emit(&Event::Conflict { app: &app.name });
+            if options.dry_run {
+                for choice in CONFLICT_CHOICES.iter() {
+                    if let Some((to_dir, diff)) = conflict_resolution_diff(choice.value, &dir_state, &dropbox_dir_state, may_delete_from_dropbox, app.may_delete_from_app()) {
+                        say!("  If you choose \"{}\":", choice.name);
+                        print_dry_run(&diff, to_dir);
+                    }
+                }
+            }
+            if options.non_interactive {
+                complain!("  Skipping it, since we're running non-interactively.");
+                return (SyncResult::Skipped, copy, None);
+            }
+            let choice = ask::ask_with_choices("  ", "How do you want to proceed? ", &CONFLICT_CHOICES);
+            match choice {
+                ConflictChoice::UseApp => {
+                    copy = copy_files_with_maybe_confirmation(&dir_state, &dropbox_dir_state, false, may_delete_from_dropbox, app, options);
+                    SyncResult::AppNewerThanDropbox
+                }
+                ConflictChoice::UseDropbox => {
+                    copy = copy_files_with_maybe_confirmation(&dropbox_dir_state, &dir_state, false, app.may_delete_from_app(), app, options);
+                    SyncResult::DropboxNewerThanApp
+                }
+                ConflictChoice::Explore => {
+                    reveal_first_difference(&dir_state, &dropbox_dir_state);
+                    reveal_first_difference(&dropbox_dir_state, &dir_state);
+                    // Anything could have changed while we were looking, so
+                    // compare both sides again before asking what to do.
+                    rprompt::prompt_reply_stdout("  Press enter once you're done looking. ").unwrap();
+                    return sync_app(app, confirm_if_app_is_newer, options, None);
+                }
+                ConflictChoice::Skip => {
+                    say!("  Okay, leaving both sides alone for now.");
+                    SyncResult::Skipped
+                }
+                ConflictChoice::PerFile => {
+                    let last_sync = snapshot::load(app, &options.hostname);
+                    let plan = dir_state.merge_plan(&dropbox_dir_state, last_sync.as_ref());
+                    copy = merge_files(&dir_state, &dropbox_dir_state, plan, may_delete_from_dropbox, app, options);
+                    SyncResult::Merged
+                }
+                ConflictChoice::Newest => {
+                    let plan = dir_state.newest_wins_plan(&dropbox_dir_state);
+                    copy = merge_files(&dir_state, &dropbox_dir_state, plan, may_delete_from_dropbox, app, options);
+                    SyncResult::Merged
+                }
+            }
+        },
+    };
+    if copy.completed {
+        let synced_dropbox_dir_state = app.scan_dropbox_path();
+        // Whatever we copied looks just like where it came from, so we
+        // should already know the hashes of most things.
+        if app.compare.uses_hashes() {
+            synced_dropbox_dir_state.adopt_hashes_from(&dropbox_dir_state);
+            synced_dropbox_dir_state.adopt_hashes_from(&dir_state);
+            synced_dropbox_dir_state.compute_hashes();
+        }
+        snapshot::save(app, &options.hostname, &synced_dropbox_dir_state);
+        return (result, copy, Some(synced_dropbox_dir_state));
+    }
+    (result, copy, None)
+}
+
+fn warn_about_large_files(dir_state: &DirState, max_size: u64) {
+    for (path, size) in dir_state.files_larger_than(max_size) {
+        complain!("  Warning: {} is {} bytes, which is bigger than {} bytes.", path.to_string_lossy(), size, max_size);
+    }
+}
+
+pub fn open_in_explorer(path: &Path) {
+    if let Err(e) = explorer::open_in_explorer(path) {
+        complain!("Oops, I couldn't open it: {}.", e);
+        complain!("Please open {} yourself. Sorry!", path.to_string_lossy());
+    }
+}
+
+// Shows the user the first file that's different on this side, or just
+// opens the folder if there isn't one.
+fn reveal_first_difference(dir_state: &DirState, other: &DirState) {
+    let path = match dir_state.files_differing_from(other).first() {
+        Some((rel_path, _)) => dir_state.path().join(rel_path),
+        None => return open_in_explorer(dir_state.path()),
+    };
+    if let Err(e) = explorer::reveal_in_explorer(&path) {
+        complain!("Oops, I couldn't open it: {}.", e);
+        complain!("Please look at {} yourself. Sorry!", path.to_string_lossy());
+    }
+}
+
+fn diff_for_sync(from_dir: &DirState, to_dir: &DirState, should_delete: bool) -> dir_state::CopyDiff {
+    let mut diff = from_dir.diff_copy_into(to_dir);
+    // We only actually copy the files that differ.
+    diff.to_copy = from_dir.files_differing_from(to_dir).into_iter().map(|(path, _)| path).collect();
+    if !should_delete {
+        diff.to_delete.clear();
+    }
+    diff
+}
+
+// The destination and files that resolving a conflict with the given
+// choice would copy and delete, if it would change anything at all.
+fn conflict_resolution_diff<'a>(choice: ConflictChoice, dir_state: &'a DirState, dropbox_dir_state: &'a DirState, may_delete_from_dropbox: bool, may_delete_from_app: bool) -> Option<(&'a DirState, dir_state::CopyDiff)> {
+    match choice {
+        ConflictChoice::UseApp => Some((dropbox_dir_state, diff_for_sync(dir_state, dropbox_dir_state, may_delete_from_dropbox))),
+        ConflictChoice::UseDropbox => Some((dir_state, diff_for_sync(dropbox_dir_state, dir_state, may_delete_from_app))),
+        ConflictChoice::Explore | ConflictChoice::Skip | ConflictChoice::PerFile | ConflictChoice::Newest => None,
+    }
+}
+
+fn print_dry_run(diff: &dir_state::CopyDiff, to_dir: &DirState) {
+    say!("  Dry run: would copy {} file(s) into {}.", diff.to_copy.len(), to_dir.path().to_string_lossy());
+    for path in diff.to_copy.iter() {
+        say!("    {}", path.to_string_lossy());
+    }
+    say!("  Dry run: would delete {} file(s) from {}.", diff.to_delete.len(), to_dir.path().to_string_lossy());
+    for path in diff.to_delete.iter() {
+        say!("    {}", path.to_string_lossy());
+    }
+}
+
+fn ensure_enough_space(dir: &Path, bytes_needed: u64) -> Result<(), String> {
+    match util::get_available_space(dir) {
+        Some(available) if available < bytes_needed => Err(format!(
+            "{} bytes need to be copied into {}, but only {} bytes are free there.", bytes_needed, dir.to_string_lossy(), available
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn print_file_details(verb: &str, paths: &[PathBuf]) {
+    for path in paths {
+        detail!("    {} {}", verb, path.to_string_lossy());
+    }
+}
+
+fn print_file_errors(verb: &str, errors: &[dir_state::FileError]) {
+    complain!("  Unable to {} {} file(s):", verb, errors.len());
+    for error in errors {
+        complain!("    {}", error);
+    }
+}
+
+fn add_file_errors(report: &mut CopyReport, verb: &str, errors: &[dir_state::FileError]) {
+    print_file_errors(verb, errors);
+    report.errors.extend(errors.iter().map(|error| format!("Unable to {} {}", verb, error)));
+}
+
+// Copying files whose names only differ by case would quietly lose one of
+// them on the way, so it's better to not copy anything.
+fn check_case_collisions(from_dir: &DirState, report: &mut CopyReport) -> bool {
+    let collisions = from_dir.case_collisions();
+    for (a, b) in collisions.iter() {
+        let error = format!("{} and {} only differ by case, so a case-insensitive filesystem or Dropbox would lose one of them.", a.to_string_lossy(), b.to_string_lossy());
+        complain!("  {}", error);
+        report.errors.push(error);
+    }
+    if !collisions.is_empty() {
+        complain!("  Not synchronizing; please rename one of each pair.");
+    }
+    collisions.is_empty()
+}
+
+fn copy_files_with_maybe_confirmation(from_dir: &DirState, to_dir_state: &DirState, should_ask: bool, should_delete: bool, app: &config::AppConfig, options: &SyncOptions) -> CopyReport {
+    let mut report = CopyReport::default();
+    if !check_case_collisions(from_dir, &mut report) {
+        return report;
+    }
+    let diff = diff_for_sync(from_dir, to_dir_state, should_delete);
+    if options.dry_run {
+        print_dry_run(&diff, to_dir_state);
+        return report;
+    }
+    let to_dir = to_dir_state.path();
+    let yes = if should_ask && !options.non_interactive && !options.already_confirmed {
+        ask::ask_yes_or_no("  Proceed with synchronization (y/n) ? ")
+    } else {
+        say!("  Synchronizing files.");
+        true
+    };
+    if yes {
+        let total_files = diff.to_copy.len();
+        let total_bytes: u64 = from_dir.files_differing_from(to_dir_state).iter().map(|(_, size)| size).sum();
+        // Better to not start at all than to fill up the disk halfway through.
+        if let Err(e) = ensure_enough_space(to_dir, total_bytes) {
+            complain!("  {} Not synchronizing.", e);
+            report.errors.push(e);
+            return report;
+        }
+        let _changing_files = interrupt::ChangingFiles::start();
+        if let Some(backup_path) = app.new_backup_path() {
+            let to_back_up = [diff.to_copy.clone(), diff.to_delete.clone()].concat();
+            let side = if to_dir == app.path { rollback::Side::App } else { rollback::Side::Dropbox };
+            let change = rollback::Change::new(side, "", &diff.to_copy, &diff.to_delete);
+            if let Err(errors) = to_dir_state.back_up_into(&to_back_up, &backup_path).and_then(|_| rollback::record(&backup_path, &[change])) {
+                add_file_errors(&mut report, "back up", &errors);
+                complain!("  Not synchronizing, since some files couldn't be backed up.");
+                return report;
+            }
+        }
+        say!("  {} of {} file(s) ({} of {} bytes) need to be copied.", total_files, from_dir.total_file_count(), total_bytes, from_dir.total_size());
+        let files_done = AtomicU64::new(0);
+        let result = from_dir.copy_changed_into_with_progress(to_dir_state, &|files, bytes| {
+            files_done.fetch_max(files, Ordering::SeqCst);
+            let percent = (bytes * 100).checked_div(total_bytes).unwrap_or(100);
+            if output::is_enabled(output::Level::Normal) {
+                print!("\r  Copied {}/{} file(s) ({}%).", files, total_files, percent);
+                let _ = io::stdout().flush();
+            }
+        });
+        if total_files > 0 {
+            say!();
+        }
+        if let Err(errors) = result {
+            report.files_copied = diff.to_copy.len() - errors.len();
+            add_file_errors(&mut report, "copy", &errors);
+            complain!("  Not deleting extraneous files, since some files couldn't be copied.");
+            return report;
+        }
+        if interrupt::is_interrupted() {
+            report.files_copied = files_done.load(Ordering::SeqCst) as usize;
+            complain!("  Interrupted after copying {} of {} file(s), so not deleting anything.", report.files_copied, total_files);
+            return report;
+        }
+        if options.verify || app.verify {
+            if let Err(errors) = from_dir.verify_copies_in(&diff.to_copy, to_dir) {
+                add_file_errors(&mut report, "verify", &errors);
+                complain!("  Not deleting extraneous files, since some copies don't match their originals.");
+                return report;
+            }
+            say!("  Verified {} copied file(s).", diff.to_copy.len());
+        }
+        report.files_copied = diff.to_copy.len();
+        print_file_details("Copied", &diff.to_copy);
+        if !app.compare.mirror {
+            say!("  Not deleting extraneous files, since the app isn't mirrored.");
+        } else if !should_delete {
+            say!("  Not deleting extraneous files, since this computer isn't the delete authority.");
+        } else {
+            let result = if options.use_trash || app.use_trash {
+                from_dir.trash_extraneous_files_from(to_dir)
+            } else {
+                from_dir.remove_extraneous_files_from(to_dir)
+            };
+            if let Err(errors) = result {
+                report.files_deleted = diff.to_delete.len().saturating_sub(errors.len());
+                add_file_errors(&mut report, "delete", &errors);
+                return report;
+            }
+            report.files_deleted = diff.to_delete.len();
+            print_file_details("Deleted", &diff.to_delete);
+        }
+        if options.self_check {
+            if from_dir.is_synced_with(to_dir, !should_delete) {
+                say!("  Self-check passed.");
+            } else {
+                complain!("  WARNING: Self-check failed! {} and {} still differ after syncing.",
+                    from_dir.path().to_string_lossy(), to_dir.to_string_lossy());
+            }
+        }
+        report.completed = true;
+    } else {
+        say!("  Okay, not doing anything.");
+    }
+    report
+}
+
+// Resolves a conflict one file at a time: changes that were only made on
+// one side are merged automatically, and we ask about the files that were
+// changed on both.
+fn merge_files(dir_state: &DirState, dropbox_dir_state: &DirState, mut plan: dir_state::MergePlan, may_delete_from_dropbox: bool, app: &config::AppConfig, options: &SyncOptions) -> CopyReport {
+    let mut report = CopyReport::default();
+    if !check_case_collisions(dir_state, &mut report) || !check_case_collisions(dropbox_dir_state, &mut report) {
+        return report;
+    }
+    say!("  {} file(s) need you to pick which version to keep.", plan.conflicts.len());
+    for path in std::mem::take(&mut plan.conflicts) {
+        let prompt = format!("Which version of {} do you want to keep? ", path.to_string_lossy());
+        // Keeping a side's version of a file it doesn't have means deleting it.
+        let (keep, other_side) = if ask::ask_with_choices("  ", &prompt, &FILE_CONFLICT_CHOICES) {
+            (dir_state, (&mut plan.to_other, &mut plan.delete_from_other))
+        } else {
+            (dropbox_dir_state, (&mut plan.from_other, &mut plan.delete_from_self))
+        };
+        if keep.has_file(&path) {
+            other_side.0.push(path);
+        } else {
+            other_side.1.push(path);
+        }
+    }
+    if !may_delete_from_dropbox {
+        plan.delete_from_other.clear();
+    }
+    if !app.may_delete_from_app() {
+        plan.delete_from_self.clear();
+    }
+    if options.dry_run {
+        print_dry_run(&dir_state::CopyDiff { to_copy: plan.to_other, to_delete: plan.delete_from_other }, dropbox_dir_state);
+        print_dry_run(&dir_state::CopyDiff { to_copy: plan.from_other, to_delete: plan.delete_from_self }, dir_state);
+        return report;
+    }
+    let _changing_files = interrupt::ChangingFiles::start();
+    if let Some(backup_path) = app.new_backup_path() {
+        let errors = vec![
+            dir_state.back_up_into(&[plan.from_other.clone(), plan.delete_from_self.clone()].concat(), &backup_path.join("app")),
+            dropbox_dir_state.back_up_into(&[plan.to_other.clone(), plan.delete_from_other.clone()].concat(), &backup_path.join("dropbox")),
+            rollback::record(&backup_path, &[
+                rollback::Change::new(rollback::Side::App, "app", &plan.from_other, &plan.delete_from_self),
+                rollback::Change::new(rollback::Side::Dropbox, "dropbox", &plan.to_other, &plan.delete_from_other),
+            ]),
+        ].into_iter().filter_map(Result::err).flatten().collect::<Vec<_>>();
+        if !errors.is_empty() {
+            add_file_errors(&mut report, "back up", &errors);
+            complain!("  Not merging, since some files couldn't be backed up.");
+            return report;
+        }
+    }
+    say!("  Copying {} file(s) into Dropbox and {} into the app.", plan.to_other.len(), plan.from_other.len());
+    let errors = vec![
+        dir_state.copy_files_into(&plan.to_other, dropbox_dir_state.path()),
+        dropbox_dir_state.copy_files_into(&plan.from_other, dir_state.path()),
+    ].into_iter().filter_map(Result::err).flatten().collect::<Vec<_>>();
+    if !errors.is_empty() {
+        report.files_copied = (plan.to_other.len() + plan.from_other.len()).saturating_sub(errors.len());
+        add_file_errors(&mut report, "copy", &errors);
+        complain!("  Not deleting anything, since some files couldn't be copied.");
+        return report;
+    }
+    if interrupt::is_interrupted() {
+        complain!("  Interrupted while copying, so not deleting anything.");
+        return report;
+    }
+    if options.verify || app.verify {
+        let errors = vec![
+            dir_state.verify_copies_in(&plan.to_other, dropbox_dir_state.path()),
+            dropbox_dir_state.verify_copies_in(&plan.from_other, dir_state.path()),
+        ].into_iter().filter_map(Result::err).flatten().collect::<Vec<_>>();
+        if !errors.is_empty() {
+            add_file_errors(&mut report, "verify", &errors);
+            complain!("  Not deleting anything, since some copies don't match their originals.");
+            return report;
+        }
+    }
+    report.files_copied = plan.to_other.len() + plan.from_other.len();
+    print_file_details("Copied into Dropbox:", &plan.to_other);
+    print_file_details("Copied into the app:", &plan.from_other);
+    let use_trash = options.use_trash || app.use_trash;
+    let errors = vec![
+        dir_state::remove_files_from(dropbox_dir_state.path(), &plan.delete_from_other, use_trash),
+        dir_state::remove_files_from(dir_state.path(), &plan.delete_from_self, use_trash),
+    ].into_iter().filter_map(Result::err).flatten().collect::<Vec<_>>();
+    if !errors.is_empty() {
+        report.files_deleted = (plan.delete_from_other.len() + plan.delete_from_self.len()).saturating_sub(errors.len());
+        add_file_errors(&mut report, "delete", &errors);
+        return report;
+    }
+    report.files_deleted = plan.delete_from_other.len() + plan.delete_from_self.len();
+    print_file_details("Deleted from Dropbox:", &plan.delete_from_other);
+    print_file_details("Deleted from the app:", &plan.delete_from_self);
+    report.completed = true;
+    report
+}
+
+#[test]
+fn test_classify_empty() {
+    use std::fs;
+
+    let file_filter = file_filter::FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_classify_empty");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    fs::create_dir_all(tmp_dir.join("app")).unwrap();
+    fs::create_dir_all(tmp_dir.join("dropbox")).unwrap();
+
+    let app_state = DirState::from_dir(&tmp_dir.join("app"), &file_filter);
+    let dropbox_state = DirState::from_dir(&tmp_dir.join("dropbox"), &file_filter);
+    let unreadable_state = DirState::from_dir(&tmp_dir.join("nonexistent"), &file_filter);
+    assert_eq!(app_state.classify_against(&dropbox_state), SyncResult::BothEmpty);
+    assert_eq!(app_state.classify_against(&unreadable_state), SyncResult::ReadError);
+    assert_eq!(unreadable_state.classify_against(&dropbox_state), SyncResult::ReadError);
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_conflict_resolution_diff() {
+    use std::fs;
+
+    let file_filter = file_filter::FileFilter::default();
+
+    let tmp_dir = PathBuf::from(".test_conflict_resolution_diff");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app_dir = tmp_dir.join("app");
+    let dropbox_dir = tmp_dir.join("dropbox");
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &file_filter);
+    src_state.copy_into(&app_dir).unwrap();
+    src_state.copy_into(&dropbox_dir).unwrap();
+
+    // Each side has one file that's newer than the other side's, and one
+    // file that the other side doesn't have.
+    let newer = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    fs::File::options().write(true).open(app_dir.join("boop")).unwrap().set_modified(newer).unwrap();
+    fs::File::options().write(true).open(dropbox_dir.join("subdir").join("shoop")).unwrap().set_modified(newer).unwrap();
+    fs::write(app_dir.join("app_only"), "hi").unwrap();
+    fs::write(dropbox_dir.join("dropbox_only"), "hi").unwrap();
+
+    let app_state = DirState::from_dir(&app_dir, &file_filter);
+    let dropbox_state = DirState::from_dir(&dropbox_dir, &file_filter);
+    assert_eq!(app_state.classify_against(&dropbox_state), SyncResult::Conflict);
+
+    let shoop = PathBuf::from("subdir").join("shoop");
+    let (to_dir, diff) = conflict_resolution_diff(ConflictChoice::UseApp, &app_state, &dropbox_state, true, true).unwrap();
+    assert_eq!(to_dir.path(), dropbox_dir);
+    assert_eq!(diff.to_copy, vec![PathBuf::from("app_only"), PathBuf::from("boop"), shoop.clone()]);
+    assert_eq!(diff.to_delete, vec![PathBuf::from("dropbox_only")]);
+
+    let (_, diff) = conflict_resolution_diff(ConflictChoice::UseApp, &app_state, &dropbox_state, false, true).unwrap();
+    assert!(diff.to_delete.is_empty());
+
+    let (to_dir, diff) = conflict_resolution_diff(ConflictChoice::UseDropbox, &app_state, &dropbox_state, false, true).unwrap();
+    assert_eq!(to_dir.path(), app_dir);
+    assert_eq!(diff.to_copy, vec![PathBuf::from("boop"), PathBuf::from("dropbox_only"), shoop.clone()]);
+    assert_eq!(diff.to_delete, vec![PathBuf::from("app_only")]);
+
+    // With `mirror = false`, nothing is deleted from the app either.
+    let (_, diff) = conflict_resolution_diff(ConflictChoice::UseDropbox, &app_state, &dropbox_state, false, false).unwrap();
+    assert_eq!(diff.to_copy, vec![PathBuf::from("boop"), PathBuf::from("dropbox_only"), shoop]);
+    assert!(diff.to_delete.is_empty());
+
+    assert!(conflict_resolution_diff(ConflictChoice::Explore, &app_state, &dropbox_state, true, true).is_none());
+    assert!(conflict_resolution_diff(ConflictChoice::Skip, &app_state, &dropbox_state, true, true).is_none());
+
+    // Figuring out the resolutions shouldn't have touched anything.
+    assert_eq!(DirState::from_dir(&app_dir, &file_filter), app_state);
+    assert_eq!(DirState::from_dir(&dropbox_dir, &file_filter), dropbox_state);
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
+
+#[test]
+fn test_direction_apply() {
+    assert_eq!(Direction::Push.apply(SyncResult::Conflict), SyncResult::AppNewerThanDropbox);
+    assert_eq!(Direction::Push.apply(SyncResult::DropboxNewerThanApp), SyncResult::AppNewerThanDropbox);
+    assert_eq!(Direction::Pull.apply(SyncResult::AppNewerThanDropbox), SyncResult::DropboxNewerThanApp);
+    assert_eq!(Direction::Pull.apply(SyncResult::AlreadySynced), SyncResult::AlreadySynced);
+    assert_eq!(Direction::Pull.apply(SyncResult::ReadError), SyncResult::ReadError);
+}
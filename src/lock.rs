@@ -0,0 +1,83 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use sysinfo::{System, SystemExt};
+
+use crate::file_filter::RESERVED_PREFIX;
+
+// Keeps two runs on the same computer from syncing into the same Dropbox
+// folder at once.
+pub fn get_lock_path(dropbox_dir: &Path, hostname: &str) -> PathBuf {
+    dropbox_dir.join(format!("{}lock-{}", RESERVED_PREFIX, hostname))
+}
+
+// The lock we're holding, so it can be released even if we exit without
+// unwinding.
+static HELD: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub struct RunLock;
+
+impl RunLock {
+    // The lock file contains the ID of the process holding it, so a lock
+    // left behind by a run that was killed can be taken over.
+    pub fn acquire(path: &Path) -> Result<RunLock, String> {
+        loop {
+            match fs::OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    file.write_all(std::process::id().to_string().as_bytes())
+                        .map_err(|e| format!("Unable to write {}: {}", path.to_string_lossy(), e))?;
+                    *HELD.lock().unwrap() = Some(path.to_path_buf());
+                    return Ok(RunLock);
+                },
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let pid = fs::read_to_string(path).unwrap_or_default();
+                    if is_running(pid.trim()) {
+                        return Err(format!("Another dropsync (process {}) is already running. If it isn't, delete {}.", pid.trim(), path.to_string_lossy()));
+                    }
+                    fs::remove_file(path).map_err(|e| format!("Unable to remove stale lock {}: {}", path.to_string_lossy(), e))?;
+                },
+                Err(e) => return Err(format!("Unable to create {}: {}", path.to_string_lossy(), e)),
+            }
+        }
+    }
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        release();
+    }
+}
+
+pub fn release() {
+    if let Some(path) = HELD.lock().unwrap().take() {
+        let _ = fs::remove_file(path);
+    }
+}
+
+fn is_running(pid: &str) -> bool {
+    match pid.parse() {
+        Ok(pid) => System::new().refresh_process(pid),
+        // If we can't tell who holds it, play it safe.
+        Err(_) => true,
+    }
+}
+
+#[test]
+fn test_run_lock() {
+    let path = PathBuf::from(".test_run_lock");
+    if path.exists() {
+        fs::remove_file(&path).unwrap();
+    }
+    let lock = RunLock::acquire(&path).unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), std::process::id().to_string());
+    assert!(RunLock::acquire(&path).is_err());
+    drop(lock);
+    assert!(!path.exists());
+
+    // A lock held by a process that no longer exists is stale.
+    fs::write(&path, "999999999").unwrap();
+    let lock = RunLock::acquire(&path).unwrap();
+    drop(lock);
+    assert!(!path.exists());
+}
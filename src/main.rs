@@ -1,153 +1,341 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::fs;
-use file_filter::FileFilter;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use serde::Deserialize;
-use sysinfo::{System, SystemExt, ProcessExt};
+use sysinfo::{ProcessExt, ProcessStatus, System, SystemExt};
 
-mod dir_state;
-mod dropbox;
-mod util;
-mod ask;
-mod config;
-mod explorer;
-mod file_filter;
+mod doctor;
+mod watch;
 
-use dir_state::DirState;
-use util::get_primary_hostname;
+use dropsync::dir_state;
+use dropsync::output::{self, complain, say};
+use dropsync::report::{self, AppReport, CopyReport};
+use dropsync::util::{self, get_primary_hostname};
+use dropsync::{archive, config, dropbox, encrypt, history, interrupt, lock, notify, plan, progress, rollback};
+use dropsync::{ask, describe_comparison, open_in_explorer, sync_app_and_report, sync_app_and_report_reusing, Direction, SyncOptions, SyncResult};
+use dropsync::events::EventSink;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-const USAGE: &'static str = "
+const USAGE: &str = "
 Synchronize app files with Dropbox.
 
 Usage:
-  dropsync
-  dropsync config
-  dropsync explore <app>
-  dropsync play <app>
+  dropsync [options]
+  dropsync config [options]
+  dropsync sync <app> [options]
+  dropsync sync --group=<name> [options]
+  dropsync status [options]
+  dropsync list [options]
+  dropsync validate [options]
+  dropsync doctor [options]
+  dropsync explore <app> [options]
+  dropsync play <app> [options]
+  dropsync hash <app> [options]
+  dropsync rollback <app> [options]
+  dropsync watch [options]
   dropsync --version
   dropsync (-h | --help)
 
 Options:
-  -h --help     Show this screen.
-  --version     Show version.
+  -h --help               Show this screen.
+  --version               Show version.
+  --dropbox-health-check  Make sure Dropbox is running before syncing.
+  --profile=<name>        Use dropsync.<name>.toml instead of dropsync.toml.
+  --config=<path>         Use this config file instead of the one in Dropbox.
+  --event-socket=<path>   Send sync events as JSON lines to a socket.
+  --self-check            Rescan both sides after syncing to make sure they match.
+  --verify                Re-read every copied file to make sure it arrived intact.
+  --dry-run               Print what would be copied and deleted, without doing it.
+  --dump-plan-and-exit=<file>  Write what would be synced to a file, then exit.
+  --apply-plan=<file>     Sync exactly what a previously dumped plan says.
+  --resume                Skip apps that an interrupted previous run finished.
+  --max-file-size-warn=<bytes>  Warn about app files bigger than this size.
+  --jobs=<n>              Copy up to this many files at once.
+  --copy-retries=<n>      Retry copying a locked file up to this many times.
+  --copy-retry-delay=<ms>  Wait this long before first retrying a copy.
+  --trash                 Send deleted files to the trash instead of deleting them.
+  --push                  Always copy each app into Dropbox, whichever is newer.
+  --pull                  Always copy Dropbox into each app, whichever is newer.
+  --non-interactive       Never ask anything; skip conflicts and exit with an error.
+  --json                  Print a JSON summary of what happened instead of messages.
+  --history-file=<path>   Append a log of what was synced to this file.
+  --only=<apps>           Only sync these apps (separated by commas).
+  --group=<name>          Only sync apps in this group.
+  -v --verbose            Also print every file that's copied or deleted.
+  -q --quiet              Only print warnings and errors.
+  --notify                Show a desktop notification about conflicts and when done.
+  --poll-interval=<secs>  When watching, check folders that can't be watched this often.
+  --settle-time=<secs>    When watching, wait until nothing has changed for this long.
+  --with-status           When listing apps, also check whether each needs syncing.
 ";
 
-const WATCH_DIR_MAX_SECONDS: u64 = 3;
-
 #[derive(Debug, Deserialize)]
 struct Args {
     cmd_explore: bool,
     cmd_config: bool,
     cmd_play: bool,
+    cmd_hash: bool,
+    cmd_rollback: bool,
+    cmd_sync: bool,
+    cmd_status: bool,
+    cmd_list: bool,
+    cmd_validate: bool,
+    cmd_doctor: bool,
+    cmd_watch: bool,
     arg_app: Option<String>,
+    flag_dropbox_health_check: bool,
+    flag_with_status: bool,
+    flag_profile: Option<String>,
+    flag_config: Option<PathBuf>,
+    flag_event_socket: Option<PathBuf>,
+    flag_self_check: bool,
+    flag_verify: bool,
+    flag_dry_run: bool,
+    flag_dump_plan_and_exit: Option<PathBuf>,
+    flag_apply_plan: Option<PathBuf>,
+    flag_resume: bool,
+    flag_max_file_size_warn: Option<u64>,
+    flag_jobs: Option<usize>,
+    flag_copy_retries: Option<u32>,
+    flag_copy_retry_delay: Option<u64>,
+    flag_trash: bool,
+    flag_push: bool,
+    flag_pull: bool,
+    flag_non_interactive: bool,
+    flag_json: bool,
+    flag_history_file: Option<PathBuf>,
+    flag_only: Option<String>,
+    flag_group: Option<String>,
+    flag_notify: bool,
+    flag_poll_interval: Option<u64>,
+    flag_settle_time: Option<u64>,
+    flag_verbose: bool,
+    flag_quiet: bool,
 }
 
-#[derive(PartialEq)]
-enum SyncResult {
-    AlreadySynced,
-    AppNewerThanDropbox,
-    DropboxNewerThanApp,
-    BothEmpty,
-    Conflict,
-}
-
-#[derive(PartialEq, Copy, Clone)]
-enum ConflictChoice {
-    UseApp,
-    UseDropbox,
-    Explore
-}
-
-static CONFLICT_CHOICES: [ask::Choice<ConflictChoice>; 3] = [
-    ask::Choice { name: "use app", value: ConflictChoice::UseApp },
-    ask::Choice { name: "use dropbox", value: ConflictChoice::UseDropbox },
-    ask::Choice { name: "explore", value: ConflictChoice::Explore },
-];
-
-fn sync_app(app: &config::AppConfig, confirm_if_app_is_newer: bool) -> SyncResult {
-    let file_filter = FileFilter::new(app.include_only.clone());
-    let dir_state = DirState::from_dir(&app.path, &file_filter);
-    let dropbox_dir_state = DirState::from_dir(&app.dropbox_path, &file_filter);
-    if dir_state.are_contents_equal_to(&dropbox_dir_state) {
-        println!("  App state matches Dropbox. Nothing to do!");
-        SyncResult::AlreadySynced
-    } else {
-        if dir_state.are_contents_generally_newer_than(&dropbox_dir_state) {
-            println!("  App state is newer than Dropbox.");
-            copy_files_with_maybe_confirmation(&dir_state, &app.dropbox_path, confirm_if_app_is_newer);
-            SyncResult::AppNewerThanDropbox
-        } else if dropbox_dir_state.are_contents_generally_newer_than(&dir_state) {
-            println!("  Dropbox state is newer than app.");
-            copy_files_with_maybe_confirmation(&dropbox_dir_state, &app.path, true);
-            SyncResult::DropboxNewerThanApp
-        } else if dir_state.is_empty() && dropbox_dir_state.is_empty() {
-            println!("  Both Dropbox and app state are empty. Nothing to do!");
-            SyncResult::BothEmpty
-        } else {
-            println!("  App and Dropbox state are in conflict; manual resolution required.");
-            let choice = ask::ask_with_choices("  ", "How do you want to proceed? ", &CONFLICT_CHOICES);
-            match choice {
-                ConflictChoice::UseApp => {
-                    copy_files_with_maybe_confirmation(&dir_state, &app.dropbox_path, false);
-                    SyncResult::AppNewerThanDropbox
-                }
-                ConflictChoice::UseDropbox => {
-                    copy_files_with_maybe_confirmation(&dropbox_dir_state, &app.path, false);
-                    SyncResult::DropboxNewerThanApp
-                }
-                ConflictChoice::Explore => {
-                    explorer::open_in_explorer(&app.path);
-                    explorer::open_in_explorer(&app.dropbox_path);
-                    SyncResult::Conflict
-                }
-            }
-        }
+// Whether a process seems to belong to the app in the given directory. We
+// can't always tell where a process's executable is (e.g. on macOS, for
+// processes we don't own), so if we can't, we go by its command line and
+// working directory instead.
+fn is_process_in_dir(exe: &Path, cmd: &[String], cwd: &Path, dir: &Path) -> bool {
+    if !exe.as_os_str().is_empty() {
+        return exe.starts_with(dir);
     }
+    let cmd_in_dir = cmd.first().is_some_and(|program| Path::new(program).starts_with(dir));
+    cmd_in_dir || (!cwd.as_os_str().is_empty() && cwd.starts_with(dir))
 }
 
-fn copy_files_with_maybe_confirmation(from_dir: &DirState, to_dir: &PathBuf, should_ask: bool) {
-    let yes = if should_ask {
-        ask::ask_yes_or_no("  Proceed with synchronization (y/n) ? ")
-    } else {
-        println!("  Synchronizing files.");
-        true
-    };
-    if yes {
-        from_dir.copy_into(to_dir);
-        from_dir.remove_extraneous_files_from(to_dir);
-    } else {
-        println!("  Okay, not doing anything.");
-    }
+#[test]
+fn test_is_process_in_dir() {
+    let dir = Path::new("/games/boop");
+    let cmd = |args: &[&str]| args.iter().map(|arg| String::from(*arg)).collect::<Vec<String>>();
+    let empty = Path::new("");
+    assert!(is_process_in_dir(Path::new("/games/boop/bin/boop"), &[], empty, dir));
+    assert!(!is_process_in_dir(Path::new("/usr/bin/vim"), &cmd(&["vim"]), dir, dir));
+    assert!(is_process_in_dir(empty, &cmd(&["/games/boop/boop", "--fullscreen"]), empty, dir));
+    assert!(is_process_in_dir(empty, &cmd(&["boop"]), Path::new("/games/boop/data"), dir));
+    assert!(!is_process_in_dir(empty, &cmd(&["boop"]), Path::new("/home/atul"), dir));
+    assert!(!is_process_in_dir(empty, &[], empty, dir));
 }
 
-fn play(executable: &PathBuf, watch_dir: &Option<PathBuf>) {
+fn play(executable: &PathBuf, app: &config::AppConfig) {
     let executable_dir = executable.parent()
         .expect("executable should have a parent directory")
         .to_path_buf();
     let mut child = Command::new(executable)
+        .args(&app.play_args)
         .current_dir(&executable_dir)
         .stdin(Stdio::inherit())
         .spawn()
         .expect("process failed to execute");
     child.wait().expect("failed to wait on child");
 
-    let final_watch_dir = watch_dir.as_ref().unwrap_or(&executable_dir);
+    let final_watch_dir = app.play_watch_dir.as_ref().unwrap_or(&executable_dir);
+    // Process paths are usually fully resolved, e.g. `/private/var` rather
+    // than `/var` on macOS.
+    let canonical_watch_dir = fs::canonicalize(final_watch_dir).unwrap_or_else(|_| final_watch_dir.clone());
+    let our_pid = sysinfo::get_current_pid().ok();
     let mut sys = System::new();
     let mut seconds_without_exe = 0;
-    println!("Waiting for no processes to be running in app directory for {} seconds.", WATCH_DIR_MAX_SECONDS);
-    while seconds_without_exe < WATCH_DIR_MAX_SECONDS {
+    let mut seconds_waited = 0;
+    say!("Waiting for no processes to be running in app directory for {} seconds.", app.play_watch_seconds);
+    while seconds_without_exe < app.play_watch_seconds {
+        if app.play_max_wait_seconds.is_some_and(|max| seconds_waited >= max) {
+            complain!("Gave up waiting after {} seconds; something is still running in {}.", seconds_waited, final_watch_dir.to_string_lossy());
+            return;
+        }
         std::thread::sleep(std::time::Duration::from_secs(1));
         seconds_without_exe += 1;
+        seconds_waited += 1;
         sys.refresh_processes();
-        for (_, process) in sys.processes() {
-            if process.exe().starts_with(final_watch_dir) {
+        for (pid, process) in sys.processes() {
+            // A process that's exited but hasn't been cleaned up isn't running anything.
+            if matches!(process.status(), ProcessStatus::Zombie) {
+                continue;
+            }
+            if Some(*pid) != our_pid && is_process_in_dir(process.exe(), process.cmd(), process.cwd(), &canonical_watch_dir) {
                 seconds_without_exe = 0;
             }
         }
     }
-    println!("Looks like the app is finished.");
+    say!("Looks like the app is finished.");
+}
+
+// Syncs each of the given apps once, and then again whenever either side
+// of it changes and then settles down, until we're interrupted. Apps whose
+// folders can't be watched for changes are checked every `poll_interval`
+// instead.
+fn watch_apps(configs: &[&config::AppConfig], options: &SyncOptions, poll_interval: Duration, settle_time: Duration) -> ! {
+    let scan = |config: &config::AppConfig| {
+        // There's no cheap way to notice changes on a remote host.
+        (config.scan_path(), config.remote.as_ref().map_or_else(|| Some(config.scan_dropbox_path()), |_| None))
+    };
+    let sync = |config: &config::AppConfig| {
+        if let Err(e) = config.validate() {
+            complain!("  {} Skipping this app.", e);
+        } else {
+            sync_app_and_report(config, true, options);
+        }
+        if interrupt::is_interrupted() {
+            exit(130);
+        }
+    };
+    let (sender, events) = mpsc::channel();
+    let mut debouncer = notify_debouncer_mini::new_debouncer(watch::DEBOUNCE_TIME, sender).unwrap_or_else(|e| {
+        complain!("Unable to watch for changes: {}", e);
+        exit(1);
+    });
+    let mut folders = watch::WatchedFolders::default();
+    let mut polled = vec![];
+    let mut tracker = watch::ChangeTracker::new(settle_time);
+    for config in configs {
+        say!("Syncing app {}.", config.name);
+        sync(config);
+        tracker.reset(&config.name, scan(config));
+        if let Err(e) = folders.watch(debouncer.watcher(), config) {
+            complain!("  Warning: unable to watch for changes ({}), so checking every {} seconds instead.", e, poll_interval.as_secs());
+            polled.push(config.name.clone());
+        }
+    }
+    say!("Watching {} app(s) for changes. Press Ctrl-C to stop.", configs.len());
+    let mut next_poll = Instant::now() + poll_interval;
+    loop {
+        // Events only tell us where to look; it's the tracker that decides
+        // whether anything we care about actually changed, and whether
+        // it's settled down yet.
+        let mut to_check = HashSet::new();
+        let wake_at = tracker.next_settle_time().map_or(next_poll, |settle_time| settle_time.min(next_poll));
+        match events.recv_timeout(wake_at.saturating_duration_since(Instant::now())) {
+            Ok(Ok(events)) => {
+                for event in events {
+                    to_check.extend(folders.apps_affected_by(&event.path).map(String::from));
+                }
+            },
+            Ok(Err(e)) => complain!("Warning: error while watching for changes: {}", e),
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                complain!("Stopped receiving changes, so exiting.");
+                exit(1);
+            },
+        }
+        let now = Instant::now();
+        if now >= next_poll {
+            to_check.extend(polled.iter().cloned());
+            next_poll = now + poll_interval;
+        }
+        to_check.extend(tracker.settled(now));
+        for config in configs.iter().filter(|config| to_check.contains(&config.name)) {
+            if tracker.update(&config.name, scan(config), now) {
+                say!("Syncing app {}, since it changed.", config.name);
+                sync(config);
+                // Don't count what syncing did as another change.
+                tracker.reset(&config.name, scan(config));
+            }
+        }
+    }
+}
+
+// Whether the app needs syncing, as a sentence, for `dropsync status`.
+fn describe_status(config: &config::AppConfig, hostname: &str) -> String {
+    if let Some(remote) = &config.remote {
+        return format!("syncs with {}, so not checking it.", remote.host);
+    }
+    if let Err(e) = config.validate() {
+        return e;
+    }
+    let comparison = if config.archive {
+        archive::compare_app_with_archive(config, hostname)
+    } else if config.encrypt {
+        encrypt::compare_app_with_encrypted(config, hostname)
+    } else {
+        plan::compare_app(config, hostname)
+    };
+    format!("{}.", describe_comparison(&comparison))
+}
+
+// Shows what syncing all the given apps would do, and asks whether to go
+// ahead with all of it at once, rather than app by app. Apps in conflict
+// still need to be resolved once we get to them.
+fn confirm_all(configs: &[&config::AppConfig], hostname: &str, direction: Option<Direction>) -> bool {
+    // Asking about a summary that wasn't printed would be asking blind.
+    if !output::is_enabled(output::Level::Normal) {
+        return false;
+    }
+    let mut comparisons = vec![];
+    for config in configs {
+        // Remote apps ask for themselves, since we'd have to connect to see
+        // what they need, and apps that can't be synced will say so later.
+        if config.remote.is_some() || !config.problems().is_empty() {
+            continue;
+        }
+        let comparison = if config.archive {
+            archive::compare_app_with_archive(config, hostname)
+        } else if config.encrypt {
+            encrypt::compare_app_with_encrypted(config, hostname)
+        } else {
+            plan::compare_app(config, hostname)
+        };
+        let result = direction.map_or(comparison.result, |direction| direction.apply(comparison.result));
+        comparisons.push((config, comparison, result));
+    }
+    let needs_copying = |result: SyncResult| result == SyncResult::AppNewerThanDropbox || result == SyncResult::DropboxNewerThanApp;
+    if !comparisons.iter().any(|(_, _, result)| needs_copying(*result)) {
+        return false;
+    }
+    say!("Here's what needs to be done:");
+    for (config, comparison, result) in comparisons.iter() {
+        match direction {
+            Some(direction) if *result != comparison.result => {
+                say!("  {}: {}, but {} anyway.", config.name, describe_comparison(comparison), direction.describe());
+            },
+            _ => say!("  {}: {}.", config.name, describe_comparison(comparison)),
+        }
+    }
+    if ask::ask_yes_or_no("Proceed with all of it (y/n) ? ") {
+        return true;
+    }
+    say!("Okay, not doing anything.");
+    exit(0);
+}
+
+// For commands that are all about a single app, there's no point in going
+// on if it's misconfigured.
+fn validate_or_exit(config: &config::AppConfig) {
+    if let Err(e) = config.validate() {
+        println!("{}", e);
+        exit(1);
+    }
+}
+
+// Like `std::process::exit`, but releases our lock first, since exiting
+// this way doesn't run destructors.
+fn exit(code: i32) -> ! {
+    lock::release();
+    std::process::exit(code)
 }
 
 fn get_hostname() -> String {
@@ -162,56 +350,388 @@ fn main() {
         .and_then(|d| d.version(Some(version)).deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    interrupt::install_handler();
+
+    output::set_level(if args.flag_json {
+        output::Level::Silent
+    } else if args.flag_quiet {
+        output::Level::Quiet
+    } else if args.flag_verbose {
+        output::Level::Verbose
+    } else {
+        output::Level::Normal
+    });
+
+    dir_state::set_copy_retries(
+        args.flag_copy_retries.unwrap_or(dir_state::DEFAULT_COPY_RETRIES),
+        args.flag_copy_retry_delay.unwrap_or(dir_state::DEFAULT_COPY_RETRY_DELAY_MS),
+    );
+    if let Some(jobs) = args.flag_jobs {
+        rayon::ThreadPoolBuilder::new().num_threads(jobs).build_global().unwrap();
+    }
+
     let hostname = get_hostname();
 
-    let dropbox_dir = dropbox::get_dropbox_dir();
-    let cfg_file = dropbox_dir.join("dropsync.toml");
-    util::ensure_path_exists(&cfg_file);
+    if args.flag_config.is_some() && args.flag_profile.is_some() {
+        println!("--config and --profile can't be used together.");
+        exit(1);
+    }
+    if args.flag_push && args.flag_pull {
+        println!("--push and --pull can't be used together.");
+        exit(1);
+    }
+
+    if args.cmd_doctor {
+        exit(if doctor::run(&hostname, args.flag_profile.as_deref(), args.flag_config.as_deref()) { 0 } else { 1 });
+    }
+
+    let dropbox_dir = dropbox::get_dropbox_dir().unwrap_or_else(|e| {
+        println!("Unable to find the Dropbox folder. {}", e);
+        exit(1);
+    });
+    let cfg_file = args.flag_config.clone().unwrap_or_else(|| config::get_config_path(&dropbox_dir, args.flag_profile.as_deref()));
+    if let Err(e) = util::ensure_path_exists(&cfg_file) {
+        println!("Unable to find the config file. {}", e);
+        exit(1);
+    }
 
     if args.cmd_config {
-        explorer::open_in_explorer(&cfg_file);
+        open_in_explorer(&cfg_file);
         return;
     }
 
-    println!("Loading config for {} from {}.", hostname, cfg_file.to_string_lossy());
+    if args.flag_dropbox_health_check {
+        match dropbox::get_health() {
+            dropbox::Health::Healthy => {
+                say!("Dropbox appears to be running.");
+            },
+            dropbox::Health::Idle(seconds) => {
+                complain!("Warning: Dropbox is running, but hasn't done anything in {} minutes. Is it paused?", seconds / 60);
+            },
+            dropbox::Health::Unknown => {
+                complain!("Warning: Dropbox is running, but I can't tell when it last did anything.");
+            },
+            dropbox::Health::NotRunning => {
+                complain!("Dropbox doesn't appear to be running! Please start it and try again.");
+                exit(1);
+            },
+        }
+    }
 
-    let toml_str = fs::read_to_string(cfg_file).unwrap();
-    let app_configs = config::load_config(&hostname, &toml_str, &dropbox_dir);
+    say!("Loading config for {} from {}.", hostname, cfg_file.to_string_lossy());
 
-    if args.cmd_explore || args.cmd_play {
-        let app_name = args.arg_app.unwrap();
-        if let Some(config) = util::get_case_insensitive(&app_configs, &app_name) {
+    let options = SyncOptions {
+        hostname: hostname.clone(),
+        events: match &args.flag_event_socket {
+            Some(path) => EventSink::connect(path),
+            None => EventSink::none(),
+        },
+        self_check: args.flag_self_check,
+        verify: args.flag_verify,
+        dry_run: args.flag_dry_run,
+        max_file_size_warn: args.flag_max_file_size_warn,
+        use_trash: args.flag_trash,
+        // Nobody's around to answer questions while we're watching, and
+        // prompts would end up in the middle of JSON output.
+        non_interactive: args.flag_non_interactive || args.flag_json || args.cmd_watch,
+        already_confirmed: false,
+        // Nothing actually happens during a dry run, so there's nothing to log.
+        history_file: if args.flag_dry_run {
+            None
+        } else {
+            Some(args.flag_history_file.clone().unwrap_or_else(|| history::get_default_history_path(&dropbox_dir, &hostname)))
+        },
+        notify: args.flag_notify,
+        direction: if args.flag_push {
+            Some(Direction::Push)
+        } else if args.flag_pull {
+            Some(Direction::Pull)
+        } else {
+            None
+        },
+        passphrase: std::env::var(encrypt::PASSPHRASE_ENV_VAR).ok(),
+    };
+
+    let app_configs = config::load_config_file(&hostname, &cfg_file, &dropbox_dir).unwrap_or_else(|e| {
+        println!("Error in {}: {}", cfg_file.to_string_lossy(), e);
+        exit(1);
+    });
+
+    let only_reads = args.flag_dry_run || args.cmd_explore || args.cmd_hash || args.cmd_status || args.cmd_list || args.cmd_validate || args.flag_dump_plan_and_exit.is_some();
+    let _lock = if only_reads {
+        None
+    } else {
+        Some(lock::RunLock::acquire(&lock::get_lock_path(&dropbox_dir, &hostname)).unwrap_or_else(|e| {
+            println!("{}", e);
+            exit(1);
+        }))
+    };
+
+    // Every command that's about a single app takes its name.
+    if let Some(app_name) = &args.arg_app {
+        if let Some(config) = util::get_case_insensitive(&app_configs, app_name) {
             if args.cmd_explore {
-                explorer::open_in_explorer(&config.path);
-                explorer::open_in_explorer(&config.dropbox_path);
+                open_in_explorer(&config.path);
+                open_in_explorer(&config.dropbox_path);
+            } else if args.cmd_sync {
+                say!("Syncing app {}.", config.name);
+                validate_or_exit(config);
+                let run_report = report::RunReport { apps: vec![sync_app_and_report(config, true, &options)] };
+                if args.flag_json {
+                    println!("{}", run_report.to_json());
+                }
+                if options.notify {
+                    notify::notify("dropsync", &run_report.summary());
+                }
+                if interrupt::is_interrupted() {
+                    exit(130);
+                }
+                exit(run_report.exit_code());
+            } else if args.cmd_rollback {
+                say!("Rolling back app {}.", config.name);
+                validate_or_exit(config);
+                if let Err(e) = rollback::rollback_app(config, &options) {
+                    complain!("  {}", e);
+                    exit(1);
+                }
+            } else if args.cmd_hash {
+                validate_or_exit(config);
+                let hash_or_exit = |side: &str, state: dir_state::DirState| {
+                    state.tree_hash().unwrap_or_else(|e| {
+                        complain!("Unable to hash the {} side: {}", side, e);
+                        exit(1);
+                    })
+                };
+                let app_hash = hash_or_exit("app", config.scan_path());
+                let dropbox_hash = hash_or_exit("Dropbox", config.scan_dropbox_path());
+                println!("App:     {}", app_hash.to_hex());
+                println!("Dropbox: {}", dropbox_hash.to_hex());
+                if app_hash == dropbox_hash {
+                    println!("The contents of both sides are identical.");
+                } else {
+                    println!("The contents of the two sides differ.");
+                }
             } else {
-                assert_eq!(args.cmd_play, true);
+                assert!(args.cmd_play);
                 if let Some(play_path) = &config.play_path {
-                    config.validate();
-                    if sync_app(config, true) == SyncResult::Conflict {
+                    validate_or_exit(config);
+                    let (report, mut dropbox_dir_state) = sync_app_and_report_reusing(config, true, &options, None);
+                    if report.result == SyncResult::Conflict && !options.non_interactive {
                         rprompt::prompt_reply_stdout("Press enter once you've resolved the conflict.").unwrap();
+                        // Who knows what was changed to resolve it.
+                        dropbox_dir_state = None;
+                    }
+                    play(play_path, config);
+                    // Only the app's folder should have changed while it
+                    // was being played, so there's no need to scan the
+                    // Dropbox one again. Don't ask anything if the app is
+                    // newer, since we fully expect that to be the case.
+                    let (report, _) = sync_app_and_report_reusing(config, false, &options, dropbox_dir_state);
+                    if options.notify {
+                        notify::notify("dropsync", &report::RunReport { apps: vec![report] }.summary());
                     }
-                    play(play_path, &config.play_watch_dir);
-                    // Don't ask anything if the app is newer, since we fully expect that to be the case.
-                    sync_app(config, false);
                 } else {
                     println!("No play_path is defined for {}!", app_name);
-                    std::process::exit(1);
+                    exit(1);
                 }
             }
         } else {
             let app_names = app_configs.keys().map(|s| format!("'{}'", s)).collect::<Vec<String>>();
-            println!("App '{}' not found! Please choose from {}.", &app_name, app_names.join(", "));
-            std::process::exit(1);
+            println!("App '{}' not found! Please choose from {}.", app_name, app_names.join(", "));
+            exit(1);
         }
     } else {
         let mut sorted_configs: Vec<&config::AppConfig> = app_configs.values().collect();
         sorted_configs.sort_by(|a, b| a.name.cmp(&b.name));
+        if let Some(only) = &args.flag_only {
+            let mut wanted = vec![];
+            for name in only.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                match util::get_case_insensitive(&app_configs, name) {
+                    Some(config) => wanted.push(&config.name),
+                    None => complain!("Warning: there's no app called '{}', so ignoring it.", name),
+                }
+            }
+            sorted_configs.retain(|config| wanted.contains(&&config.name));
+        }
+        if let Some(group) = &args.flag_group {
+            sorted_configs.retain(|config| config.group.as_ref().is_some_and(|name| name.eq_ignore_ascii_case(group)));
+            if sorted_configs.is_empty() {
+                complain!("Warning: there are no apps in the group '{}'.", group);
+            }
+        }
+
+        if args.cmd_watch {
+            let enabled_configs = sorted_configs.into_iter().filter(|cfg| !cfg.disabled).collect::<Vec<_>>();
+            let poll_interval = Duration::from_secs(args.flag_poll_interval.unwrap_or(watch::DEFAULT_POLL_INTERVAL_SECS));
+            let settle_time = Duration::from_secs(args.flag_settle_time.unwrap_or(watch::DEFAULT_SETTLE_SECS));
+            watch_apps(&enabled_configs, &options, poll_interval, settle_time);
+        }
+
+        if args.cmd_status {
+            for config in sorted_configs.iter().filter(|cfg| !cfg.disabled) {
+                println!("{}: {}", config.name, describe_status(config, &hostname));
+            }
+            return;
+        }
+
+        if args.cmd_list {
+            for config in sorted_configs.iter() {
+                println!("{}{}", config.name, if config.disabled { " (disabled)" } else { "" });
+                println!("  path: {}", config.path.to_string_lossy());
+                println!("  dropbox_path: {}", config.dropbox_path.to_string_lossy());
+                if let Some(play_path) = &config.play_path {
+                    println!("  play_path: {}", play_path.to_string_lossy());
+                }
+                if args.flag_with_status && !config.disabled {
+                    println!("  status: {}", describe_status(config, &hostname));
+                }
+            }
+            return;
+        }
+
+        if args.cmd_validate {
+            let mut found_problems = false;
+            for config in sorted_configs.iter().filter(|cfg| !cfg.disabled) {
+                for problem in config.problems() {
+                    println!("{}: {}.", config.name, problem);
+                    found_problems = true;
+                }
+            }
+            if found_problems {
+                exit(1);
+            }
+            println!("All enabled apps look good.");
+            return;
+        }
+
+        if let Some(plan_file) = &args.flag_dump_plan_and_exit {
+            let mut plan = plan::Plan::default();
+            for config in sorted_configs.iter().filter(|cfg| !cfg.disabled) {
+                if let Err(e) = config.validate() {
+                    complain!("{}: {} Leaving it out of the plan.", config.name, e);
+                    continue;
+                }
+                if config.archive {
+                    complain!("{}: its Dropbox copy is an archive, so leaving it out of the plan.", config.name);
+                    continue;
+                }
+                if config.encrypt {
+                    complain!("{}: its Dropbox copy is encrypted, so leaving it out of the plan.", config.name);
+                    continue;
+                }
+                let app_plan = plan::AppPlan::new(config, &hostname);
+                say!("{}: {:?}, {} file(s) to copy, {} to delete.",
+                    config.name, app_plan.result, app_plan.to_copy.len(), app_plan.to_delete.len());
+                plan.apps.push(app_plan);
+            }
+            if let Err(e) = fs::write(plan_file, plan.to_json()) {
+                complain!("Unable to write plan to {}: {}", plan_file.to_string_lossy(), e);
+                exit(1);
+            }
+            say!("Wrote plan to {}.", plan_file.to_string_lossy());
+            return;
+        }
+
+        if let Some(plan_file) = &args.flag_apply_plan {
+            let plan = fs::read_to_string(plan_file).map_err(|e| e.to_string()).and_then(|json| plan::Plan::from_json(&json));
+            let plan = match plan {
+                Ok(plan) => plan,
+                Err(e) => {
+                    complain!("Unable to load plan from {}: {}", plan_file.to_string_lossy(), e);
+                    exit(1);
+                },
+            };
+            let mut applied_all = true;
+            for app_plan in plan.apps.iter() {
+                say!("Applying plan for app {}.", app_plan.app);
+                if let Some(config) = app_configs.get(&app_plan.app) {
+                    if let Err(e) = app_plan.apply(config) {
+                        complain!("  {}", e);
+                        applied_all = false;
+                    }
+                } else {
+                    say!("  App is no longer in the config, skipping it.");
+                }
+            }
+            if !applied_all {
+                exit(1);
+            }
+            return;
+        }
+
+        let progress_file = progress::get_progress_path(&dropbox_dir, &hostname);
+        let mut progress = if args.flag_resume {
+            progress::BatchProgress::load(&progress_file)
+        } else {
+            progress::BatchProgress::default()
+        };
+        let enabled_configs = sorted_configs.into_iter().filter(|cfg| !cfg.disabled).collect::<Vec<_>>();
+        for config in enabled_configs.iter().filter(|cfg| progress.is_completed(&cfg.name)) {
+            say!("Skipping app {}, since it was synced by the previous run.", config.name);
+        }
+
+        let configs_to_sync = progress.skip_completed(enabled_configs);
+        let options = SyncOptions {
+            already_confirmed: !options.non_interactive && !options.dry_run && confirm_all(&configs_to_sync, &hostname, options.direction),
+            ..options
+        };
+
+        let mut finished_all = true;
+        let mut skipped_conflicts = false;
+        let mut run_report = report::RunReport::default();
+        for config in configs_to_sync {
+            say!("Syncing app {}.", config.name);
+            if let Err(e) = config.validate() {
+                complain!("  {} Skipping this app.", e);
+                let mut copy = CopyReport::default();
+                copy.errors.push(e);
+                run_report.apps.push(AppReport { app: config.name.clone(), result: SyncResult::ReadError, copy });
+                finished_all = false;
+                continue;
+            }
+            let app_report = sync_app_and_report(config, true, &options);
+            let result = app_report.result;
+            run_report.apps.push(app_report);
+            if interrupt::is_interrupted() {
+                complain!("Stopping, since we were interrupted. Run dropsync with --resume to pick up where it left off.");
+                finished_all = false;
+                break;
+            }
+            if result == SyncResult::Skipped && options.non_interactive {
+                skipped_conflicts = true;
+            }
+            if result == SyncResult::Conflict || result == SyncResult::ReadError || result == SyncResult::Skipped {
+                finished_all = false;
+            } else if !options.dry_run {
+                progress.mark_completed(&config.name);
+                progress.save(&progress_file);
+            }
+        }
+
+        let (files_copied, files_deleted) = run_report.totals();
+        if run_report.apps.len() > 1 && (files_copied > 0 || files_deleted > 0) {
+            say!("In total, {}.", report::describe_copy_counts(files_copied, files_deleted));
+        }
+
+        if finished_all {
+            progress::BatchProgress::remove(&progress_file);
+        }
+
+        if args.flag_json {
+            println!("{}", run_report.to_json());
+        }
+
+        if options.notify {
+            notify::notify("dropsync", &run_report.summary());
+        }
+
+        if interrupt::is_interrupted() {
+            exit(130);
+        }
 
-        for config in sorted_configs.iter().filter(|cfg| !cfg.disabled) {
-            println!("Syncing app {}.", config.name);
-            config.validate();
-            sync_app(config, true);
+        if skipped_conflicts {
+            complain!("Some apps were in conflict and need to be resolved manually.");
         }
+        exit(run_report.exit_code());
     }
 }
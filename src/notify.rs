@@ -0,0 +1,51 @@
+use std::process::{Command, Stdio};
+
+use crate::output::detail;
+
+// Shows a desktop notification, using whatever the OS provides for it.
+// This is just a nicety, so if it doesn't work, we don't make a fuss.
+pub fn notify(title: &str, body: &str) {
+    let mut command = if cfg!(target_os = "windows") {
+        let script = format!(
+            "Add-Type -AssemblyName System.Windows.Forms; \
+             $n = New-Object System.Windows.Forms.NotifyIcon; \
+             $n.Icon = [System.Drawing.SystemIcons]::Information; \
+             $n.Visible = $true; \
+             $n.ShowBalloonTip(10000, '{}', '{}', 'Info'); \
+             Start-Sleep 10; $n.Dispose()",
+            powershell_escape(title), powershell_escape(body)
+        );
+        let mut command = Command::new("powershell");
+        command.args(["-NoProfile", "-WindowStyle", "Hidden", "-Command", &script]);
+        command
+    } else if cfg!(target_os = "macos") {
+        let script = format!("display notification \"{}\" with title \"{}\"", applescript_escape(body), applescript_escape(title));
+        let mut command = Command::new("osascript");
+        command.args(["-e", &script]);
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.args([title, body]);
+        command
+    };
+    // Don't wait for it, since on some systems it sticks around until the
+    // notification goes away.
+    let result = command.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn();
+    if let Err(e) = result {
+        detail!("Unable to show a notification: {}", e);
+    }
+}
+
+fn powershell_escape(s: &str) -> String {
+    s.replace('\'', "''")
+}
+
+fn applescript_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[test]
+fn test_escape() {
+    assert_eq!(powershell_escape("Atul's game"), "Atul''s game");
+    assert_eq!(applescript_escape("a \"quoted\" \\ game"), "a \\\"quoted\\\" \\\\ game");
+}
@@ -0,0 +1,84 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+// How much we print, from nothing at all to every file we touch.
+#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
+pub enum Level {
+    // Nothing but what's explicitly asked for, e.g. because we're
+    // printing JSON instead.
+    Silent,
+    // Only warnings and errors, e.g. for running from cron.
+    Quiet,
+    Normal,
+    // Also every file that's copied or deleted.
+    Verbose,
+}
+
+impl Level {
+    // Whether messages at the given level are printed at this one.
+    pub fn includes(self, level: Level) -> bool {
+        self >= level
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Normal as u8);
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+fn get_level() -> Level {
+    match LEVEL.load(Ordering::SeqCst) {
+        0 => Level::Silent,
+        1 => Level::Quiet,
+        2 => Level::Normal,
+        _ => Level::Verbose,
+    }
+}
+
+pub fn is_enabled(level: Level) -> bool {
+    get_level().includes(level)
+}
+
+// Like `println!`, but only prints at the normal level or above.
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_enabled($crate::output::Level::Normal) {
+            println!($($arg)*);
+        }
+    };
+}
+
+// For per-file details, which are only printed in verbose mode.
+#[macro_export]
+macro_rules! detail {
+    ($($arg:tt)*) => {
+        if $crate::output::is_enabled($crate::output::Level::Verbose) {
+            println!($($arg)*);
+        }
+    };
+}
+
+// For warnings and errors, which are printed even in quiet mode.
+#[macro_export]
+macro_rules! complain {
+    ($($arg:tt)*) => {
+        if $crate::output::is_enabled($crate::output::Level::Quiet) {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub use crate::{complain, detail, say};
+
+#[test]
+fn test_levels() {
+    assert!(Level::Silent < Level::Quiet);
+    assert!(Level::Normal < Level::Verbose);
+    assert!(Level::Quiet.includes(Level::Quiet));
+    assert!(!Level::Quiet.includes(Level::Normal));
+    assert!(!Level::Silent.includes(Level::Quiet));
+    assert!(Level::Verbose.includes(Level::Normal));
+    // Nothing in the tests changes the level, so it's still the default.
+    assert_eq!(get_level(), Level::Normal);
+}
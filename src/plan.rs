@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::dir_state::{self, CopyDiff, DirState};
+use crate::output::say;
+use crate::{interrupt, rollback, snapshot, SyncResult};
+
+// Everything we need to know to decide how to sync an app, figured out
+// without changing anything.
+pub struct AppComparison {
+    pub result: SyncResult,
+    pub diff: CopyDiff,
+    pub app_state: DirState,
+    pub dropbox_state: DirState,
+}
+
+pub fn compare_app(app: &AppConfig, hostname: &str) -> AppComparison {
+    compare_app_with_dropbox_state(app, hostname, None)
+}
+
+// Like `compare_app`, but uses the given scan of the app's Dropbox folder,
+// if we already know what it looks like, instead of scanning it again.
+pub fn compare_app_with_dropbox_state(app: &AppConfig, hostname: &str, dropbox_state: Option<DirState>) -> AppComparison {
+    let app_state = app.scan_path();
+    let dropbox_state = dropbox_state.unwrap_or_else(|| app.scan_dropbox_path());
+    let last_sync = snapshot::load(app, hostname);
+    if let Some(last_sync) = &last_sync {
+        // The snapshot only knows what Dropbox had, so being paranoid means
+        // not assuming the app's files are the same just because they look it.
+        if !app.compare.paranoid {
+            app_state.adopt_hashes_from(last_sync);
+        }
+        dropbox_state.adopt_hashes_from(last_sync);
+    }
+    let result = snapshot::classify_with_snapshot(&app_state, &dropbox_state, last_sync.as_ref());
+    let diff = match result {
+        SyncResult::AppNewerThanDropbox => {
+            let mut diff = app_state.diff_copy_into(&dropbox_state);
+            if !app.may_delete_from_dropbox(hostname) {
+                diff.to_delete.clear();
+            }
+            diff
+        },
+        SyncResult::DropboxNewerThanApp => {
+            let mut diff = dropbox_state.diff_copy_into(&app_state);
+            if !app.may_delete_from_app() {
+                diff.to_delete.clear();
+            }
+            diff
+        },
+        _ => Default::default(),
+    };
+    AppComparison { result, diff, app_state, dropbox_state }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Plan {
+    pub apps: Vec<AppPlan>,
+}
+
+impl Plan {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|e| format!("The plan isn't valid: {}", e))
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppPlan {
+    pub app: String,
+    pub result: SyncResult,
+    pub to_copy: Vec<PathBuf>,
+    pub to_delete: Vec<PathBuf>,
+    // What both sides looked like when the plan was made, so we can tell
+    // if they've changed since.
+    app_state: DirState,
+    dropbox_state: DirState,
+}
+
+impl AppPlan {
+    pub fn new(app: &AppConfig, hostname: &str) -> Self {
+        let comparison = compare_app(app, hostname);
+        AppPlan {
+            app: app.name.clone(),
+            result: comparison.result,
+            to_copy: comparison.diff.to_copy,
+            to_delete: comparison.diff.to_delete,
+            app_state: comparison.app_state,
+            dropbox_state: comparison.dropbox_state,
+        }
+    }
+
+    pub fn has_drifted(&self, app: &AppConfig) -> bool {
+        let app_state = app.scan_path();
+        let dropbox_state = app.scan_dropbox_path();
+        app_state.path() != self.app_state.path() ||
+        dropbox_state.path() != self.dropbox_state.path() ||
+        !app_state.are_contents_equal_to(&self.app_state) ||
+        !dropbox_state.are_contents_equal_to(&self.dropbox_state)
+    }
+
+    pub fn apply(&self, app: &AppConfig) -> Result<(), String> {
+        if self.has_drifted(app) {
+            return Err(String::from("App or Dropbox state has changed since the plan was made, skipping it."));
+        }
+        // The stored states don't know about the app's file filter, so use fresh ones.
+        let (from_dir, to_dir) = match self.result {
+            SyncResult::AppNewerThanDropbox => (&app.path, &app.dropbox_path),
+            SyncResult::DropboxNewerThanApp => (&app.dropbox_path, &app.path),
+            SyncResult::Conflict => {
+                return Err(String::from("App and Dropbox state were in conflict, skipping it."));
+            },
+            SyncResult::ReadError => {
+                return Err(String::from("App or Dropbox state couldn't be read, skipping it."));
+            },
+            _ => {
+                say!("  Nothing to do!");
+                return Ok(());
+            },
+        };
+        let _changing_files = interrupt::ChangingFiles::start();
+        let from_state = DirState::from_dir(from_dir, &app.file_filter);
+        let to_error_message = |errors: Vec<dir_state::FileError>| {
+            errors.iter().map(|e| e.to_string()).collect::<Vec<String>>().join("\n  ")
+        };
+        if let Some(backup_path) = app.new_backup_path() {
+            let to_state = DirState::from_dir(to_dir, &app.file_filter);
+            let to_back_up = [self.to_copy.clone(), self.to_delete.clone()].concat();
+            to_state.back_up_into(&to_back_up, &backup_path).map_err(to_error_message)?;
+            let side = if to_dir == &app.path { rollback::Side::App } else { rollback::Side::Dropbox };
+            rollback::record(&backup_path, &[rollback::Change::new(side, "", &self.to_copy, &self.to_delete)]).map_err(to_error_message)?;
+        }
+        say!("  Copying {} file(s).", self.to_copy.len());
+        from_state.copy_into(to_dir).map_err(to_error_message)?;
+        if interrupt::is_interrupted() {
+            return Err(String::from("Interrupted while copying, so not deleting anything."));
+        }
+        if !self.to_delete.is_empty() {
+            say!("  Deleting {} file(s).", self.to_delete.len());
+            if app.use_trash {
+                from_state.trash_extraneous_files_from(to_dir).map_err(to_error_message)?;
+            } else {
+                from_state.remove_extraneous_files_from(to_dir).map_err(to_error_message)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn make_test_dirs(name: &str) -> AppConfig {
+    use std::fs;
+
+    let root = PathBuf::from(name);
+    if root.exists() {
+        fs::remove_dir_all(&root).unwrap();
+    }
+    let app = AppConfig {
+        name: String::from("boop"),
+        path: root.join("app"),
+        dropbox_path: root.join("dropbox"),
+        ..Default::default()
+    };
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &app.file_filter);
+    src_state.copy_into(&app.path).unwrap();
+    src_state.copy_into(&app.dropbox_path).unwrap();
+
+    // Make the app side newer than Dropbox.
+    let newer = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+    fs::File::options().write(true).open(app.path.join("boop")).unwrap().set_modified(newer).unwrap();
+    app
+}
+
+#[test]
+fn test_plan_serialization() {
+    let app = make_test_dirs(".test_plan_serialization");
+
+    let plan = Plan { apps: vec![AppPlan::new(&app, "my_computer")] };
+    let loaded = Plan::from_json(&plan.to_json()).unwrap();
+    assert_eq!(loaded.apps.len(), 1);
+    assert_eq!(loaded.apps[0].app, "boop");
+    assert_eq!(loaded.apps[0].result, SyncResult::AppNewerThanDropbox);
+    assert_eq!(loaded.apps[0].to_copy, vec![PathBuf::from("boop"), PathBuf::from("subdir").join("shoop")]);
+    assert_eq!(loaded.apps[0].to_delete, Vec::<PathBuf>::new());
+    assert!(loaded.apps[0].app_state.are_contents_equal_to(&plan.apps[0].app_state));
+    assert!(!loaded.apps[0].has_drifted(&app));
+    assert!(Plan::from_json("{\"apps\": 5}").is_err());
+
+    std::fs::remove_dir_all(".test_plan_serialization").unwrap();
+}
+
+#[test]
+fn test_plan_drift() {
+    let app = make_test_dirs(".test_plan_drift");
+
+    let plan = AppPlan::new(&app, "my_computer");
+    std::fs::write(app.path.join("new_file"), "blarg").unwrap();
+    assert!(plan.has_drifted(&app));
+    assert!(plan.apply(&app).is_err());
+    assert!(!app.dropbox_path.join("new_file").exists());
+
+    let plan = AppPlan::new(&app, "my_computer");
+    assert_eq!(plan.to_delete, Vec::<PathBuf>::new());
+    assert!(plan.apply(&app).is_ok());
+    assert!(app.dropbox_path.join("new_file").exists());
+    assert!(app.scan_dropbox_path().are_contents_equal_to(&plan.app_state));
+
+    std::fs::remove_dir_all(".test_plan_drift").unwrap();
+}
+
+#[test]
+fn test_compare_app() {
+    let app = make_test_dirs(".test_compare_app");
+    // The file was in Dropbox the last time we synced, so it must have
+    // been deleted from the app since.
+    std::fs::write(app.dropbox_path.join("extra_file"), "blarg").unwrap();
+    snapshot::save(&app, "my_computer", &app.scan_dropbox_path());
+
+    let comparison = compare_app(&app, "my_computer");
+    assert_eq!(comparison.result, SyncResult::AppNewerThanDropbox);
+    assert_eq!(comparison.diff.to_delete, vec![PathBuf::from("extra_file")]);
+
+    // Only the delete authority would delete anything from Dropbox.
+    let app = AppConfig { delete_authority: Some(String::from("other_computer")), ..app };
+    let comparison = compare_app(&app, "my_computer");
+    assert!(comparison.diff.to_delete.is_empty());
+
+    // Comparing shouldn't have changed anything.
+    assert!(app.dropbox_path.join("extra_file").exists());
+
+    std::fs::remove_dir_all(".test_compare_app").unwrap();
+}
@@ -0,0 +1,91 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::file_filter::RESERVED_PREFIX;
+use crate::output::complain;
+
+// Where this computer keeps track of how far its last batch run got.
+pub fn get_progress_path(dropbox_dir: &Path, hostname: &str) -> PathBuf {
+    dropbox_dir.join(format!("{}progress-{}.json", RESERVED_PREFIX, hostname))
+}
+
+// Keeps track of which apps a batch run has finished with, so that an
+// interrupted run can be resumed later.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BatchProgress {
+    completed: Vec<String>,
+}
+
+impl BatchProgress {
+    pub fn load(path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).unwrap_or_default(),
+            Err(_) => Self::default(),
+        }
+    }
+
+    // Losing track of our progress only means redoing some work after
+    // an interruption, so this isn't fatal.
+    pub fn save(&self, path: &Path) {
+        if let Err(e) = fs::write(path, serde_json::to_string(self).unwrap()) {
+            complain!("  Warning: unable to save {}: {}", path.to_string_lossy(), e);
+        }
+    }
+
+    // Forgets about the progress saved at the given path, once there's no
+    // longer anything to resume.
+    pub fn remove(path: &Path) {
+        if !path.exists() {
+            return;
+        }
+        if let Err(e) = fs::remove_file(path) {
+            complain!("Warning: unable to remove {}: {}", path.to_string_lossy(), e);
+        }
+    }
+
+    pub fn mark_completed(&mut self, app_name: &str) {
+        if !self.is_completed(app_name) {
+            self.completed.push(app_name.to_owned());
+        }
+    }
+
+    pub fn is_completed(&self, app_name: &str) -> bool {
+        self.completed.iter().any(|name| name == app_name)
+    }
+
+    pub fn skip_completed<'a>(&self, configs: Vec<&'a AppConfig>) -> Vec<&'a AppConfig> {
+        configs.into_iter().filter(|config| !self.is_completed(&config.name)).collect()
+    }
+}
+
+#[test]
+fn test_batch_progress() {
+    let path = Path::new(".test_batch_progress.json");
+    if path.exists() {
+        fs::remove_file(path).unwrap();
+    }
+    assert_eq!(BatchProgress::load(path), BatchProgress::default());
+
+    let mut progress = BatchProgress::default();
+    progress.mark_completed("app1");
+    progress.mark_completed("app1");
+    progress.save(path);
+    let loaded = BatchProgress::load(path);
+    assert_eq!(loaded, progress);
+    assert!(loaded.is_completed("app1"));
+    assert!(!loaded.is_completed("app2"));
+
+    let app1 = AppConfig { name: String::from("app1"), ..Default::default() };
+    let app2 = AppConfig { name: String::from("app2"), ..Default::default() };
+    let remaining = loaded.skip_completed(vec![&app1, &app2]);
+    assert_eq!(remaining, vec![&app2]);
+
+    BatchProgress::remove(path);
+    assert!(!path.exists());
+
+    // Failing to save is only worth a warning.
+    progress.save(Path::new(".test_batch_progress_nonexistent").join("progress.json").as_path());
+    assert_eq!(get_progress_path(Path::new("dropbox"), "my_computer"), PathBuf::from("dropbox").join(".dropsync-progress-my_computer.json"));
+}
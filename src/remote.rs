@@ -0,0 +1,309 @@
+// Without the `remote` feature, only the tests use most of this module.
+#![cfg_attr(not(feature = "remote"), allow(dead_code))]
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::AppConfig;
+use crate::dir_state::{DirState, ListedEntry};
+use crate::file_filter::FileFilter;
+use crate::output::say;
+use crate::report::CopyReport;
+use crate::{SyncOptions, SyncResult};
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RemoteTarget {
+    pub host: String,
+    pub path: PathBuf,
+}
+
+impl RemoteTarget {
+    // Splits `user@host:port` into its parts, filling in defaults for
+    // anything that's missing.
+    pub fn parse_host(&self) -> (String, String, u16) {
+        let (user, host) = match self.host.split_once('@') {
+            Some((user, host)) => (String::from(user), host),
+            None => (std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default(), self.host.as_str()),
+        };
+        match host.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => (user, String::from(host), port.parse().unwrap()),
+            _ => (user, String::from(host), 22),
+        }
+    }
+}
+
+pub trait RemoteFs {
+    fn list_dir(&self, path: &Path) -> io::Result<Vec<ListedEntry>>;
+}
+
+pub fn remote_dir_state(remote_fs: &dyn RemoteFs, path: &Path, file_filter: &FileFilter) -> DirState {
+    DirState::from_listing(path, file_filter, &|dir: &Path| remote_fs.list_dir(dir))
+}
+
+#[cfg(not(feature = "remote"))]
+pub fn sync_app_with_remote(_app: &AppConfig, remote: &RemoteTarget, _confirm_if_app_is_newer: bool, _options: &SyncOptions) -> (SyncResult, CopyReport) {
+    say!("  This app syncs with {}, but dropsync was built without remote support.", remote.host);
+    say!("  Please reinstall it with `--features remote`.");
+    (SyncResult::ReadError, CopyReport::default())
+}
+
+#[cfg(feature = "remote")]
+pub fn sync_app_with_remote(app: &AppConfig, remote: &RemoteTarget, confirm_if_app_is_newer: bool, options: &SyncOptions) -> (SyncResult, CopyReport) {
+    use crate::{add_file_errors, apply_direction, ask};
+    use crate::output::complain;
+
+    let mut report = CopyReport::default();
+    let sftp = match sftp::SftpRemote::connect(remote) {
+        Ok(sftp) => sftp,
+        Err(e) => {
+            complain!("  Unable to connect to {}: {}", remote.host, e);
+            report.errors.push(format!("Unable to connect to {}: {}", remote.host, e));
+            return (SyncResult::ReadError, report);
+        }
+    };
+    let dir_state = app.scan_path();
+    let remote_state = remote_dir_state(&sftp, &remote.path, &app.file_filter).with_compare_options(app.compare);
+    let (result, forced) = apply_direction(dir_state.classify_against(&remote_state), options);
+    let (from_state, to_state, should_ask) = match result {
+        SyncResult::AppNewerThanDropbox => {
+            if !forced {
+                say!("  App state is newer than {}.", remote.host);
+            }
+            (&dir_state, &remote_state, confirm_if_app_is_newer)
+        },
+        SyncResult::DropboxNewerThanApp => {
+            if !forced {
+                say!("  State on {} is newer than app.", remote.host);
+            }
+            (&remote_state, &dir_state, true)
+        },
+        SyncResult::AlreadySynced | SyncResult::BothEmpty => {
+            say!("  App state matches {}. Nothing to do!", remote.host);
+            report.completed = true;
+            return (result, report);
+        },
+        SyncResult::Conflict => {
+            complain!("  App and {} state are in conflict; please resolve it manually.", remote.host);
+            return (result, report);
+        },
+        SyncResult::ReadError => {
+            complain!("  Unable to read the app or remote state, so not doing anything.");
+            return (result, report);
+        },
+        SyncResult::Skipped | SyncResult::Merged => unreachable!("classification never skips or merges an app"),
+    };
+    let mut diff = from_state.diff_copy_into(to_state);
+    if !app.compare.mirror {
+        diff.to_delete.clear();
+    }
+    if options.dry_run {
+        say!("  Dry run: would copy {} file(s) and delete {}.", diff.to_copy.len(), diff.to_delete.len());
+        return (result, report);
+    }
+    if should_ask && !options.non_interactive && !ask::ask_yes_or_no("  Proceed with synchronization (y/n) ? ") {
+        say!("  Okay, not doing anything.");
+        return (result, report);
+    }
+    let errors = if result == SyncResult::AppNewerThanDropbox {
+        sftp.push(&app.path, &remote.path, &diff)
+    } else {
+        sftp.pull(&remote.path, &app.path, &diff)
+    };
+    if errors.is_empty() {
+        report.files_copied = diff.to_copy.len();
+        report.files_deleted = diff.to_delete.len();
+        report.completed = true;
+    } else {
+        add_file_errors(&mut report, "sync", &errors);
+    }
+    (result, report)
+}
+
+#[cfg(feature = "remote")]
+mod sftp {
+    use std::fs;
+    use std::io;
+    use std::net::TcpStream;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use super::{RemoteFs, RemoteTarget};
+    use crate::dir_state::{CopyDiff, FileError, ListedEntry};
+
+    pub struct SftpRemote {
+        sftp: ssh2::Sftp,
+        // The session needs to stay alive as long as we're using SFTP.
+        _session: ssh2::Session,
+    }
+
+    // SFTP always wants forward slashes, regardless of what we're running on.
+    fn to_remote_path(path: &Path) -> PathBuf {
+        PathBuf::from(path.to_string_lossy().replace('\\', "/"))
+    }
+
+    fn collect_error(path: &Path, result: io::Result<()>, errors: &mut Vec<FileError>) {
+        if let Err(error) = result {
+            errors.push(FileError { path: path.to_path_buf(), error });
+        }
+    }
+
+    impl SftpRemote {
+        pub fn connect(remote: &RemoteTarget) -> io::Result<Self> {
+            let (user, host, port) = remote.parse_host();
+            let tcp = TcpStream::connect((host.as_str(), port))?;
+            let mut session = ssh2::Session::new()?;
+            session.set_tcp_stream(tcp);
+            session.handshake()?;
+            session.userauth_agent(&user)?;
+            let sftp = session.sftp()?;
+            Ok(SftpRemote { sftp, _session: session })
+        }
+
+        fn upload(&self, local_path: &Path, remote_path: &Path) -> io::Result<()> {
+            let mut src = fs::File::open(local_path)?;
+            let mut dest = self.sftp.create(remote_path)?;
+            io::copy(&mut src, &mut dest)?;
+            let modified = fs::metadata(local_path)?.modified()?.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+            let mut stat = self.sftp.stat(remote_path)?;
+            stat.mtime = Some(modified);
+            stat.atime = Some(modified);
+            self.sftp.setstat(remote_path, stat)?;
+            Ok(())
+        }
+
+        fn download(&self, remote_path: &Path, local_path: &Path) -> io::Result<()> {
+            let mut src = self.sftp.open(remote_path)?;
+            let mut dest = fs::File::create(local_path)?;
+            io::copy(&mut src, &mut dest)?;
+            if let Some(mtime) = self.sftp.stat(remote_path)?.mtime {
+                dest.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(mtime))?;
+            }
+            Ok(())
+        }
+
+        fn make_remote_dirs(&self, remote_dir: &Path) {
+            let mut path = PathBuf::new();
+            for component in remote_dir.components() {
+                path.push(component);
+                // This will fail if the directory already exists, which is fine.
+                let _ = self.sftp.mkdir(&to_remote_path(&path), 0o755);
+            }
+        }
+
+        fn remove_remote_all(&self, remote_path: &Path) -> io::Result<()> {
+            let stat = self.sftp.stat(remote_path)?;
+            if stat.is_dir() {
+                for (child, _) in self.sftp.readdir(remote_path)? {
+                    self.remove_remote_all(&to_remote_path(&child))?;
+                }
+                self.sftp.rmdir(remote_path)?;
+            } else {
+                self.sftp.unlink(remote_path)?;
+            }
+            Ok(())
+        }
+
+        pub fn push(&self, local_root: &Path, remote_root: &Path, diff: &CopyDiff) -> Vec<FileError> {
+            let mut errors = vec![];
+            for rel_path in diff.to_copy.iter() {
+                let remote_path = to_remote_path(&remote_root.join(rel_path));
+                if let Some(parent) = remote_path.parent() {
+                    self.make_remote_dirs(parent);
+                }
+                collect_error(&remote_path, self.upload(&local_root.join(rel_path), &remote_path), &mut errors);
+            }
+            if errors.is_empty() {
+                for rel_path in diff.to_delete.iter() {
+                    let remote_path = to_remote_path(&remote_root.join(rel_path));
+                    collect_error(&remote_path, self.remove_remote_all(&remote_path), &mut errors);
+                }
+            }
+            errors
+        }
+
+        pub fn pull(&self, remote_root: &Path, local_root: &Path, diff: &CopyDiff) -> Vec<FileError> {
+            let mut errors = vec![];
+            for rel_path in diff.to_copy.iter() {
+                let local_path = local_root.join(rel_path);
+                let result = local_path.parent().map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| self.download(&to_remote_path(&remote_root.join(rel_path)), &local_path));
+                collect_error(&local_path, result, &mut errors);
+            }
+            if errors.is_empty() {
+                for rel_path in diff.to_delete.iter() {
+                    let local_path = local_root.join(rel_path);
+                    let result = if local_path.is_dir() { fs::remove_dir_all(&local_path) } else { fs::remove_file(&local_path) };
+                    collect_error(&local_path, result, &mut errors);
+                }
+            }
+            errors
+        }
+    }
+
+    impl RemoteFs for SftpRemote {
+        fn list_dir(&self, path: &Path) -> io::Result<Vec<ListedEntry>> {
+            let entries = self.sftp.readdir(to_remote_path(path))?;
+            Ok(entries.into_iter().filter_map(|(child, stat)| {
+                let name = child.file_name()?.to_string_lossy().to_string();
+                Some(ListedEntry {
+                    name,
+                    is_dir: stat.is_dir(),
+                    size: stat.size.unwrap_or(0),
+                    modified: stat.mtime.unwrap_or(0),
+                })
+            }).collect())
+        }
+    }
+}
+
+#[test]
+fn test_parse_host() {
+    let target = |host: &str| RemoteTarget { host: String::from(host), path: PathBuf::from("/saves") };
+    assert_eq!(target("atul@boop").parse_host(), (String::from("atul"), String::from("boop"), 22));
+    assert_eq!(target("atul@boop:2222").parse_host(), (String::from("atul"), String::from("boop"), 2222));
+}
+
+#[test]
+fn test_remote_dir_state() {
+    use std::collections::HashMap;
+    use std::fs;
+
+    struct MockRemote(HashMap<PathBuf, Vec<ListedEntry>>);
+
+    impl RemoteFs for MockRemote {
+        fn list_dir(&self, path: &Path) -> io::Result<Vec<ListedEntry>> {
+            self.0.get(path).cloned().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not found"))
+        }
+    }
+
+    // Mirror the metadata of our test directory.
+    let src_dir = PathBuf::from("test-data/dirstate_test");
+    let entry = |name: &str, path: PathBuf| {
+        let metadata = fs::metadata(&path).unwrap();
+        ListedEntry {
+            name: String::from(name),
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            modified: metadata.modified().unwrap().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+        }
+    };
+    let remote_root = PathBuf::from("/saves");
+    let mut listings = HashMap::new();
+    listings.insert(remote_root.clone(), vec![
+        entry("boop", src_dir.join("boop")),
+        entry("subdir", src_dir.join("subdir")),
+    ]);
+    listings.insert(remote_root.join("subdir"), vec![
+        entry("shoop", src_dir.join("subdir").join("shoop")),
+    ]);
+    let mock = MockRemote(listings);
+
+    let local_state = DirState::from_dir(&src_dir, &FileFilter::default());
+    let remote_state = remote_dir_state(&mock, &remote_root, &FileFilter::default());
+    assert!(remote_state.was_read_cleanly());
+    assert!(remote_state.are_contents_equal_to(&local_state));
+
+    let missing_state = remote_dir_state(&mock, Path::new("/nonexistent"), &FileFilter::default());
+    assert!(missing_state.is_empty());
+    assert!(!missing_state.was_read_cleanly());
+}
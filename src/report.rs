@@ -0,0 +1,155 @@
+use serde::Serialize;
+
+use crate::SyncResult;
+
+// What actually happened when we tried to copy files from one side to
+// the other.
+#[derive(Debug, Default, Serialize)]
+pub struct CopyReport {
+    pub files_copied: usize,
+    pub files_deleted: usize,
+    pub errors: Vec<String>,
+    // Whether both sides were in sync by the time we were done.
+    #[serde(skip)]
+    pub completed: bool,
+}
+
+impl CopyReport {
+    pub fn did_anything(&self) -> bool {
+        self.files_copied > 0 || self.files_deleted > 0
+    }
+}
+
+// E.g. "copied 12 files and deleted 3 files".
+pub fn describe_copy_counts(files_copied: usize, files_deleted: usize) -> String {
+    format!("copied {} and deleted {}", count(files_copied, "file"), count(files_deleted, "file"))
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppReport {
+    pub app: String,
+    pub result: SyncResult,
+    #[serde(flatten)]
+    pub copy: CopyReport,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct RunReport {
+    pub apps: Vec<AppReport>,
+}
+
+// What dropsync exits with, so scripts can tell what went wrong.
+pub const EXIT_CONFLICTS: i32 = 2;
+pub const EXIT_ERRORS: i32 = 3;
+
+impl RunReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    // Errors are more important than conflicts, since a conflict at least
+    // leaves both sides alone.
+    pub fn exit_code(&self) -> i32 {
+        if self.apps.iter().any(|app| app.result == SyncResult::ReadError || !app.copy.errors.is_empty()) {
+            EXIT_ERRORS
+        } else if self.apps.iter().any(|app| app.result == SyncResult::Conflict || app.result == SyncResult::Skipped) {
+            EXIT_CONFLICTS
+        } else {
+            0
+        }
+    }
+
+    // How many files were copied and deleted across all apps.
+    pub fn totals(&self) -> (usize, usize) {
+        let files_copied = self.apps.iter().map(|app| app.copy.files_copied).sum();
+        let files_deleted = self.apps.iter().map(|app| app.copy.files_deleted).sum();
+        (files_copied, files_deleted)
+    }
+
+    // A one-line description of how the run went, e.g. for a notification.
+    pub fn summary(&self) -> String {
+        let names = |filter: &dyn Fn(&AppReport) -> bool| self.apps.iter().filter(|app| filter(app)).map(|app| app.app.as_str()).collect::<Vec<&str>>();
+        let failed = names(&|app| app.result == SyncResult::ReadError || !app.copy.errors.is_empty());
+        let conflicted = names(&|app| app.copy.errors.is_empty() && (app.result == SyncResult::Conflict || app.result == SyncResult::Skipped));
+        let synced = self.apps.len() - failed.len() - conflicted.len();
+        let mut parts = vec![format!("{} synced", count(synced, "app"))];
+        if !conflicted.is_empty() {
+            parts.push(format!("{} in {}", count(conflicted.len(), "conflict"), conflicted.join(", ")));
+        }
+        if !failed.is_empty() {
+            parts.push(format!("{} in {}", count(failed.len(), "error"), failed.join(", ")));
+        }
+        parts.join(", ")
+    }
+}
+
+fn count(n: usize, noun: &str) -> String {
+    format!("{} {}{}", n, noun, if n == 1 { "" } else { "s" })
+}
+
+#[test]
+fn test_summary() {
+    let app = |name: &str, result, errors: &[&str]| AppReport {
+        app: String::from(name),
+        result,
+        copy: CopyReport { errors: errors.iter().map(|e| String::from(*e)).collect(), ..Default::default() },
+    };
+    assert_eq!(RunReport::default().summary(), "0 apps synced");
+    assert_eq!(RunReport { apps: vec![app("a", SyncResult::AlreadySynced, &[])] }.summary(), "1 app synced");
+    let report = RunReport { apps: vec![
+        app("a", SyncResult::AlreadySynced, &[]),
+        app("b", SyncResult::AppNewerThanDropbox, &[]),
+        app("c", SyncResult::Merged, &[]),
+        app("MyGame", SyncResult::Skipped, &[]),
+        app("d", SyncResult::DropboxNewerThanApp, &["oops"]),
+    ] };
+    assert_eq!(report.summary(), "3 apps synced, 1 conflict in MyGame, 1 error in d");
+}
+
+#[test]
+fn test_totals() {
+    let app = |files_copied, files_deleted| AppReport {
+        app: String::from("boop"),
+        result: SyncResult::AppNewerThanDropbox,
+        copy: CopyReport { files_copied, files_deleted, ..Default::default() },
+    };
+    assert_eq!(RunReport::default().totals(), (0, 0));
+    assert_eq!(RunReport { apps: vec![app(12, 0), app(0, 0), app(1, 3)] }.totals(), (13, 3));
+    assert_eq!(describe_copy_counts(13, 1), "copied 13 files and deleted 1 file");
+}
+
+#[test]
+fn test_run_report_to_json() {
+    let report = RunReport {
+        apps: vec![AppReport {
+            app: String::from("boop"),
+            result: SyncResult::AppNewerThanDropbox,
+            copy: CopyReport { files_copied: 2, files_deleted: 1, errors: vec![String::from("blah: oops")], completed: false },
+        }],
+    };
+    let value: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+    assert_eq!(value, serde_json::json!({
+        "apps": [{
+            "app": "boop",
+            "result": "app_newer_than_dropbox",
+            "files_copied": 2,
+            "files_deleted": 1,
+            "errors": ["blah: oops"],
+        }],
+    }));
+}
+
+#[test]
+fn test_exit_code() {
+    let app = |result, errors: &[&str]| AppReport {
+        app: String::from("boop"),
+        result,
+        copy: CopyReport { errors: errors.iter().map(|e| String::from(*e)).collect(), ..Default::default() },
+    };
+    let exit_code = |apps| RunReport { apps }.exit_code();
+    assert_eq!(exit_code(vec![]), 0);
+    assert_eq!(exit_code(vec![app(SyncResult::AlreadySynced, &[]), app(SyncResult::DropboxNewerThanApp, &[])]), 0);
+    assert_eq!(exit_code(vec![app(SyncResult::AlreadySynced, &[]), app(SyncResult::Skipped, &[])]), EXIT_CONFLICTS);
+    assert_eq!(exit_code(vec![app(SyncResult::Conflict, &[]), app(SyncResult::AppNewerThanDropbox, &["oops"])]), EXIT_ERRORS);
+    assert_eq!(exit_code(vec![app(SyncResult::ReadError, &[])]), EXIT_ERRORS);
+}
@@ -0,0 +1,162 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::config::AppConfig;
+use crate::dir_state::{self, DirState, FileError};
+use crate::file_filter::RESERVED_PREFIX;
+use crate::output::say;
+use crate::{ask, SyncOptions};
+
+// Which of an app's folders a sync changed.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Side {
+    App,
+    Dropbox,
+}
+
+// What a sync did to one side, so that it can be undone. Everything it
+// overwrote or deleted was backed up into `backup_subdir` of the backup
+// folder, so anything it copied that isn't there must have been new.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    pub side: Side,
+    pub backup_subdir: PathBuf,
+    pub copied: Vec<PathBuf>,
+    pub deleted: Vec<PathBuf>,
+}
+
+impl Change {
+    pub fn new(side: Side, backup_subdir: &str, copied: &[PathBuf], deleted: &[PathBuf]) -> Self {
+        Change { side, backup_subdir: PathBuf::from(backup_subdir), copied: copied.to_vec(), deleted: deleted.to_vec() }
+    }
+}
+
+fn get_manifest_path(backup_path: &Path) -> PathBuf {
+    backup_path.join(format!("{}backup.json", RESERVED_PREFIX))
+}
+
+// Remembers what a sync is about to do alongside the backups it made, so
+// `dropsync rollback` can undo it.
+pub fn record(backup_path: &Path, changes: &[Change]) -> Result<(), Vec<FileError>> {
+    let manifest_path = get_manifest_path(backup_path);
+    fs::create_dir_all(backup_path)
+        .and_then(|_| fs::write(&manifest_path, serde_json::to_string_pretty(changes).unwrap()))
+        .map_err(|error| vec![FileError { path: manifest_path, error }])
+}
+
+// The app's most recent backup that we know how to undo, if any.
+pub fn find_latest_backup(app: &AppConfig) -> Option<PathBuf> {
+    let prefix = format!("{}-", app.name);
+    fs::read_dir(app.backup_dir.as_ref()?).ok()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let timestamp: u64 = entry.file_name().to_string_lossy().strip_prefix(&prefix)?.parse().ok()?;
+            Some((timestamp, entry.path()))
+        })
+        .filter(|(_, path)| get_manifest_path(path).is_file())
+        .max_by_key(|(timestamp, _)| *timestamp)
+        .map(|(_, path)| path)
+}
+
+// Undoing a change means putting back whatever was backed up, and
+// deleting whatever was copied that wasn't there before.
+fn plan_undo(backup_path: &Path, change: &Change) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let backup_root = backup_path.join(&change.backup_subdir);
+    let (mut to_restore, to_remove): (Vec<PathBuf>, Vec<PathBuf>) = change.copied.iter().cloned()
+        .partition(|rel_path| backup_root.join(rel_path).exists());
+    to_restore.extend(change.deleted.iter().filter(|rel_path| backup_root.join(rel_path).exists()).cloned());
+    (to_restore, to_remove)
+}
+
+fn undo(app: &AppConfig, backup_path: &Path, undos: &[(&Change, Vec<PathBuf>, Vec<PathBuf>)], use_trash: bool) -> Vec<FileError> {
+    let mut errors = vec![];
+    for (change, to_restore, to_remove) in undos.iter() {
+        let root = if change.side == Side::App { &app.path } else { &app.dropbox_path };
+        // Backing up into the app's folder is the same as restoring from the backup.
+        let backup = DirState::empty(&backup_path.join(&change.backup_subdir));
+        errors.extend(backup.back_up_into(to_restore, root).err().into_iter().flatten());
+        errors.extend(dir_state::remove_files_from(root, to_remove, use_trash).err().into_iter().flatten());
+    }
+    errors
+}
+
+// Puts both of the app's folders back the way they were before its most
+// recent sync, as far as its backups can tell.
+pub fn rollback_app(app: &AppConfig, options: &SyncOptions) -> Result<(), String> {
+    let backup_path = find_latest_backup(app).ok_or_else(|| {
+        format!("There's no backup of {} to roll back to. Backups are only made if the app has a backup_dir.", app.name)
+    })?;
+    let json = fs::read_to_string(get_manifest_path(&backup_path)).map_err(|e| format!("Unable to read the backup: {}", e))?;
+    let changes: Vec<Change> = serde_json::from_str(&json).map_err(|e| format!("Unable to read the backup: {}", e))?;
+    say!("  Rolling back to {}.", backup_path.to_string_lossy());
+    let undos: Vec<(&Change, Vec<PathBuf>, Vec<PathBuf>)> = changes.iter().map(|change| {
+        let (to_restore, to_remove) = plan_undo(&backup_path, change);
+        (change, to_restore, to_remove)
+    }).collect();
+    for (change, to_restore, to_remove) in undos.iter() {
+        let side = if change.side == Side::App { "the app" } else { "Dropbox" };
+        say!("  Restoring {} file(s) in {} and deleting {}.", to_restore.len(), side, to_remove.len());
+    }
+    if options.dry_run {
+        return Ok(());
+    }
+    if options.non_interactive {
+        return Err(String::from("Not rolling back, since that needs confirming and we're running non-interactively."));
+    }
+    if !ask::ask_yes_or_no("  This will overwrite what's there now. Proceed (y/n) ? ") {
+        say!("  Okay, not doing anything.");
+        return Ok(());
+    }
+    let errors = undo(app, &backup_path, &undos, options.use_trash || app.use_trash);
+    if !errors.is_empty() {
+        return Err(errors.iter().map(|e| format!("Unable to roll back {}", e)).collect::<Vec<String>>().join("\n  "));
+    }
+    say!("  Rolled back.");
+    Ok(())
+}
+
+#[test]
+fn test_rollback() {
+    let tmp_dir = PathBuf::from(".test_rollback");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app = AppConfig {
+        name: String::from("boop"),
+        path: tmp_dir.join("app"),
+        dropbox_path: tmp_dir.join("dropbox"),
+        backup_dir: Some(tmp_dir.join("backups")),
+        ..Default::default()
+    };
+    assert_eq!(find_latest_backup(&app), None);
+    fs::create_dir_all(&app.path).unwrap();
+    fs::write(app.path.join("save1"), "new").unwrap();
+    fs::write(app.path.join("save2"), "brand new").unwrap();
+
+    // A sync overwrote save1, added save2, and deleted save3.
+    let backup_path = app.backup_dir.as_ref().unwrap().join("boop-1000");
+    fs::create_dir_all(&backup_path).unwrap();
+    fs::write(backup_path.join("save1"), "old").unwrap();
+    fs::write(backup_path.join("save3"), "deleted").unwrap();
+    let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<PathBuf>>();
+    let change = Change::new(Side::App, "", &paths(&["save1", "save2"]), &paths(&["save3"]));
+    assert_eq!(plan_undo(&backup_path, &change), (paths(&["save1", "save3"]), paths(&["save2"])));
+    record(&backup_path, std::slice::from_ref(&change)).unwrap();
+    fs::create_dir_all(app.backup_dir.as_ref().unwrap().join("boop-999")).unwrap();
+    fs::create_dir_all(app.backup_dir.as_ref().unwrap().join("boop-other-2000")).unwrap();
+    assert_eq!(find_latest_backup(&app), Some(backup_path.clone()));
+
+    let options = SyncOptions { non_interactive: true, ..SyncOptions::new("my_computer") };
+    assert!(rollback_app(&app, &options).is_err());
+    assert_eq!(fs::read_to_string(app.path.join("save1")).unwrap(), "new");
+
+    let (to_restore, to_remove) = plan_undo(&backup_path, &change);
+    assert!(undo(&app, &backup_path, &[(&change, to_restore, to_remove)], false).is_empty());
+    assert_eq!(fs::read_to_string(app.path.join("save1")).unwrap(), "old");
+    assert!(!app.path.join("save2").exists());
+    assert_eq!(fs::read_to_string(app.path.join("save3")).unwrap(), "deleted");
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
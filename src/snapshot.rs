@@ -0,0 +1,84 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::AppConfig;
+use crate::dir_state::DirState;
+use crate::file_filter::RESERVED_PREFIX;
+use crate::output::complain;
+use crate::SyncResult;
+
+// Where we remember what the app's Dropbox folder looked like the last
+// time this computer synchronized it.
+pub fn get_snapshot_path(app: &AppConfig, hostname: &str) -> PathBuf {
+    app.dropbox_path.join(format!("{}snapshot-{}.json", RESERVED_PREFIX, hostname))
+}
+
+pub fn load(app: &AppConfig, hostname: &str) -> Option<DirState> {
+    let json = fs::read_to_string(get_snapshot_path(app, hostname)).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+pub fn save(app: &AppConfig, hostname: &str, state: &DirState) {
+    let path = get_snapshot_path(app, hostname);
+    // Without a snapshot we just can't be as clever next time, so this isn't fatal.
+    if let Err(e) = fs::write(&path, serde_json::to_string(state).unwrap()) {
+        complain!("  Warning: unable to save {}: {}", path.to_string_lossy(), e);
+    }
+}
+
+// Like `DirState::classify_against`, but if the two sides conflict, uses the state from the
+// last sync to figure out whether only one of them has actually changed
+// since then. This is what lets us tell a file that was deleted on one
+// side apart from one that was added on the other.
+pub fn classify_with_snapshot(dir_state: &DirState, dropbox_dir_state: &DirState, last_sync: Option<&DirState>) -> SyncResult {
+    let result = dir_state.classify_against(dropbox_dir_state);
+    let last_sync = match last_sync {
+        Some(last_sync) if result == SyncResult::Conflict => last_sync,
+        _ => return result,
+    };
+    let app_changed = !last_sync.are_contents_equal_to(dir_state);
+    let dropbox_changed = !last_sync.are_contents_equal_to(dropbox_dir_state);
+    match (app_changed, dropbox_changed) {
+        (true, false) => SyncResult::AppNewerThanDropbox,
+        (false, true) => SyncResult::DropboxNewerThanApp,
+        _ => result,
+    }
+}
+
+#[test]
+fn test_classify_with_snapshot() {
+    use crate::file_filter::FileFilter;
+
+    let tmp_dir = PathBuf::from(".test_classify_with_snapshot");
+    if tmp_dir.exists() {
+        fs::remove_dir_all(&tmp_dir).unwrap();
+    }
+    let app = AppConfig {
+        name: String::from("boop"),
+        path: tmp_dir.join("app"),
+        dropbox_path: tmp_dir.join("dropbox"),
+        ..Default::default()
+    };
+    let src_state = DirState::from_dir(&PathBuf::from("test-data/dirstate_test"), &FileFilter::default());
+    src_state.copy_into(&app.path).unwrap();
+    src_state.copy_into(&app.dropbox_path).unwrap();
+    save(&app, "my_computer", &app.scan_dropbox_path());
+    let last_sync = load(&app, "my_computer").unwrap();
+    assert!(load(&app, "other_computer").is_none());
+
+    // The snapshot itself shouldn't show up as part of the Dropbox folder.
+    assert!(app.scan_dropbox_path().are_contents_equal_to(&app.scan_path()));
+
+    // Another computer deleted a file from Dropbox.
+    fs::remove_file(app.dropbox_path.join("boop")).unwrap();
+    assert_eq!(app.scan_path().classify_against(&app.scan_dropbox_path()), SyncResult::Conflict);
+    assert_eq!(classify_with_snapshot(&app.scan_path(), &app.scan_dropbox_path(), Some(&last_sync)), SyncResult::DropboxNewerThanApp);
+    assert_eq!(classify_with_snapshot(&app.scan_path(), &app.scan_dropbox_path(), None), SyncResult::Conflict);
+
+    // Meanwhile, the file was deleted from the app too, but it has another one.
+    fs::remove_file(app.path.join("boop")).unwrap();
+    fs::write(app.path.join("new_save"), "hi").unwrap();
+    assert_eq!(classify_with_snapshot(&app.scan_path(), &app.scan_dropbox_path(), Some(&last_sync)), SyncResult::Conflict);
+
+    fs::remove_dir_all(&tmp_dir).unwrap();
+}
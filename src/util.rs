@@ -1,14 +1,22 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
+use sysinfo::{DiskExt, System, SystemExt};
 
-pub fn ensure_path_exists(value: &PathBuf) {
-  if !value.exists() {
-      let err = format!("Path '{}' does not exist!", value.to_string_lossy());
-      panic!("{}", err);
+pub fn ensure_path_exists(value: &Path) -> Result<(), String> {
+  if value.exists() {
+    Ok(())
+  } else {
+    Err(format!("Path '{}' does not exist!", value.to_string_lossy()))
   }
 }
 
-pub fn get_case_insensitive<'a, T>(map: &'a HashMap<String, T>, key: &String) -> Option<&'a T> {
+#[test]
+fn test_ensure_path_exists() {
+  assert_eq!(ensure_path_exists(Path::new("test-data")), Ok(()));
+  assert_eq!(ensure_path_exists(Path::new("test-data/nonexistent")), Err(String::from("Path 'test-data/nonexistent' does not exist!")));
+}
+
+pub fn get_case_insensitive<'a, T>(map: &'a HashMap<String, T>, key: &str) -> Option<&'a T> {
   let key_lowercase = key.to_lowercase();
 
   for (k, v) in map {
@@ -25,8 +33,8 @@ fn test_get_case_insensitive() {
 
   h.insert("Boop".to_owned(), 1);
 
-  assert_eq!(get_case_insensitive(&h, &"BOOP".to_owned()), Some(&1));
-  assert_eq!(get_case_insensitive(&h, &"meh".to_owned()), None);
+  assert_eq!(get_case_insensitive(&h, "BOOP"), Some(&1));
+  assert_eq!(get_case_insensitive(&h, "meh"), None);
 }
 
 pub fn get_primary_hostname<T: AsRef<str>>(hostname: T) -> String {
@@ -43,3 +51,145 @@ fn test_get_primary_hostname() {
   assert_eq!(get_primary_hostname("boop.local"), "boop".to_owned());
   assert_eq!(get_primary_hostname("boop.blap.local"), "boop".to_owned());
 }
+
+// Whether two names refer to the same computer, e.g. `boop` and
+// `BOOP.local`.
+pub fn is_same_host(hostname: &str, other: &str) -> bool {
+  get_primary_hostname(hostname).to_lowercase() == get_primary_hostname(other).to_lowercase()
+}
+
+#[test]
+fn test_is_same_host() {
+  assert!(is_same_host("boop", "boop"));
+  assert!(is_same_host("boop", "Boop.local"));
+  assert!(is_same_host("boop.local", "boop.lan"));
+  assert!(!is_same_host("boop", "blap.local"));
+}
+
+pub fn may_delete(hostname: &str, delete_authority: Option<&str>) -> bool {
+  if let Some(authority) = delete_authority {
+    is_same_host(hostname, authority)
+  } else {
+    true
+  }
+}
+
+#[test]
+fn test_may_delete() {
+  assert!(may_delete("boop", None));
+  assert!(may_delete("boop", Some("boop")));
+  assert!(may_delete("boop", Some("BOOP.local")));
+  assert!(!may_delete("boop", Some("blap")));
+}
+
+// Where the given program would be found on the `PATH`, if anywhere.
+pub fn find_in_path(program: &str) -> Option<PathBuf> {
+  let program_path = Path::new(program);
+  if program_path.components().count() > 1 {
+    return Some(program_path.to_path_buf()).filter(|path| path.is_file());
+  }
+  let names = if cfg!(windows) && program_path.extension().is_none() {
+    vec![format!("{}.exe", program), String::from(program)]
+  } else {
+    vec![String::from(program)]
+  };
+  let path_var = std::env::var_os("PATH")?;
+  std::env::split_paths(&path_var)
+    .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+    .find(|path| path.is_file())
+}
+
+#[test]
+fn test_find_in_path() {
+  assert!(find_in_path("cargo").is_some());
+  assert_eq!(find_in_path("dropsync-nonexistent-program"), None);
+  assert_eq!(find_in_path("test-data/sample_config.toml"), Some(PathBuf::from("test-data/sample_config.toml")));
+}
+
+// How many bytes are free on the volume the given path is on, if we can tell.
+pub fn get_available_space(path: &Path) -> Option<u64> {
+  let path = std::fs::canonicalize(path).ok()?;
+  let mut sys = System::new();
+  sys.refresh_disks_list();
+  let mounts: Vec<(PathBuf, u64)> = sys.disks().iter().map(|disk| (disk.mount_point().to_path_buf(), disk.available_space())).collect();
+  find_available_space(&path, &mounts)
+}
+
+// The path is on whichever volume is mounted closest to it.
+fn find_available_space(path: &Path, mounts: &[(PathBuf, u64)]) -> Option<u64> {
+  mounts.iter()
+    .filter(|(mount_point, _)| path.starts_with(mount_point))
+    .max_by_key(|(mount_point, _)| mount_point.components().count())
+    .map(|(_, available)| *available)
+}
+
+#[test]
+fn test_find_available_space() {
+  let mounts = vec![(PathBuf::from("/"), 100), (PathBuf::from("/home"), 200), (PathBuf::from("/home2"), 300)];
+  assert_eq!(find_available_space(Path::new("/home/atul"), &mounts), Some(200));
+  assert_eq!(find_available_space(Path::new("/home2"), &mounts), Some(300));
+  assert_eq!(find_available_space(Path::new("/var"), &mounts), Some(100));
+  assert_eq!(find_available_space(Path::new("/var"), &mounts[1..]), None);
+}
+
+// Expands a leading `~` to the user's home directory, along with any
+// `$VAR`, `${VAR}` or `%VAR%` environment variables. Unset variables are
+// left as-is.
+pub fn expand_path(path: &str) -> String {
+  let mut result = String::new();
+  let mut rest = path;
+
+  if rest == "~" || rest.starts_with("~/") || rest.starts_with("~\\") {
+    if let Some(home_dir) = dirs::home_dir() {
+      result.push_str(&home_dir.to_string_lossy());
+      rest = &rest[1..];
+    }
+  }
+
+  while let Some(index) = rest.find(['$', '%']) {
+    result.push_str(&rest[..index]);
+    let after = &rest[index + 1..];
+    let (name, remainder) = if rest[index..].starts_with('%') {
+      match after.find('%') {
+        Some(end) => (&after[..end], &after[end + 1..]),
+        None => ("", after),
+      }
+    } else if let Some(braced) = after.strip_prefix('{') {
+      match braced.find('}') {
+        Some(end) => (&braced[..end], &braced[end + 1..]),
+        None => ("", after),
+      }
+    } else {
+      let end = after.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(after.len());
+      (&after[..end], &after[end..])
+    };
+    match std::env::var(name) {
+      Ok(value) if !name.is_empty() => {
+        result.push_str(&value);
+        rest = remainder;
+      },
+      _ => {
+        result.push_str(&rest[index..index + 1]);
+        rest = after;
+      }
+    }
+  }
+  result.push_str(rest);
+  result
+}
+
+#[test]
+fn test_expand_path() {
+  std::env::set_var("DROPSYNC_TEST_EXPAND", "boop");
+  let home = dirs::home_dir().unwrap().to_string_lossy().to_string();
+
+  assert_eq!(expand_path("/foo/bar"), "/foo/bar");
+  assert_eq!(expand_path("~"), home);
+  assert_eq!(expand_path("~/foo"), format!("{}/foo", home));
+  assert_eq!(expand_path("foo/~/bar"), "foo/~/bar");
+  assert_eq!(expand_path("$DROPSYNC_TEST_EXPAND/foo"), "boop/foo");
+  assert_eq!(expand_path("${DROPSYNC_TEST_EXPAND}foo"), "boopfoo");
+  assert_eq!(expand_path("C:\\%DROPSYNC_TEST_EXPAND%\\foo"), "C:\\boop\\foo");
+  assert_eq!(expand_path("$DROPSYNC_TEST_UNSET/foo"), "$DROPSYNC_TEST_UNSET/foo");
+  assert_eq!(expand_path("100%"), "100%");
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use notify_debouncer_mini::notify::{self, RecursiveMode, Watcher};
+
+use dropsync::config::AppConfig;
+
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 5;
+pub const DEFAULT_SETTLE_SECS: u64 = 10;
+
+// How long to let a burst of file system events pile up before we hear
+// about them.
+pub const DEBOUNCE_TIME: Duration = Duration::from_millis(500);
+
+// Which app each watched folder belongs to, so we can tell which apps a
+// file system event is about.
+#[derive(Default)]
+pub struct WatchedFolders {
+    folders: Vec<(PathBuf, String)>,
+}
+
+impl WatchedFolders {
+    // Starts watching the app's folder and, unless it syncs with a remote
+    // host, its Dropbox folder.
+    pub fn watch(&mut self, watcher: &mut dyn Watcher, config: &AppConfig) -> notify::Result<()> {
+        let mut folders = vec![&config.path];
+        if config.remote.is_none() {
+            folders.push(&config.dropbox_path);
+        }
+        for folder in folders {
+            watcher.watch(folder, RecursiveMode::Recursive)?;
+            self.add(folder, &config.name);
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, folder: &Path, app_name: &str) {
+        // Events might name the folder by where it really is, e.g. if
+        // it's a symlink.
+        if let Ok(canonical) = folder.canonicalize() {
+            if canonical != folder {
+                self.folders.push((canonical, app_name.to_owned()));
+            }
+        }
+        self.folders.push((folder.to_path_buf(), app_name.to_owned()));
+    }
+
+    pub fn apps_affected_by<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a str> + 'a {
+        self.folders.iter().filter(move |(folder, _)| path.starts_with(folder)).map(|(_, app_name)| app_name.as_str())
+    }
+}
+
+// Keeps track of which apps have changed since they were last synced, so
+// that we only sync each one once it's stopped changing for a bit, rather
+// than in the middle of the app writing its files.
+pub struct ChangeTracker<T> {
+    settle_time: Duration,
+    apps: HashMap<String, Watched<T>>,
+}
+
+struct Watched<T> {
+    state: T,
+    // When we last saw the state change, if it has since the last sync.
+    changed_at: Option<Instant>,
+}
+
+impl<T: PartialEq> ChangeTracker<T> {
+    pub fn new(settle_time: Duration) -> Self {
+        ChangeTracker { settle_time, apps: HashMap::new() }
+    }
+
+    // Remembers what the app looks like right after it was synced.
+    pub fn reset(&mut self, app_name: &str, state: T) {
+        self.apps.insert(app_name.to_owned(), Watched { state, changed_at: None });
+    }
+
+    // Records what the app looks like now, returning whether it's time to
+    // sync it: it's changed since it was last synced, but not in a while.
+    pub fn update(&mut self, app_name: &str, state: T, now: Instant) -> bool {
+        let watched = match self.apps.get_mut(app_name) {
+            Some(watched) => watched,
+            None => {
+                self.reset(app_name, state);
+                return false;
+            }
+        };
+        if watched.state != state {
+            watched.state = state;
+            watched.changed_at = Some(now);
+            return false;
+        }
+        watched.changed_at.is_some_and(|changed_at| now.duration_since(changed_at) >= self.settle_time)
+    }
+
+    // The apps that changed since they were last synced, but haven't
+    // changed again for the settle time, so they need another look.
+    pub fn settled(&self, now: Instant) -> Vec<String> {
+        self.apps.iter()
+            .filter(|(_, watched)| watched.changed_at.is_some_and(|changed_at| now.duration_since(changed_at) >= self.settle_time))
+            .map(|(app_name, _)| app_name.clone())
+            .collect()
+    }
+
+    // When the next app that's changed will have settled, if any have.
+    pub fn next_settle_time(&self) -> Option<Instant> {
+        self.apps.values().filter_map(|watched| watched.changed_at).min().map(|changed_at| changed_at + self.settle_time)
+    }
+}
+
+#[test]
+fn test_change_tracker() {
+    let start = Instant::now();
+    let secs = |n| start + Duration::from_secs(n);
+    let mut tracker = ChangeTracker::new(Duration::from_secs(10));
+    tracker.reset("boop", 1);
+
+    // Nothing changed, so there's nothing to sync.
+    assert!(!tracker.update("boop", 1, secs(20)));
+    assert_eq!(tracker.next_settle_time(), None);
+
+    // Keep waiting as long as it's still changing.
+    assert!(!tracker.update("boop", 2, secs(25)));
+    assert!(!tracker.update("boop", 3, secs(30)));
+    assert_eq!(tracker.next_settle_time(), Some(secs(40)));
+    assert!(tracker.settled(secs(35)).is_empty());
+    assert!(!tracker.update("boop", 3, secs(35)));
+    assert_eq!(tracker.settled(secs(40)), vec![String::from("boop")]);
+    assert!(tracker.update("boop", 3, secs(40)));
+
+    // Once it's synced, it's back to normal.
+    tracker.reset("boop", 3);
+    assert!(!tracker.update("boop", 3, secs(60)));
+
+    // Apps we haven't seen before just start being watched.
+    assert!(!tracker.update("shoop", 1, secs(60)));
+    assert!(!tracker.update("shoop", 1, secs(100)));
+}
+
+#[test]
+fn test_watched_folders() {
+    let mut folders = WatchedFolders::default();
+    folders.add(Path::new("/games/game1"), "game1");
+    folders.add(Path::new("/dropbox/game1"), "game1");
+    folders.add(Path::new("/games/game2"), "game2");
+    let affected = |path: &str| folders.apps_affected_by(Path::new(path)).map(String::from).collect::<Vec<_>>();
+    assert_eq!(affected("/dropbox/game1/saves/slot1.sav"), vec!["game1"]);
+    assert_eq!(affected("/games/game2"), vec!["game2"]);
+    assert!(affected("/games/game20/slot1.sav").is_empty());
+    assert!(affected("/dropbox/.dropsync-history-my_computer.jsonl").is_empty());
+}